@@ -0,0 +1,178 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::{AnyError, AnyResult};
+use crate::container::ArchiveEntry;
+use std::fs;
+use std::fs::{File, Metadata};
+use std::io;
+use std::io::{ErrorKind, Read, Take};
+use std::path::{Component, Path, PathBuf};
+
+// -----------------------------------------------
+
+// the archived path keeps the normal components of the given path, like tar does
+fn archive_path(path: &Path) -> AnyResult<String> {
+	let mut components: Vec<&str> = Vec::new();
+	for component in path.components() {
+		match component {
+			Component::Normal(name) => components.push(
+				name.to_str()
+					.filter(|name| !name.contains(['/', '\\', '\0']))
+					.ok_or_else(|| unsupported_path(path))?,
+			),
+			Component::ParentDir => return Err(unsupported_path(path)),
+			Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+		}
+	}
+	Ok(components.join("/"))
+}
+
+fn scan_path(
+	source: PathBuf,
+	path: String,
+	entries: &mut Vec<(PathBuf, ArchiveEntry)>,
+	offset: &mut u64,
+) -> AnyResult<()> {
+	// links are not followed, which could store a file twice or never end on a loop
+	let metadata: Metadata = fs::symlink_metadata(&source)?;
+	if metadata.file_type().is_symlink() {
+		return Err(AnyError::from_string(format!(
+			"Can not archive the symbolic link {}!",
+			source.display()
+		)));
+	} else if metadata.is_dir() {
+		let mut children: Vec<PathBuf> = fs::read_dir(&source)?
+			.map(|child| child.map(|child| child.path()))
+			.collect::<io::Result<Vec<PathBuf>>>()?;
+		children.sort();
+		// the directory given as "." or "/" has no entry of its own
+		if !path.is_empty() {
+			entries.push((
+				source,
+				ArchiveEntry {
+					path: path.clone(),
+					is_directory: true,
+					offset: *offset,
+					length: 0,
+				},
+			));
+		}
+		for child in children {
+			let name: String = archive_path(Path::new(child.file_name().unwrap_or_default()))?;
+			let child_path: String = match path.is_empty() {
+				true => name,
+				false => format!("{}/{}", path, name),
+			};
+			scan_path(child, child_path, entries, offset)?;
+		}
+	} else if metadata.is_file() && !path.is_empty() {
+		entries.push((
+			source,
+			ArchiveEntry {
+				path,
+				is_directory: false,
+				offset: *offset,
+				length: metadata.len(),
+			},
+		));
+		*offset += metadata.len();
+	} else {
+		return Err(unsupported_path(&source));
+	}
+	Ok(())
+}
+
+// list the given files and directory trees in archive order, with where to read each entry
+pub fn scan_tree(paths: &[&Path]) -> AnyResult<Vec<(PathBuf, ArchiveEntry)>> {
+	let mut entries: Vec<(PathBuf, ArchiveEntry)> = Vec::new();
+	let mut offset: u64 = 0;
+	for &path in paths {
+		scan_path(
+			path.to_path_buf(),
+			archive_path(path)?,
+			&mut entries,
+			&mut offset,
+		)?;
+	}
+	// the catalog stores the length of a path in an u16
+	if let Some((source, _)) = entries
+		.iter()
+		.find(|(_, entry)| entry.path.len() > u16::MAX as usize)
+	{
+		return Err(unsupported_path(source));
+	}
+	Ok(entries)
+}
+
+#[cold]
+fn unsupported_path(path: &Path) -> AnyError {
+	AnyError::from_string(format!("Can not archive {}!", path.display()))
+}
+
+// -----------------------------------------------
+
+// read the data of the scanned files one after another, as a single stream
+pub struct TreeReader {
+	files: std::vec::IntoIter<(PathBuf, u64)>,
+	file: Option<(PathBuf, Take<File>)>,
+}
+
+impl TreeReader {
+	pub fn new(entries: &[(PathBuf, ArchiveEntry)]) -> Self {
+		let files: Vec<(PathBuf, u64)> = entries
+			.iter()
+			.filter(|(_, entry)| !entry.is_directory)
+			.map(|(source, entry)| (source.clone(), entry.length))
+			.collect();
+		Self {
+			files: files.into_iter(),
+			file: None,
+		}
+	}
+}
+
+impl Read for TreeReader {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		if buffer.is_empty() {
+			return Ok(0);
+		}
+		loop {
+			if let Some((source, file)) = &mut self.file {
+				let length: usize = file.read(buffer)?;
+				if length > 0 {
+					return Ok(length);
+				}
+				// the length is already in the catalog, so files must not shrink meanwhile
+				if file.limit() > 0 {
+					return Err(io::Error::new(
+						ErrorKind::UnexpectedEof,
+						format!("{} shrank while being archived!", source.display()),
+					));
+				}
+			}
+			match self.files.next() {
+				None => return Ok(0),
+				Some((source, length)) => {
+					let file: Take<File> = File::open(&source)?.take(length);
+					self.file = Some((source, file));
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,100 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{
+	compress_slice, create_archive, decompress_slice, extract_archive, AnyError, AnyResult,
+	ArchiveEntry, FormatError, Options,
+};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+// -----------------------------------------------
+
+fn text(length: usize) -> Vec<u8> {
+	b"archived file "
+		.iter()
+		.copied()
+		.cycle()
+		.take(length)
+		.collect()
+}
+
+#[test]
+fn test_archive() -> AnyResult<()> {
+	let root: PathBuf = env::temp_dir().join(format!("srx-test-archive-{}", process::id()));
+	let source: PathBuf = root.join("source");
+	let _error_ignored_ = fs::remove_dir_all(&root);
+	fs::create_dir_all(source.join("tree/empty"))?;
+	fs::create_dir_all(source.join("tree/sub"))?;
+	fs::write(source.join("tree/a.txt"), text(3000))?;
+	fs::write(source.join("tree/sub/b.txt"), text(70000))?;
+	fs::write(source.join("tree/sub/zero"), b"")?;
+	fs::write(source.join("single"), b"one file")?;
+
+	let paths: [&Path; 2] = [&source.join("tree"), &source.join("single")];
+	for options in [Options::new(), Options::new().block_size(20000)] {
+		let (archive, entries): (Vec<u8>, Vec<ArchiveEntry>) =
+			create_archive(&paths, Vec::new(), &options)?;
+		let names: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+		// the paths keep every normal component of the given paths
+		let prefix: String = source.to_str().unwrap().trim_start_matches('/').to_string();
+		assert_eq!(names.len(), 7);
+		assert_eq!(names[0], format!("{}/tree", prefix));
+		assert_eq!(names[6], format!("{}/single", prefix));
+
+		// plain decompression gives the data of the files one after another
+		let data: Vec<u8> = [text(3000), text(70000), b"one file".to_vec()].concat();
+		assert_eq!(decompress_slice(&archive, &options)?, data);
+
+		let target: PathBuf = root.join("target");
+		let _error_ignored_ = fs::remove_dir_all(&target);
+		let (_, extracted): (Cursor<&[u8]>, Vec<ArchiveEntry>) =
+			extract_archive(Cursor::new(&archive[..]), &target, &options)?;
+		assert_eq!(extracted, entries);
+		let restored: PathBuf = target.join(&prefix);
+		assert_eq!(fs::read(restored.join("tree/a.txt"))?, text(3000));
+		assert_eq!(fs::read(restored.join("tree/sub/b.txt"))?, text(70000));
+		assert_eq!(fs::read(restored.join("tree/sub/zero"))?, b"");
+		assert_eq!(fs::read(restored.join("single"))?, b"one file");
+		assert!(restored.join("tree/empty").is_dir());
+	}
+
+	// a single file is not an archive
+	let compressed: Vec<u8> = compress_slice(b"one file", &Options::new())?;
+	assert!(matches!(
+		extract_archive(Cursor::new(&compressed[..]), &root, &Options::new()),
+		Err(AnyError::Format(FormatError::NotArchive))
+	));
+
+	// symbolic links are rejected instead of followed, even when they loop
+	#[cfg(unix)]
+	{
+		let link: PathBuf = source.join("tree/sub/loop");
+		std::os::unix::fs::symlink(&source, &link)?;
+		match create_archive(&paths, Vec::new(), &Options::new()) {
+			Err(error) => assert!(error.to_string().contains("symbolic link")),
+			Ok(_) => panic!("unexpected success"),
+		}
+		fs::remove_file(&link)?;
+		std::os::unix::fs::symlink(source.join("single"), &link)?;
+		assert!(create_archive(&[link.as_path()], Vec::new(), &Options::new()).is_err());
+	}
+	fs::remove_dir_all(&root)?;
+	Ok(())
+}
@@ -0,0 +1,112 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::{AnyError, AnyResult};
+use crate::container::ArchiveEntry;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// -----------------------------------------------
+
+// write the decompressed data of an archive into its files under the given directory
+pub struct TreeWriter {
+	directory: PathBuf,
+	entries: std::vec::IntoIter<ArchiveEntry>,
+	// the file being written and how many bytes it still misses
+	file: Option<(File, u64)>,
+}
+
+impl TreeWriter {
+	pub fn new(directory: &Path, entries: Vec<ArchiveEntry>) -> Self {
+		Self {
+			directory: directory.to_path_buf(),
+			entries: entries.into_iter(),
+			file: None,
+		}
+	}
+
+	// create the next entry, or return false if there is none left
+	fn next_entry(&mut self) -> io::Result<bool> {
+		self.file = None;
+		let entry: ArchiveEntry = match self.entries.next() {
+			None => return Ok(false),
+			Some(entry) => entry,
+		};
+		let path: PathBuf = self.directory.join(&entry.path);
+		if entry.is_directory {
+			fs::create_dir_all(path)?;
+		} else {
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			self.file = Some((File::create(path)?, entry.length));
+		}
+		Ok(true)
+	}
+
+	// create the entries after the end of the data, then check that every file is complete
+	pub fn finish(mut self) -> AnyResult<()> {
+		loop {
+			if let Some((file, remaining)) = &mut self.file {
+				if *remaining > 0 {
+					return Err(AnyError::from_string(
+						"Archive data ended before its last file!",
+					));
+				}
+				file.flush()?;
+			}
+			if !self.next_entry()? {
+				return Ok(());
+			}
+		}
+	}
+}
+
+impl Write for TreeWriter {
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		if buffer.is_empty() {
+			return Ok(0);
+		}
+		loop {
+			if let Some((file, remaining)) = &mut self.file {
+				if *remaining > 0 {
+					let length: usize = (*remaining).min(buffer.len() as u64) as usize;
+					file.write_all(&buffer[..length])?;
+					*remaining -= length as u64;
+					return Ok(length);
+				}
+			}
+			if !self.next_entry()? {
+				return Err(io::Error::new(
+					ErrorKind::InvalidData,
+					"Archive data goes on after its last file!",
+				));
+			}
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut self.file {
+			None => Ok(()),
+			Some((file, _)) => file.flush(),
+		}
+	}
+}
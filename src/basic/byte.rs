@@ -61,7 +61,7 @@ macro_rules! impl_from_signed_for_byte {
 
             impl From<$t> for Byte {
 				fn from(value: $t) -> Self {
-					debug_assert!(value >= 0 && value <= 255, "Unexpected value for Byte!");
+					debug_assert!((0..=255).contains(&value), "Unexpected value for Byte!");
 					Byte(value as usize)
 				}
             }
@@ -0,0 +1,163 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::slice::ChunksExact;
+
+// -----------------------------------------------
+
+// CRC-32C (Castagnoli), reflected polynomial, with the tables of the next 7 bytes after the first
+// one so that the slices are digested 8 bytes at a time
+const CRC_TABLES: [[u32; 256]; 8] = crc_tables(0x82F63B78);
+const CRC_TABLE: [u32; 256] = CRC_TABLES[0];
+
+const fn crc_tables(polynomial: u32) -> [[u32; 256]; 8] {
+	let mut tables: [[u32; 256]; 8] = [[0; 256]; 8];
+	let mut index: usize = 0;
+	while index < 256 {
+		let mut value: u32 = index as u32;
+		let mut bit: usize = 0;
+		while bit < 8 {
+			value = if value & 1 != 0 {
+				(value >> 1) ^ polynomial
+			} else {
+				value >> 1
+			};
+			bit += 1;
+		}
+		tables[0][index] = value;
+		index += 1;
+	}
+	let mut table: usize = 1;
+	while table < 8 {
+		index = 0;
+		while index < 256 {
+			let value: u32 = tables[table - 1][index];
+			tables[table][index] = tables[0][(value & 0xFF) as usize] ^ (value >> 8);
+			index += 1;
+		}
+		table += 1;
+	}
+	tables
+}
+
+// -----------------------------------------------
+
+// the length of the data, and its CRC unless the stream has no checksum to compare it with
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Digest {
+	length: u64,
+	crc: Option<u32>,
+}
+
+impl Default for Digest {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Digest {
+	pub fn new() -> Self {
+		Self {
+			length: 0,
+			crc: Some(0xFFFFFFFF),
+		}
+	}
+
+	// the length alone, which spares the table lookup of every byte
+	pub fn without_checksum() -> Self {
+		Self {
+			length: 0,
+			crc: None,
+		}
+	}
+
+	pub fn from_slice(buffer: &[u8]) -> Self {
+		let mut digest: Digest = Self::new();
+		digest.update_slice(buffer);
+		digest
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, value: u8) {
+		if let Some(crc) = &mut self.crc {
+			*crc = CRC_TABLE[((*crc ^ value as u32) & 0xFF) as usize] ^ (*crc >> 8);
+		}
+		self.length += 1;
+	}
+
+	pub fn update_slice(&mut self, buffer: &[u8]) {
+		if let Some(crc) = &mut self.crc {
+			let mut chunks: ChunksExact<u8> = buffer.chunks_exact(8);
+			for chunk in &mut chunks {
+				let low: u32 = *crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+				*crc = CRC_TABLES[7][(low & 0xFF) as usize]
+					^ CRC_TABLES[6][(low >> 8 & 0xFF) as usize]
+					^ CRC_TABLES[5][(low >> 16 & 0xFF) as usize]
+					^ CRC_TABLES[4][(low >> 24) as usize]
+					^ CRC_TABLES[3][chunk[4] as usize]
+					^ CRC_TABLES[2][chunk[5] as usize]
+					^ CRC_TABLES[1][chunk[6] as usize]
+					^ CRC_TABLES[0][chunk[7] as usize];
+			}
+			for &value in chunks.remainder() {
+				*crc = CRC_TABLE[((*crc ^ value as u32) & 0xFF) as usize] ^ (*crc >> 8);
+			}
+		}
+		self.length += buffer.len() as u64;
+	}
+
+	pub fn length(&self) -> u64 {
+		self.length
+	}
+
+	// the CRC-32C of the data, only known to a digest made with it
+	pub fn checksum(&self) -> Option<u32> {
+		self.crc.map(|crc: u32| !crc)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Digest;
+
+	#[test]
+	fn test_digest() {
+		assert_eq!(
+			Digest::from_slice(b"123456789").checksum(),
+			Some(0xE3069283)
+		);
+
+		// the slices digested 8 bytes at a time give the CRC of the bytes one by one
+		let data: Vec<u8> = (0..40u32)
+			.map(|index: u32| (index * 37 + 11) as u8)
+			.collect();
+		for split in 0..data.len() {
+			let mut bytes: Digest = Digest::new();
+			data.iter().for_each(|&value: &u8| bytes.update(value));
+			let mut slices: Digest = Digest::from_slice(&data[..split]);
+			slices.update_slice(&data[split..]);
+			assert_eq!(slices, bytes);
+		}
+
+		// without a checksum only the length is kept
+		let mut length_only: Digest = Digest::without_checksum();
+		length_only.update_slice(b"1234");
+		length_only.update(b'5');
+		assert_eq!((length_only.length(), length_only.checksum()), (5, None));
+	}
+}
@@ -20,6 +20,7 @@ use std::any::Any;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
+use std::io;
 
 // -----------------------------------------------
 
@@ -27,11 +28,119 @@ pub type AnyResult<T> = Result<T, AnyError>;
 
 // -----------------------------------------------
 
+/// Ways a compressed stream can fail to be a valid `.srx` stream.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum FormatError {
+	/// The input does not start with the `.srx` magic bytes.
+	NotSrx,
+	/// The format version is newer than this build understands.
+	UnsupportedVersion(u8),
+	/// The header asks for flags or model parameters this build does not support.
+	UnsupportedHeader(String),
+	/// The decompressed length differs from the length recorded in the header.
+	LengthMismatch { expected: u64, actual: u64 },
+	/// The checksum of the decompressed data differs from the one recorded in the trailer.
+	ChecksumMismatch { expected: u32, actual: u32 },
+	/// The input ended before the end of the stream, at the given byte offset.
+	Truncated { offset: u64 },
+	/// The input goes on after the end of the stream, starting at the given byte offset.
+	TrailingData { offset: u64 },
+	/// The block starting at the given byte offset does not match its recorded lengths.
+	CorruptBlock { offset: u64 },
+	/// The block index starting at the given byte offset does not match the blocks.
+	CorruptIndex { offset: u64 },
+	/// Random access was asked for a stream written without a block index.
+	NotSeekable,
+	/// The archive catalog starting at the given byte offset is malformed or does not match the
+	/// data.
+	CorruptCatalog { offset: u64 },
+	/// Extraction was asked for a stream that is not an archive.
+	NotArchive,
+	/// The stream was compressed with a preset dictionary of the expected id, but the dictionary
+	/// given to decompress it has another id, or none was given.
+	DictionaryMismatch { expected: u32, actual: Option<u32> },
+}
+
+impl Display for FormatError {
+	fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+		match self {
+			FormatError::NotSrx => write!(formatter, "Not a SRX compressed file!"),
+			FormatError::UnsupportedVersion(version) => {
+				write!(formatter, "Unsupported SRX format version {}!", version)
+			}
+			FormatError::UnsupportedHeader(reason) => {
+				write!(formatter, "Unsupported SRX header: {}!", reason)
+			}
+			FormatError::LengthMismatch { expected, actual } => write!(
+				formatter,
+				"Length mismatch: expected {} bytes but got {} bytes!",
+				expected, actual
+			),
+			FormatError::ChecksumMismatch { expected, actual } => write!(
+				formatter,
+				"Checksum mismatch: expected {:08X} but got {:08X}!",
+				expected, actual
+			),
+			FormatError::Truncated { offset } => write!(
+				formatter,
+				"Truncated input: unexpected end of data at byte offset {}!",
+				offset
+			),
+			FormatError::TrailingData { offset } => write!(
+				formatter,
+				"Trailing data: unexpected bytes after the end of the stream at byte offset {}!",
+				offset
+			),
+			FormatError::CorruptBlock { offset } => {
+				write!(formatter, "Corrupt block at byte offset {}!", offset)
+			}
+			FormatError::CorruptIndex { offset } => {
+				write!(formatter, "Corrupt block index at byte offset {}!", offset)
+			}
+			FormatError::NotSeekable => {
+				write!(
+					formatter,
+					"Not a seekable SRX file, compress it with a block index!"
+				)
+			}
+			FormatError::CorruptCatalog { offset } => {
+				write!(
+					formatter,
+					"Corrupt archive catalog at byte offset {}!",
+					offset
+				)
+			}
+			FormatError::NotArchive => {
+				write!(formatter, "Not a SRX archive, it holds a single file!")
+			}
+			FormatError::DictionaryMismatch {
+				expected,
+				actual: None,
+			} => write!(
+				formatter,
+				"Dictionary mismatch: expected dictionary {:08X} but got none!",
+				expected
+			),
+			FormatError::DictionaryMismatch {
+				expected,
+				actual: Some(actual),
+			} => write!(
+				formatter,
+				"Dictionary mismatch: expected dictionary {:08X} but got {:08X}!",
+				expected, actual
+			),
+		}
+	}
+}
+
+// -----------------------------------------------
+
 #[derive(Debug)]
 pub enum AnyError {
 	String(String),
 	Error(Box<dyn Error + Send>),
 	Box(Box<dyn Any + Send>),
+	Format(FormatError),
 }
 
 impl AnyError {
@@ -54,6 +163,7 @@ impl Display for AnyError {
 			AnyError::String(value) => Display::fmt(value, formatter),
 			AnyError::Error(value) => Display::fmt(value, formatter),
 			AnyError::Box(value) => Debug::fmt(value, formatter),
+			AnyError::Format(value) => Display::fmt(value, formatter),
 		}
 	}
 }
@@ -65,3 +175,25 @@ impl<E: Error + Send + 'static> From<E> for AnyError {
 		Self::Error(Box::new(e))
 	}
 }
+
+impl From<FormatError> for AnyError {
+	#[cold]
+	#[inline(always)]
+	fn from(error: FormatError) -> Self {
+		Self::Format(error)
+	}
+}
+
+impl From<AnyError> for io::Error {
+	#[cold]
+	fn from(error: AnyError) -> Self {
+		match error {
+			AnyError::Error(value) => match value.downcast::<io::Error>() {
+				Ok(io_error) => *io_error,
+				Err(value) => io::Error::other(value.to_string()),
+			},
+			AnyError::Format(_) => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+			_ => io::Error::other(error.to_string()),
+		}
+	}
+}
@@ -30,12 +30,26 @@ pub trait Reader<T> {
 	fn read(&mut self) -> AnyResult<Option<T>>;
 }
 
+impl<T, R: Reader<T>> Reader<T> for &mut R {
+	#[inline(always)]
+	fn read(&mut self) -> AnyResult<Option<T>> {
+		(**self).read()
+	}
+}
+
 // -----------------------------------------------
 
 pub trait Writer<T> {
 	fn write(&mut self, value: T) -> AnyResult<()>;
 }
 
+impl<T, W: Writer<T>> Writer<T> for &mut W {
+	#[inline(always)]
+	fn write(&mut self, value: T) -> AnyResult<()> {
+		(**self).write(value)
+	}
+}
+
 // -----------------------------------------------
 
 pub trait Consumer<T> {
@@ -18,12 +18,17 @@
 
 mod buffer;
 mod byte;
+mod digest;
 mod error;
 mod io;
 mod pipe;
+mod reciprocal;
+mod stream;
 
-pub use self::buffer::Buffer;
 pub use self::byte::Byte;
-pub use self::error::{AnyError, AnyResult};
+pub use self::digest::Digest;
+pub use self::error::{AnyError, AnyResult, FormatError};
 pub use self::io::{Closable, Consumer, FromProducer, Producer, Reader, ToConsumer, Writer};
 pub use self::pipe::{pipe, PipedReader, PipedWriter};
+pub use self::reciprocal::reciprocals;
+pub use self::stream::{IoReader, IoWriter};
@@ -0,0 +1,29 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// 1 / (count + 1.5) in 16 bits fixed point for every count below the length, the rate at which an
+// adaptive probability moves toward the bit of its next update after count updates
+pub const fn reciprocals<const LENGTH: usize>() -> [u32; LENGTH] {
+	let mut table: [u32; LENGTH] = [0; LENGTH];
+	let mut count: usize = 0;
+	while count < LENGTH {
+		table[count] = 65536 * 2 / (2 * count as u32 + 3);
+		count += 1;
+	}
+	table
+}
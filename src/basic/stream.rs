@@ -0,0 +1,143 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::error::AnyResult;
+use super::io::{Closable, Reader, Writer};
+use std::io::{ErrorKind, Read, Write};
+
+// -----------------------------------------------
+
+pub struct IoReader<R: Read, const SIZE: usize> {
+	reader: R,
+	buffer: Box<[u8]>,
+	length: usize,
+	index: usize,
+}
+
+impl<R: Read, const SIZE: usize> IoReader<R, SIZE> {
+	pub fn new(reader: R) -> Self {
+		Self {
+			reader,
+			buffer: vec![0; SIZE].into_boxed_slice(),
+			length: 0,
+			index: 0,
+		}
+	}
+
+	#[cold]
+	fn fill(&mut self) -> AnyResult<()> {
+		debug_assert!(self.index == self.length);
+		self.index = 0;
+		self.length = loop {
+			match self.reader.read(&mut self.buffer) {
+				Ok(length) => break length,
+				Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+				Err(error) => return Err(error.into()),
+			}
+		};
+		Ok(())
+	}
+}
+
+impl<R: Read, const SIZE: usize> Reader<u8> for IoReader<R, SIZE> {
+	fn read(&mut self) -> AnyResult<Option<u8>> {
+		debug_assert!(self.index <= self.length && self.length <= SIZE);
+		if self.index == self.length {
+			self.fill()?;
+			if self.length == 0 {
+				return Ok(None);
+			}
+		}
+		let value: u8 = self.buffer[self.index];
+		self.index += 1;
+		Ok(Some(value))
+	}
+}
+
+impl<R: Read, const SIZE: usize> Closable<R> for IoReader<R, SIZE> {
+	fn close(self) -> AnyResult<R> {
+		Ok(self.reader)
+	}
+}
+
+// -----------------------------------------------
+
+pub struct IoWriter<W: Write, const SIZE: usize> {
+	writer: W,
+	buffer: Vec<u8>,
+}
+
+impl<W: Write, const SIZE: usize> IoWriter<W, SIZE> {
+	pub fn new(writer: W) -> Self {
+		Self {
+			writer,
+			buffer: Vec::with_capacity(SIZE),
+		}
+	}
+
+	// write out everything buffered so far, then flush the underlying writer
+	pub fn flush(&mut self) -> AnyResult<()> {
+		self.sync()?;
+		Ok(self.writer.flush()?)
+	}
+
+	#[cold]
+	fn sync(&mut self) -> AnyResult<()> {
+		self.writer.write_all(&self.buffer)?;
+		self.buffer.clear();
+		Ok(())
+	}
+}
+
+impl<W: Write, const SIZE: usize> Writer<u8> for IoWriter<W, SIZE> {
+	fn write(&mut self, value: u8) -> AnyResult<()> {
+		self.buffer.push(value);
+		if self.buffer.len() == SIZE {
+			self.sync()?;
+		}
+		Ok(())
+	}
+}
+
+impl<W: Write, const SIZE: usize> Closable<W> for IoWriter<W, SIZE> {
+	fn close(mut self) -> AnyResult<W> {
+		self.flush()?;
+		Ok(self.writer)
+	}
+}
+
+// -----------------------------------------------
+
+impl Reader<u8> for &[u8] {
+	fn read(&mut self) -> AnyResult<Option<u8>> {
+		match self.split_first() {
+			None => Ok(None),
+			Some((&value, remaining)) => {
+				*self = remaining;
+				Ok(Some(value))
+			}
+		}
+	}
+}
+
+impl Writer<u8> for Vec<u8> {
+	fn write(&mut self, value: u8) -> AnyResult<()> {
+		self.push(value);
+		Ok(())
+	}
+}
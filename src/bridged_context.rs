@@ -17,44 +17,206 @@
  */
 
 use crate::basic::Byte;
-use crate::primary_context::{ByteHistory, HistoryState, PrimaryContext};
-use crate::secondary_context::SecondaryContext;
+use crate::container::Header;
+use crate::primary_context::{ByteHistory, ByteMatched, HistoryState, MatchModel, PrimaryContext};
+use crate::secondary_context::{Bit, Counter16Context, Counter32Context, SecondaryContext};
 
 // -----------------------------------------------
 
-pub const PRIMARY_CONTEXT_SIZE: usize = 1 << 24;
-pub const SECONDARY_CONTEXT_SIZE: usize = 0x4000 * 256 + (1024 + 32) * 768;
+// the size of the primary context is chosen at runtime, 2^24 histories unless told otherwise
+pub const PRIMARY_CONTEXT_BITS: u8 = 24;
+pub const MIN_PRIMARY_CONTEXT_BITS: u8 = 16;
+pub const MAX_PRIMARY_CONTEXT_BITS: u8 = 28;
+// the primary context rolls its hash unless told to hash the last 1 to 8 bytes
+pub const MAX_PRIMARY_CONTEXT_ORDER: u8 = 8;
+// the literal context has 2^(level - 10) contexts, at most 2^14, which the header records
+pub const LITERAL_CONTEXT_BITS: u8 = 14;
+pub const MIN_LITERAL_CONTEXT_BITS: u8 = 6;
+
+pub fn literal_context_bits(primary_context_bits: u8) -> u8 {
+	primary_context_bits
+		.saturating_sub(10)
+		.clamp(MIN_LITERAL_CONTEXT_BITS, LITERAL_CONTEXT_BITS)
+}
+
+const BIT_CONTEXT_BUCKETS: usize = 1024 + 32;
+const MATCH_LENGTH_BUCKETS: usize = 32;
+
+// the bytes a literal can be known not to be: the ranks of a wide history but the first one, the
+// ranks of the fallback context and the byte expected by the match model
+const MAX_EXCLUDED: usize = 6 + 3 + 1;
+
+// the order of the fallback context, which is indexed by the last bytes themselves
+pub const FALLBACK_CONTEXT_ORDER: u8 = 2;
+
+// the maps of the learned state probabilities: the bits of the literals by depth, then the three
+// flags, the four flags of the deeper ranks, the three agreed flags, the three fallback flags and
+// the flag of the match model
+pub const STATE_MAPS: usize = 8 + 3 + 4 + 3 + 3 + 1;
+
+// the small contexts of the adaptive probability map: the node of a literal bit, the bucket of the
+// previous byte and match count together with the rank of a flag, or the bucket of the match length
+// together with whether the match model expects the first byte
+pub const REFINEMENT_CONTEXTS: usize =
+	256 + BIT_CONTEXT_BUCKETS * (3 + 4 + 3 + 3) + MATCH_LENGTH_BUCKETS * 2;
+
+// the depth of a node of the literals, from 0 for the first bit, the low 4 bits being coded in one
+// of 16 blocks of 15 nodes after the 15 nodes of the high 4 bits
+pub fn literal_depth(node: usize) -> usize {
+	match node {
+		1..=15 => node.ilog2() as usize,
+		_ => 4 + ((node - 1) % 15 + 1).ilog2() as usize,
+	}
+}
+
+// -----------------------------------------------
+
+// the regions of the secondary context, each only there when the header asks for its model: the
+// literals, then for every bucket of the previous byte and match count: the three flags, the four
+// flags of the deeper ranks of wide histories, the three flags again when the fallback context
+// agrees on the first byte and the three flags of the ranks of the fallback context, then the flag
+// of the match model for every bucket of the match length and whether it expects the first byte,
+// then the literals coded with exclusion in the context of the last 2 bytes, of which the previous
+// one loses its low bits below the level 24, and of the last byte, each split by whether a ruled
+// out byte is still possible and by its next bit
+#[derive(Copy, Clone)]
+pub struct ContextLayout {
+	literal_mask: usize,
+	bit_offset: usize,
+	deep_offset: usize,
+	agreed_offset: usize,
+	match_offset: usize,
+	exclusion_offset: usize,
+	exclusion_shift: u32,
+	order_1_offset: usize,
+	size: usize,
+}
+
+impl ContextLayout {
+	pub fn new(header: &Header) -> Self {
+		let literal_bits: u32 = header.literal_context_bits() as u32;
+		let region = |present: bool, size: usize| -> usize {
+			match present {
+				false => 0,
+				true => size,
+			}
+		};
+		let bit_offset: usize = (1 << literal_bits) * 256;
+		let deep_offset: usize = bit_offset + BIT_CONTEXT_BUCKETS * 768;
+		let agreed_offset: usize =
+			deep_offset + region(header.is_wide(), BIT_CONTEXT_BUCKETS * 1024);
+		let match_offset: usize =
+			agreed_offset + region(header.has_fallback(), BIT_CONTEXT_BUCKETS * 768 * 2);
+		let exclusion_offset: usize =
+			match_offset + region(header.has_match_model(), MATCH_LENGTH_BUCKETS * 2 * 256);
+		let exclusion_shift: u32 = LITERAL_CONTEXT_BITS as u32 - literal_bits;
+		let order_1_offset: usize =
+			exclusion_offset + region(header.has_exclusion(), (1 << (16 - exclusion_shift)) * 768);
+		Self {
+			literal_mask: (1 << literal_bits) - 1,
+			bit_offset,
+			deep_offset,
+			agreed_offset,
+			match_offset,
+			exclusion_offset,
+			exclusion_shift,
+			order_1_offset,
+			size: order_1_offset + region(header.has_exclusion(), 256 * 768),
+		}
+	}
+
+	// the number of cells of the secondary context
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	// whether the literals are coded with exclusion
+	pub fn has_exclusion(&self) -> bool {
+		self.order_1_offset < self.size
+	}
+
+	pub fn state_map(&self, context_index: usize) -> usize {
+		match context_index.checked_sub(self.bit_offset) {
+			None => literal_depth(context_index & 0xFF),
+			Some(offset) if context_index < self.deep_offset => 8 + offset % 768 / 256,
+			Some(_) if context_index < self.agreed_offset => {
+				11 + (context_index - self.deep_offset) % 1024 / 256
+			}
+			Some(_) if context_index < self.match_offset => {
+				let offset: usize = context_index - self.agreed_offset;
+				15 + offset / (BIT_CONTEXT_BUCKETS * 768) * 3 + offset % 768 / 256
+			}
+			Some(_) if context_index < self.exclusion_offset => 21,
+			Some(_) => literal_depth(context_index & 0xFF),
+		}
+	}
+
+	// the context of the same literal bit in the context of the last byte alone, for a literal
+	// coded with exclusion in the context of the last 2 bytes
+	pub fn order_1_literal(&self, context_index: usize) -> Option<usize> {
+		let offset: usize = context_index.checked_sub(self.exclusion_offset)?;
+		(context_index < self.order_1_offset)
+			.then(|| self.order_1_offset + ((offset / 768) & 0xFF) * 768 + offset % 768)
+	}
+
+	pub fn refinement_context(&self, context_index: usize) -> usize {
+		match context_index.checked_sub(self.bit_offset) {
+			Some(offset) if context_index < self.exclusion_offset => 256 + offset / 256,
+			_ => context_index & 0xFF,
+		}
+	}
+}
 
 // -----------------------------------------------
 
-pub type BridgedPrimaryContext = PrimaryContext<PRIMARY_CONTEXT_SIZE>;
-pub type BridgedSecondaryContext = SecondaryContext<SECONDARY_CONTEXT_SIZE>;
+pub type BridgedPrimaryContext = PrimaryContext;
+pub type BridgedMatchModel = MatchModel;
+pub type BridgedSecondaryContext = SecondaryContext;
+pub type BridgedCounter16Context = Counter16Context;
+pub type BridgedCounter32Context = Counter32Context;
 
 // -----------------------------------------------
 
+// the bucket of the flags of a history, by the last byte while the history has matched few times
+fn bit_bucket(previous_byte: u8, current_state: HistoryState) -> usize {
+	let match_count: usize = current_state.match_count();
+	if match_count < 4 {
+		(usize::from(previous_byte) << 2) | match_count
+	} else {
+		1024 + if match_count - 4 <= 63 {
+			(match_count - 4) >> 1
+		} else {
+			31
+		}
+	}
+}
+
 pub struct BridgedContextInfo {
+	layout: ContextLayout,
 	bit_context: usize,
+	bucket: usize,
 	literal_context: usize,
-	current_history: ByteHistory,
+	previous_bytes: u16,
+	// the bytes ranked by the history, whose state depends on its kind
+	current_history: ByteHistory<u64>,
 	current_state: HistoryState,
 }
 
 impl BridgedContextInfo {
-	pub fn new(current_history: ByteHistory, previous_byte: Byte, hash_value: usize) -> Self {
-		let current_state: HistoryState = current_history.get_state();
-		let match_count: usize = current_state.match_count();
+	pub fn new(
+		layout: ContextLayout,
+		current_history: ByteHistory<u64>,
+		current_state: HistoryState,
+		previous_bytes: u16,
+		hash_value: usize,
+	) -> Self {
+		let bucket: usize = bit_bucket(previous_bytes as u8, current_state);
 		Self {
-			bit_context: 0x4000 * 256
-				+ if match_count < 4 {
-					(usize::from(previous_byte) << 2) | match_count
-				} else {
-					1024 + if match_count - 4 <= 63 {
-						(match_count - 4) >> 1
-					} else {
-						31
-					}
-				} * 768,
-			literal_context: (hash_value & 0x3FFF) * 256,
+			layout,
+			bit_context: layout.bit_offset + bucket * 768,
+			bucket,
+			literal_context: (hash_value & layout.literal_mask) * 256,
+			previous_bytes,
 			current_history,
 			current_state,
 		}
@@ -78,10 +240,110 @@ impl BridgedContextInfo {
 			& 0xFF);
 	}
 
+	// the context of the flag telling the byte at `rank` from a deeper one, which for rank 1 is
+	// the third context, and for the deeper ranks of wide histories is built the same way
+	pub fn rank_context(&self, rank: usize) -> usize {
+		debug_assert!((1..6).contains(&rank));
+		if rank == 1 {
+			return self.third_context();
+		}
+		self.layout.deep_offset
+			+ self.bucket * 1024
+			+ (rank - 2) * 256
+			+ ((usize::from(self.current_history.byte(rank)) * 2)
+				.wrapping_sub(usize::from(self.current_history.byte(rank + 1)))
+				& 0xFF)
+	}
+
+	pub fn from_context(layout: ContextLayout, context: &BridgedPrimaryContext) -> Self {
+		let (history, state): (ByteHistory<u64>, HistoryState) = context.get_history();
+		Self::new(
+			layout,
+			history,
+			state,
+			context.previous_bytes(),
+			context.hash_value(),
+		)
+	}
+
+	// code the three flags in their own contexts when the fallback context, if any, ranks the same
+	// first byte
+	pub fn with_fallback(mut self, fallback: Option<&BridgedContextInfo>) -> Self {
+		if fallback.is_some_and(|fallback| fallback.first_byte() == self.first_byte()) {
+			self.bit_context += self.layout.agreed_offset - self.layout.bit_offset;
+		}
+		self
+	}
+
+	// the context of the flag telling the byte at `rank` of this fallback context from a deeper
+	// one or a literal
+	pub fn fallback_context(&self, rank: usize) -> usize {
+		debug_assert!(rank < 3);
+		self.layout.agreed_offset
+			+ BIT_CONTEXT_BUCKETS * 768
+			+ self.bucket * 768
+			+ rank * 256
+			+ usize::from(self.current_history.byte(rank))
+	}
+
+	// whether the byte at `rank` of this fallback context can be the next byte after a miss of the
+	// primary context with the given number of ranks, so that it is worth a flag
+	pub fn is_candidate(&self, rank: usize, primary: &BridgedContextInfo, ranks: usize) -> bool {
+		let byte: Byte = self.byte(rank);
+		(0..rank).all(|better: usize| self.byte(better) != byte)
+			&& (0..ranks).all(|primary_rank: usize| primary.byte(primary_rank) != byte)
+	}
+
+	// the context of the flag telling the byte expected by the match model from the others, by the
+	// length of the match: exact up to 15 bytes, then by its power of two
+	pub fn match_context(&self, expected: Byte, length: usize) -> usize {
+		let bucket: usize = match length {
+			0..=15 => length,
+			_ => 12 + length.ilog2() as usize,
+		};
+		let agreed: usize = usize::from(expected == self.first_byte());
+		self.layout.match_offset + (bucket * 2 + agreed) * 256 + usize::from(expected)
+	}
+
 	pub fn literal_context(&self) -> usize {
 		self.literal_context
 	}
 
+	// the last byte and the high bits of the one before, as many as the level leaves room for
+	fn exclusion_context(&self) -> usize {
+		let previous_bytes: usize = usize::from(self.previous_bytes);
+		(previous_bytes & 0xFF) | ((previous_bytes >> (8 + self.layout.exclusion_shift)) << 8)
+	}
+
+	// the literal coded with exclusion after a miss of every model, which is none of the bytes they
+	// ranked or expected, but the first byte, as a literal equal to it stands for the end
+	pub fn excluding_literal(
+		&self,
+		fallback: Option<&BridgedContextInfo>,
+		expected: Option<Byte>,
+		ranks: usize,
+	) -> LiteralContext {
+		let mut literal: LiteralContext = LiteralContext {
+			context: self.layout.exclusion_offset + self.exclusion_context() * 768,
+			excluded: [0; MAX_EXCLUDED],
+			count: 0,
+		};
+		let ranked: Option<[Byte; 3]> =
+			fallback.map(|fallback| [fallback.byte(0), fallback.byte(1), fallback.byte(2)]);
+		let candidates = (1..ranks)
+			.map(|rank: usize| self.byte(rank))
+			.chain(ranked.into_iter().flatten())
+			.chain(expected);
+		for byte in candidates {
+			let value: u8 = byte.into();
+			if byte != self.first_byte() && !literal.excluded[..literal.count].contains(&value) {
+				literal.excluded[literal.count] = value;
+				literal.count += 1;
+			}
+		}
+		literal
+	}
+
 	pub fn first_byte(&self) -> Byte {
 		self.current_history.first_byte()
 	}
@@ -90,11 +352,235 @@ impl BridgedContextInfo {
 		self.current_history.second_byte()
 	}
 
-	pub fn third_byte(&self) -> Byte {
-		self.current_history.third_byte()
+	// the rank of the next byte among the first `ranks` bytes
+	pub fn rank_of(&self, next_byte: Byte, ranks: usize) -> ByteMatched {
+		self.current_history.rank_of(next_byte, ranks)
+	}
+
+	// the byte at the given rank, from 0 for the first one
+	pub fn byte(&self, rank: usize) -> Byte {
+		self.current_history.byte(rank)
+	}
+
+	pub fn current_state(&self) -> HistoryState {
+		self.current_state
+	}
+}
+
+// -----------------------------------------------
+
+// the contexts of the flags and the literals of a narrow history, all that a plain stream codes
+pub struct PlainContextInfo {
+	bit_context: usize,
+	literal_context: usize,
+	current_history: ByteHistory,
+	current_state: HistoryState,
+}
+
+impl PlainContextInfo {
+	pub fn new(layout: &ContextLayout, context: &BridgedPrimaryContext) -> Self {
+		let current_history: ByteHistory = context.plain_history();
+		let current_state: HistoryState = current_history.get_state();
+		let bucket: usize = bit_bucket(context.previous_bytes() as u8, current_state);
+		Self {
+			bit_context: layout.bit_offset + bucket * 768,
+			literal_context: (context.hash_value() & layout.literal_mask) * 256,
+			current_history,
+			current_state,
+		}
+	}
+
+	pub fn first_context(&self) -> usize {
+		return self.bit_context + usize::from(self.current_history.first_byte());
+	}
+
+	pub fn second_context(&self) -> usize {
+		return self.bit_context
+			+ 0x100 + ((usize::from(self.current_history.second_byte())
+			+ usize::from(self.current_history.third_byte()))
+			& 0xFF);
+	}
+
+	pub fn third_context(&self) -> usize {
+		return self.bit_context
+			+ 0x200 + ((usize::from(self.current_history.second_byte()) * 2)
+			.wrapping_sub(usize::from(self.current_history.third_byte()))
+			& 0xFF);
+	}
+
+	pub fn literal_context(&self) -> usize {
+		self.literal_context
+	}
+
+	pub fn first_byte(&self) -> Byte {
+		self.current_history.first_byte()
 	}
 
 	pub fn current_state(&self) -> HistoryState {
 		self.current_state
 	}
 }
+
+// -----------------------------------------------
+
+pub enum LiteralBit {
+	// the context index and the node of a bit to code
+	Coded(usize, usize),
+	// a bit that can only be this one, as the other one only leads to excluded bytes
+	Known(Bit),
+}
+
+// the literal after a miss, coded bit by bit in the context of the last 2 bytes, every bit knowing
+// whether an excluded byte is still possible and its next bit, the first one in rank order, so
+// that only the bits leading to excluded bytes alone are left out, while the excluded bytes under
+// the other bits keep some probability
+pub struct LiteralContext {
+	context: usize,
+	excluded: [u8; MAX_EXCLUDED],
+	count: usize,
+}
+
+impl LiteralContext {
+	// the next bit of a literal whose first `depth` bits are `prefix`
+	pub fn bit(&self, prefix: usize, depth: u32) -> LiteralBit {
+		debug_assert!(depth < 8 && prefix >> depth == 0);
+		let node: usize = match depth {
+			0..=3 => (1 << depth) | prefix,
+			_ => {
+				15 * ((prefix >> (depth - 4)) + 1)
+					+ ((1 << (depth - 4)) | (prefix & (0xF >> (8 - depth))))
+			}
+		};
+		let mut excluded: usize = 0;
+		let mut counts: [usize; 2] = [0; 2];
+		for &value in &self.excluded[..self.count] {
+			let value: usize = usize::from(value);
+			if value >> (8 - depth) == prefix {
+				let bit: usize = (value >> (7 - depth)) & 1;
+				counts[bit] += 1;
+				if excluded == 0 {
+					excluded = 1 + bit;
+				}
+			}
+		}
+		match counts
+			.iter()
+			.position(|&count: &usize| count == 1 << (7 - depth))
+		{
+			Some(bit) => LiteralBit::Known(Bit::from(bit as u32 ^ 1)),
+			None => LiteralBit::Coded(self.context + excluded * 256 + node, node),
+		}
+	}
+
+	// the context index, the node and the value of every bit of the byte that has to be coded
+	pub fn coded_bits(&self, byte: Byte) -> impl Iterator<Item = (usize, usize, Bit)> + '_ {
+		let value: usize = usize::from(byte);
+		(0..8).filter_map(
+			move |depth: u32| match self.bit(value >> (8 - depth), depth) {
+				LiteralBit::Coded(context, node) => Some((
+					context,
+					node,
+					Bit::from(((value >> (7 - depth)) & 1) as u32),
+				)),
+				LiteralBit::Known(_) => None,
+			},
+		)
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{BridgedContextInfo, ContextLayout, LiteralBit, LiteralContext};
+	use crate::basic::Byte;
+	use crate::container::Header;
+	use crate::primary_context::ByteHistory;
+	use crate::secondary_context::Bit;
+	use crate::Options;
+
+	// the context of a history ranking the given bytes
+	fn context_info(layout: ContextLayout, bytes: &[u8]) -> BridgedContextInfo {
+		let bits: u64 = bytes
+			.iter()
+			.enumerate()
+			.fold(0, |bits: u64, (rank, &byte)| {
+				bits | u64::from(byte) << (rank * 8 + 8)
+			});
+		let history: ByteHistory<u64> = ByteHistory::new(bits);
+		BridgedContextInfo::new(layout, history, history.get_state(), 0x2020, 0)
+	}
+
+	#[test]
+	fn test_fallback_context() {
+		let layout: ContextLayout =
+			ContextLayout::new(&Header::new(&Options::new().fallback(true)));
+		let primary: BridgedContextInfo = context_info(layout, b"abc");
+
+		// only the bytes ranked neither by the primary context nor by a better rank are candidates
+		let fallback: BridgedContextInfo = context_info(layout, b"bdd");
+		assert!(!fallback.is_candidate(0, &primary, 3));
+		assert!(fallback.is_candidate(0, &primary, 1));
+		assert!(fallback.is_candidate(1, &primary, 3));
+		assert!(!fallback.is_candidate(2, &primary, 3));
+
+		// the flags move to their own contexts when both contexts rank the same first byte
+		let first_context: usize = primary.first_context();
+		let agreed: BridgedContextInfo =
+			context_info(layout, b"abc").with_fallback(Some(&context_info(layout, b"axy")));
+		assert_eq!(
+			agreed.first_context() - first_context,
+			layout.agreed_offset - layout.bit_offset
+		);
+		for other in [None, Some(&fallback)] {
+			let primary: BridgedContextInfo = context_info(layout, b"abc").with_fallback(other);
+			assert_eq!(primary.first_context(), first_context);
+		}
+		assert_eq!(layout.state_map(agreed.first_context()), 15);
+
+		// each rank of the fallback context has its own flags, after the agreed ones
+		for rank in 0..3 {
+			let context: usize = fallback.fallback_context(rank);
+			assert_eq!(context % 256, usize::from(fallback.byte(rank)));
+			assert_eq!(layout.state_map(context), 18 + rank);
+			assert!(context < layout.match_offset);
+		}
+		assert_ne!(fallback.fallback_context(1), fallback.fallback_context(2));
+
+		// and the contexts only have room for them when the header asks for the fallback
+		let layout: ContextLayout = ContextLayout::new(&Header::new(&Options::new()));
+		assert_eq!(layout.agreed_offset, layout.match_offset);
+	}
+	#[test]
+	fn test_literal_exclusion() {
+		let layout: ContextLayout =
+			ContextLayout::new(&Header::new(&Options::new().exclusion(true)));
+		assert!(layout.has_exclusion());
+
+		// the literal is none of the bytes ranked or expected but the first one, each once
+		let primary: BridgedContextInfo = context_info(layout, b"z@A");
+		let fallback: BridgedContextInfo = context_info(layout, b"@zB");
+		let literal: LiteralContext =
+			primary.excluding_literal(Some(&fallback), Some(Byte::from(b'C')), 3);
+		assert_eq!(&literal.excluded[..literal.count], b"@ABC");
+		let literal: LiteralContext = primary.excluding_literal(None, None, 1);
+		assert_eq!(literal.count, 0);
+
+		// a bit is known when the other one only leads to excluded bytes
+		let literal: LiteralContext = primary.excluding_literal(None, None, 2);
+		assert_eq!(&literal.excluded[..literal.count], b"@");
+		assert!(matches!(literal.bit(0x20, 7), LiteralBit::Known(Bit::One)));
+		assert_eq!(literal.coded_bits(Byte::from(0x41)).count(), 7);
+		assert_eq!(literal.coded_bits(Byte::from(0x42)).count(), 8);
+
+		// and the other bits are coded knowing whether an excluded byte is still possible, and its
+		// next bit
+		for (prefix, depth, node, excluded) in [(0, 0, 1, 1), (0, 1, 2, 2), (1, 1, 3, 0)] {
+			assert!(matches!(
+				literal.bit(prefix, depth),
+				LiteralBit::Coded(context, found)
+					if context == literal.context + excluded * 256 + node && found == node
+			));
+		}
+	}
+}
@@ -0,0 +1,578 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::decoder::CombinedContextDecoder;
+use super::index::BlockIndex;
+use super::shared::thread_join;
+use super::slice::encode_slice;
+use crate::basic::{AnyError, AnyResult, Byte, Closable, Digest, FormatError, Reader, Writer};
+use crate::container::Header;
+use std::io::{Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::{available_parallelism, scope, ScopedJoinHandle};
+
+// -----------------------------------------------
+
+// the block header is the original length (u32) and the compressed length (u32),
+// and a block with both lengths set to zero ends the stream
+const BLOCK_HEADER_SIZE: usize = 8;
+const END_OF_BLOCKS: [u8; BLOCK_HEADER_SIZE] = [0; BLOCK_HEADER_SIZE];
+
+// a byte is coded with at most the match flag, the two first rank flags, five more rank flags,
+// three fallback flags and eight literal bits, and the coder never shifts out more than the four
+// bytes of its range per bit, which bounds the compressed length of a block of a given length
+// together with the end of stream and the four bytes flushed after it
+const MAX_CODED_BITS_PER_BYTE: u64 = 20;
+
+fn max_compressed_length(length: u32) -> u64 {
+	(u64::from(length) + 1) * MAX_CODED_BITS_PER_BYTE * 4 + 4
+}
+
+// -----------------------------------------------
+
+type BlockResult = AnyResult<Vec<u8>>;
+
+// a job for the worker threads, with where to send its result
+type BlockJob<T> = (T, SyncSender<BlockResult>);
+
+type BlockQueue<T> = (SyncSender<T>, Receiver<T>);
+
+pub struct CompressedBlock {
+	pub offset: u64,
+	pub length: u32,
+	pub data: Vec<u8>,
+}
+
+// -----------------------------------------------
+
+fn thread_count(threads: usize) -> usize {
+	match threads {
+		0 => available_parallelism().map_or(1, |threads| threads.get()),
+		threads => threads,
+	}
+}
+
+// hand a job to the worker threads, after telling the collecting thread where to wait for its
+// result so that the results are collected in order
+fn dispatch<T: Send + 'static>(
+	jobs: &SyncSender<BlockJob<T>>,
+	pending: &SyncSender<Receiver<BlockResult>>,
+	job: T,
+) -> AnyResult<()> {
+	let (sender, receiver): (SyncSender<BlockResult>, Receiver<BlockResult>) = sync_channel(1);
+	pending.send(receiver)?;
+	jobs.send((job, sender))?;
+	Ok(())
+}
+
+fn run_block_worker<T, F: Fn(T) -> BlockResult>(
+	jobs: &Mutex<Receiver<BlockJob<T>>>,
+	work: F,
+) -> AnyResult<()> {
+	loop {
+		let job: Option<BlockJob<T>> = jobs
+			.lock()
+			.map_err(|_| AnyError::from_string("Poisoned block queue!"))?
+			.recv()
+			.ok();
+		match job {
+			None => return Ok(()),
+			// the collecting thread is gone if the receiver is, and it has its own error to report
+			Some((job, sender)) => {
+				let _error_ignored_ = sender.send(work(job));
+			}
+		}
+	}
+}
+
+// -----------------------------------------------
+
+// compress a block together with its block header
+fn encode_block(header: &Header, block: &[u8]) -> BlockResult {
+	let mut output: Vec<u8> = Vec::with_capacity(BLOCK_HEADER_SIZE + block.len() / 2 + 16);
+	output.extend_from_slice(&END_OF_BLOCKS);
+	let mut output: Vec<u8> = encode_slice(block, header, output)?;
+	let compressed_length: u32 = u32::try_from(output.len() - BLOCK_HEADER_SIZE)
+		.map_err(|_| AnyError::from_string("The compressed block is too large!"))?;
+	output[..4].copy_from_slice(&(block.len() as u32).to_le_bytes());
+	output[4..BLOCK_HEADER_SIZE].copy_from_slice(&compressed_length.to_le_bytes());
+	Ok(output)
+}
+
+fn run_block_reader<R: Read>(
+	mut reader: R,
+	block_size: usize,
+	jobs: SyncSender<BlockJob<Vec<u8>>>,
+	pending: SyncSender<Receiver<BlockResult>>,
+	mut digest: Digest,
+) -> AnyResult<(R, Digest)> {
+	loop {
+		let mut block: Vec<u8> = Vec::with_capacity(block_size);
+		(&mut reader)
+			.take(block_size as u64)
+			.read_to_end(&mut block)?;
+		if block.is_empty() {
+			return Ok((reader, digest));
+		}
+		digest.update_slice(&block);
+		dispatch(&jobs, &pending, block)?;
+	}
+}
+
+// write the end of blocks, then the block index if any
+fn write_end<W: Write>(
+	writer: &mut W,
+	header: &Header,
+	index: &BlockIndex,
+	position: u64,
+) -> AnyResult<()> {
+	writer.write_all(&END_OF_BLOCKS)?;
+	if header.is_seekable() {
+		index.write(writer, position + BLOCK_HEADER_SIZE as u64)?;
+	}
+	Ok(())
+}
+
+fn run_block_writer<W: Write>(
+	mut writer: W,
+	header: &Header,
+	pending: Receiver<Receiver<BlockResult>>,
+) -> AnyResult<W> {
+	let mut index: BlockIndex = BlockIndex::new();
+	let mut position: u64 = header.size();
+	for receiver in pending {
+		let block: Vec<u8> = receiver.recv()??;
+		index.push(read_le_u32(&block), position);
+		writer.write_all(&block)?;
+		position += block.len() as u64;
+	}
+	write_end(&mut writer, header, &index, position)?;
+	Ok(writer)
+}
+
+pub fn encode_blocks<R: Read + Send, W: Write + Send>(
+	reader: R,
+	writer: W,
+	header: &Header,
+	threads: usize,
+) -> AnyResult<(R, W, Digest)> {
+	let block_size: u32 = header.block_size().unwrap_or(u32::MAX);
+	let threads: usize = thread_count(threads);
+	// bounded queues keep at most a few blocks per thread in memory
+	let (job_sender, job_receiver): BlockQueue<BlockJob<Vec<u8>>> = sync_channel(threads);
+	let (pending_sender, pending_receiver): BlockQueue<Receiver<BlockResult>> =
+		sync_channel(threads);
+	let job_receiver: Mutex<Receiver<BlockJob<Vec<u8>>>> = Mutex::new(job_receiver);
+	scope(|scope| {
+		let block_reader: ScopedJoinHandle<AnyResult<(R, Digest)>> = scope.spawn(|| {
+			run_block_reader(
+				reader,
+				block_size as usize,
+				job_sender,
+				pending_sender,
+				header.digest(),
+			)
+		});
+		let block_encoders: Vec<ScopedJoinHandle<AnyResult<()>>> = (0..threads)
+			.map(|_| {
+				scope.spawn(|| {
+					run_block_worker(&job_receiver, |block: Vec<u8>| encode_block(header, &block))
+				})
+			})
+			.collect();
+		let block_writer: ScopedJoinHandle<AnyResult<W>> =
+			scope.spawn(|| run_block_writer(writer, header, pending_receiver));
+		// a failed writer breaks the queues of the reader, so its error is the one to report
+		let returned_reader: AnyResult<(R, Digest)> = thread_join(block_reader);
+		for block_encoder in block_encoders {
+			thread_join(block_encoder)?;
+		}
+		let returned_writer: W = thread_join(block_writer)?;
+		let (returned_reader, digest): (R, Digest) = returned_reader?;
+		Ok((returned_reader, returned_writer, digest))
+	})
+}
+
+// the output already holds the header
+pub fn encode_slice_blocks(
+	input: &[u8],
+	header: &Header,
+	mut output: Vec<u8>,
+) -> AnyResult<Vec<u8>> {
+	let block_size: u32 = header.block_size().unwrap_or(u32::MAX);
+	let mut index: BlockIndex = BlockIndex::new();
+	for block in input.chunks(block_size as usize) {
+		index.push(block.len() as u32, output.len() as u64);
+		output.extend_from_slice(&encode_block(header, block)?);
+	}
+	let position: u64 = output.len() as u64;
+	write_end(&mut output, header, &index, position)?;
+	Ok(output)
+}
+
+// -----------------------------------------------
+
+fn check_block_header(
+	header: &Header,
+	offset: u64,
+	length: u32,
+	compressed_length: u32,
+) -> AnyResult<()> {
+	if (length == 0) != (compressed_length == 0)
+		|| u64::from(compressed_length) > max_compressed_length(length)
+		|| header
+			.block_size()
+			.is_some_and(|block_size| length > block_size)
+	{
+		return Err(FormatError::CorruptBlock { offset }.into());
+	}
+	Ok(())
+}
+
+fn read_le_u32(buffer: &[u8]) -> u32 {
+	u32::from_le_bytes(buffer[..4].try_into().unwrap())
+}
+
+fn read_raw<R: Reader<u8>>(reader: &mut R, buffer: &mut [u8], offset: u64) -> AnyResult<()> {
+	for (index, value) in buffer.iter_mut().enumerate() {
+		*value = reader.read()?.ok_or(FormatError::Truncated {
+			offset: offset + index as u64,
+		})?;
+	}
+	Ok(())
+}
+
+// decode a whole block, which must end exactly with its compressed data
+pub fn decode_block(header: &Header, block: CompressedBlock) -> BlockResult {
+	let position: u64 = block.offset + BLOCK_HEADER_SIZE as u64;
+	let end: u64 = position + block.data.len() as u64;
+	let mut decoder: CombinedContextDecoder<&[u8]> =
+		CombinedContextDecoder::new(block.data.as_slice(), header, position)?;
+	let mut output: Vec<u8> = Vec::with_capacity(block.length as usize);
+	loop {
+		match decoder.decode() {
+			Ok(Some(next_byte)) if output.len() < block.length as usize => {
+				output.push(next_byte.into());
+			}
+			Ok(None) if output.len() == block.length as usize && decoder.position() == end => {
+				return Ok(output);
+			}
+			Ok(_) | Err(AnyError::Format(FormatError::Truncated { .. })) => {
+				return Err(FormatError::CorruptBlock {
+					offset: block.offset,
+				}
+				.into());
+			}
+			Err(error) => return Err(error),
+		}
+	}
+}
+
+// read the blocks starting at the given offset and return the offset right after their end
+fn run_block_dispatcher<R: Reader<u8>>(
+	reader: &mut R,
+	header: &Header,
+	position: u64,
+	jobs: SyncSender<BlockJob<CompressedBlock>>,
+	pending: SyncSender<Receiver<BlockResult>>,
+) -> AnyResult<u64> {
+	// the offsets in the index are relative to the start of the stream
+	let base: u64 = position - header.size();
+	let mut index: BlockIndex = BlockIndex::new();
+	let mut position: u64 = position;
+	loop {
+		let offset: u64 = position;
+		let mut buffer: [u8; BLOCK_HEADER_SIZE] = [0; BLOCK_HEADER_SIZE];
+		read_raw(reader, &mut buffer, position)?;
+		let length: u32 = read_le_u32(&buffer);
+		let compressed_length: u32 = read_le_u32(&buffer[4..]);
+		check_block_header(header, offset, length, compressed_length)?;
+		position += BLOCK_HEADER_SIZE as u64;
+		if length == 0 {
+			if header.is_seekable() {
+				index.check(base, position, || {
+					let mut buffer: [u8; 8] = [0; 8];
+					read_raw(reader, &mut buffer, position)?;
+					position += 8;
+					Ok(u64::from_le_bytes(buffer))
+				})?;
+			}
+			return Ok(position);
+		}
+		if header.is_seekable() {
+			index.push(length, offset - base);
+		}
+		// the buffer grows with what is actually read, so a forged length allocates nothing
+		let mut data: Vec<u8> = Vec::new();
+		for _ in 0..compressed_length {
+			data.push(
+				reader
+					.read()?
+					.ok_or(FormatError::Truncated { offset: position })?,
+			);
+			position += 1;
+		}
+		let block: CompressedBlock = CompressedBlock {
+			offset,
+			length,
+			data,
+		};
+		dispatch(&jobs, &pending, block)?;
+	}
+}
+
+fn run_block_collector<W: Writer<u8>>(
+	pending: Receiver<Receiver<BlockResult>>,
+	writer: &mut W,
+	mut digest: Digest,
+) -> AnyResult<Digest> {
+	for receiver in pending {
+		let block: Vec<u8> = receiver.recv()??;
+		for &value in &block {
+			writer.write(value)?;
+		}
+		digest.update_slice(&block);
+	}
+	Ok(digest)
+}
+
+// decode the blocks of one member starting at the given offset on a pool of threads, and return
+// the digest of the output with the offset right after the end of the blocks
+pub fn decode_blocks<R: Reader<u8>, W: Writer<u8> + Send>(
+	reader: &mut R,
+	writer: &mut W,
+	header: &Header,
+	position: u64,
+	threads: usize,
+) -> AnyResult<(Digest, u64)> {
+	let threads: usize = thread_count(threads);
+	let (job_sender, job_receiver): BlockQueue<BlockJob<CompressedBlock>> = sync_channel(threads);
+	let (pending_sender, pending_receiver): BlockQueue<Receiver<BlockResult>> =
+		sync_channel(threads);
+	let job_receiver: Mutex<Receiver<BlockJob<CompressedBlock>>> = Mutex::new(job_receiver);
+	scope(|scope| {
+		let block_decoders: Vec<ScopedJoinHandle<AnyResult<()>>> = (0..threads)
+			.map(|_| {
+				scope.spawn(|| {
+					run_block_worker(&job_receiver, |block: CompressedBlock| {
+						decode_block(header, block)
+					})
+				})
+			})
+			.collect();
+		let block_collector: ScopedJoinHandle<AnyResult<Digest>> =
+			scope.spawn(|| run_block_collector(pending_receiver, writer, header.digest()));
+		// the blocks are read on the calling thread, which owns the input
+		let dispatched: AnyResult<u64> =
+			run_block_dispatcher(reader, header, position, job_sender, pending_sender);
+		// a failed thread breaks the queues of the threads before it, so report the errors of the
+		// blocks in order first, then the output, then the malformed input
+		for block_decoder in block_decoders {
+			thread_join(block_decoder)?;
+		}
+		let digest: Digest = thread_join(block_collector)?;
+		Ok((digest, dispatched?))
+	})
+}
+
+// -----------------------------------------------
+
+struct Block {
+	offset: u64,
+	length: u32,
+	decoded: u32,
+	end: u64,
+}
+
+// decode the compressed stream after the header on the calling thread, which is either a single
+// stream or, if the header has a block size, a sequence of independent blocks
+pub struct BlockDecoder<R: Reader<u8>> {
+	decoder: CombinedContextDecoder<R>,
+	header: Header,
+	block: Option<Block>,
+	index: BlockIndex,
+	base: u64,
+}
+
+impl<R: Reader<u8>> BlockDecoder<R> {
+	// the position is the offset of the compressed stream in the whole input
+	pub fn new(reader: R, header: &Header, position: u64) -> AnyResult<Self> {
+		let mut decoder: Self = Self {
+			decoder: CombinedContextDecoder::new(reader, header, position)?,
+			header: header.clone(),
+			block: None,
+			index: BlockIndex::new(),
+			base: position - header.size(),
+		};
+		if header.block_size().is_some() {
+			decoder.next_block()?;
+		}
+		Ok(decoder)
+	}
+
+	// the offset right after the compressed stream once decode returned None
+	pub fn position(&self) -> u64 {
+		self.decoder.position()
+	}
+
+	fn next_block(&mut self) -> AnyResult<()> {
+		let offset: u64 = self.decoder.position();
+		let mut buffer: [u8; BLOCK_HEADER_SIZE] = [0; BLOCK_HEADER_SIZE];
+		self.decoder.read_raw(&mut buffer)?;
+		let length: u32 = read_le_u32(&buffer);
+		let compressed_length: u32 = read_le_u32(&buffer[4..]);
+		check_block_header(&self.header, offset, length, compressed_length)?;
+		self.block = if length == 0 {
+			if self.header.is_seekable() {
+				let decoder: &mut CombinedContextDecoder<R> = &mut self.decoder;
+				self.index.check(self.base, decoder.position(), || {
+					let mut buffer: [u8; 8] = [0; 8];
+					decoder.read_raw(&mut buffer)?;
+					Ok(u64::from_le_bytes(buffer))
+				})?;
+			}
+			None
+		} else {
+			if self.header.is_seekable() {
+				self.index.push(length, offset - self.base);
+			}
+			self.decoder.reset()?;
+			Some(Block {
+				offset,
+				length,
+				decoded: 0,
+				end: self.decoder.position() + compressed_length as u64,
+			})
+		};
+		Ok(())
+	}
+
+	// decode the next byte, or None at the end of the stream
+	pub fn decode(&mut self) -> AnyResult<Option<Byte>> {
+		if self.header.block_size().is_none() {
+			return self.decoder.decode();
+		}
+		while let Some(block) = &mut self.block {
+			match self.decoder.decode()? {
+				Some(next_byte) if block.decoded < block.length => {
+					block.decoded += 1;
+					return Ok(Some(next_byte));
+				}
+				None if block.decoded == block.length && self.decoder.position() == block.end => {
+					self.next_block()?;
+				}
+				_ => {
+					return Err(FormatError::CorruptBlock {
+						offset: block.offset,
+					}
+					.into());
+				}
+			}
+		}
+		Ok(None)
+	}
+}
+
+impl<R: Reader<u8>> Closable<R> for BlockDecoder<R> {
+	fn close(self) -> AnyResult<R> {
+		self.decoder.close()
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{
+		decode_block, encode_slice_blocks, max_compressed_length, read_le_u32, CompressedBlock,
+		BLOCK_HEADER_SIZE,
+	};
+	use crate::basic::{AnyError, AnyResult, FormatError};
+	use crate::container::Header;
+	use crate::{decompress, Options};
+
+	#[test]
+	fn test_blocks() -> AnyResult<()> {
+		// random bytes, the worst case for the bound of the compressed length
+		let mut seed: u32 = 0x12345678;
+		let data: Vec<u8> = (0..10000)
+			.map(|_| {
+				seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+				(seed >> 16) as u8
+			})
+			.collect();
+		let options: Options = Options::new().checksum(false).block_size(4000);
+		let header: Header = Header::new(&options);
+		let mut stream: Vec<u8> = Vec::new();
+		header.write(&mut stream)?;
+		let stream: Vec<u8> = encode_slice_blocks(&data, &header, stream)?;
+
+		// blocks of the block size after the header, each decoded on its own, then the end of blocks
+		let mut offset: usize = header.size() as usize;
+		let mut blocks: Vec<CompressedBlock> = Vec::new();
+		loop {
+			let length: u32 = read_le_u32(&stream[offset..]);
+			let compressed_length: usize = read_le_u32(&stream[offset + 4..]) as usize;
+			if length == 0 {
+				assert_eq!(compressed_length, 0);
+				break;
+			}
+			assert!(compressed_length as u64 <= max_compressed_length(length));
+			let start: usize = offset + BLOCK_HEADER_SIZE;
+			blocks.push(CompressedBlock {
+				offset: offset as u64,
+				length,
+				data: stream[start..start + compressed_length].to_vec(),
+			});
+			offset = start + compressed_length;
+		}
+		assert_eq!(offset + BLOCK_HEADER_SIZE, stream.len());
+		let lengths: Vec<u32> = blocks.iter().map(|block| block.length).collect();
+		assert_eq!(lengths, [4000, 4000, 2000]);
+		let block: CompressedBlock = blocks.remove(1);
+		let second: usize = block.offset as usize;
+		assert_eq!(decode_block(&header, block)?, &data[4000..8000]);
+
+		// a block must end right with its data
+		for data in [&stream[second + 8..second + 20], &stream[second + 8..]] {
+			let block: CompressedBlock = CompressedBlock {
+				offset: second as u64,
+				length: 4000,
+				data: data.to_vec(),
+			};
+			assert!(matches!(
+				decode_block(&header, block),
+				Err(AnyError::Format(FormatError::CorruptBlock { offset })) if offset == second as u64
+			));
+		}
+
+		// decoded on a pool of threads, and a compressed length that no block of its length can
+		// reach is rejected before reading it
+		let threads: Options = options.clone().threads(3);
+		let (_, decompressed): (&[u8], Vec<u8>) = decompress(&stream[..], Vec::new(), &threads)?;
+		assert_eq!(decompressed, data);
+		let mut forged: Vec<u8> = stream.clone();
+		forged[second + 4..second + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+		assert!(matches!(
+			decompress(&forged[..], Vec::new(), &threads),
+			Err(AnyError::Format(FormatError::CorruptBlock { offset })) if offset == second as u64
+		));
+		Ok(())
+	}
+}
@@ -16,100 +16,408 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+use super::block::decode_blocks;
+use super::encoder::{new_contexts, Contexts};
+use super::predictor::{BitPredictor, Predictor, StatePredictor, StreamPredictor};
 use super::shared::{run_file_reader, run_file_writer, thread_join};
-use crate::basic::{pipe, AnyResult, Byte, Closable, PipedReader, PipedWriter, Writer};
-use crate::bridged_context::{BridgedContextInfo, BridgedPrimaryContext, BridgedSecondaryContext};
+use crate::basic::{
+	pipe, AnyResult, Byte, Closable, Digest, FormatError, PipedReader, PipedWriter, Reader, Writer,
+};
+use crate::bridged_context::{
+	BridgedContextInfo, BridgedMatchModel, BridgedPrimaryContext, ContextLayout, LiteralBit,
+	LiteralContext,
+};
+use crate::container::{check_end, read_member, Dictionary, Header};
+use crate::mixing::{BitKind, MixingModel};
 use crate::primary_context::ByteMatched;
-use crate::secondary_context::{Bit, BitDecoder, StateInfo};
+use crate::secondary_context::{Bit, BitDecoder};
 use std::io::{Read, Write};
 use std::thread::{scope, ScopedJoinHandle};
 
 // -----------------------------------------------
 
-struct CombinedContextDecoder<const IO_BUFFER_SIZE: usize> {
+// the models of a stream but its predictor, which picks the decoder
+struct Models {
+	primary: BridgedPrimaryContext,
+	fallback: Option<BridgedPrimaryContext>,
+	long_match: Option<BridgedMatchModel>,
+	layout: ContextLayout,
+	mixing: Option<Box<MixingModel>>,
+}
+
+fn new_models(header: &Header) -> AnyResult<(Models, StreamPredictor)> {
+	let contexts: Contexts = new_contexts(header)?;
+	let models: Models = Models {
+		primary: contexts.primary,
+		fallback: contexts.fallback,
+		long_match: contexts.long_match,
+		layout: contexts.layout,
+		mixing: contexts.mixing,
+	};
+	Ok((models, contexts.predictor))
+}
+
+// the decoder of a stream, which for a plain stream, with OPTIONAL false, is instantiated without
+// any of the checks for the kinds of primary context, the fallback context, the match model,
+// exclusion and the mixing model
+struct ContextDecoder<R: Reader<u8>, P: Predictor, const OPTIONAL: bool> {
 	primary_context: BridgedPrimaryContext,
-	secondary_context: BridgedSecondaryContext,
-	decoder: BitDecoder<IO_BUFFER_SIZE>,
-	writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+	fallback_context: Option<BridgedPrimaryContext>,
+	long_match: Option<BridgedMatchModel>,
+	layout: ContextLayout,
+	predictor: P,
+	mixing: Option<Box<MixingModel>>,
+	decoder: BitDecoder<R>,
 }
 
-impl<const IO_BUFFER_SIZE: usize> CombinedContextDecoder<IO_BUFFER_SIZE> {
+impl<R: Reader<u8>, P: Predictor, const OPTIONAL: bool> ContextDecoder<R, P, OPTIONAL> {
+	fn new(contexts: Models, predictor: P, decoder: BitDecoder<R>) -> Self {
+		Self {
+			primary_context: contexts.primary,
+			fallback_context: contexts.fallback,
+			long_match: contexts.long_match,
+			layout: contexts.layout,
+			predictor,
+			mixing: contexts.mixing,
+			decoder,
+		}
+	}
+
+	// forget everything, keeping the bit decoder
+	fn reset(&mut self, contexts: Models, predictor: P) {
+		self.primary_context = contexts.primary;
+		self.fallback_context = contexts.fallback;
+		self.long_match = contexts.long_match;
+		self.predictor = predictor;
+		self.mixing = contexts.mixing;
+		self.decoder.reset();
+	}
+
 	#[inline(always)]
-	fn bit(&mut self, context_index: usize) -> AnyResult<Bit> {
-		let current_state: StateInfo = self.secondary_context.get_info(context_index);
-		let bit: Bit = self.decoder.bit(current_state.prediction())?;
-		self.secondary_context
-			.update(current_state, context_index, bit);
+	fn bit(&mut self, context_index: usize, kind: BitKind, symbol: usize) -> AnyResult<Bit> {
+		let refined: u32 = self.predictor.predict(context_index);
+		let bit: Bit = match (OPTIONAL, &mut self.mixing) {
+			(true, Some(model)) => {
+				let bit: Bit = self.decoder.bit(model.predict(refined, kind, symbol))?;
+				model.update(bit);
+				bit
+			}
+			_ => self.decoder.bit(refined)?,
+		};
+		self.predictor.update(bit);
 		Ok(bit)
 	}
 
+	#[inline(always)]
+	fn literal_bit(&mut self, context_index: usize, node: usize) -> AnyResult<Bit> {
+		self.bit(context_index + node, BitKind::Literal, node)
+	}
+
 	fn byte(&mut self, context_index: usize) -> AnyResult<Byte> {
 		let mut high: usize = 1;
-		high += high + usize::from(self.bit(context_index + high)?);
-		high += high + usize::from(self.bit(context_index + high)?);
-		high += high + usize::from(self.bit(context_index + high)?);
-		high += high + usize::from(self.bit(context_index + high)?);
-		let low_context: usize = context_index + 15 * (high - 15);
+		high = high * 2 + usize::from(self.literal_bit(context_index, high)?);
+		high = high * 2 + usize::from(self.literal_bit(context_index, high)?);
+		high = high * 2 + usize::from(self.literal_bit(context_index, high)?);
+		high = high * 2 + usize::from(self.literal_bit(context_index, high)?);
+		let low_node: usize = 15 * (high - 15);
 		let mut low: usize = 1;
-		low += low + usize::from(self.bit(low_context + low)?);
-		low += low + usize::from(self.bit(low_context + low)?);
-		low += low + usize::from(self.bit(low_context + low)?);
-		low += low + usize::from(self.bit(low_context + low)?);
+		low = low * 2 + usize::from(self.literal_bit(context_index, low_node + low)?);
+		low = low * 2 + usize::from(self.literal_bit(context_index, low_node + low)?);
+		low = low * 2 + usize::from(self.literal_bit(context_index, low_node + low)?);
+		low = low * 2 + usize::from(self.literal_bit(context_index, low_node + low)?);
 		return Ok(Byte::from(((high - 16) << 4) | (low - 16)));
 	}
 
-	fn decode(mut self) -> AnyResult<()> {
-		loop {
-			let info: BridgedContextInfo = BridgedContextInfo::new(
-				self.primary_context.get_history(),
-				self.primary_context.previous_byte(),
-				self.primary_context.hash_value(),
-			);
-			let (next_byte, matched): (Byte, ByteMatched) = match self.bit(info.first_context())? {
-				// match first
-				Bit::Zero => (info.first_byte(), ByteMatched::FIRST),
-				// match next
-				Bit::One => match self.bit(info.second_context())? {
-					// literal
-					Bit::Zero => {
-						let next_byte: Byte = self.byte(info.literal_context())?;
-						if next_byte == info.first_byte() {
-							// eof, gave the reader/writer back
-							self.decoder.close()?;
-							self.writer.close()?;
-							return Ok(());
-						}
-						(next_byte, ByteMatched::NONE)
-					}
+	// the literal coded with exclusion, whose bits that can only be one way are not coded
+	fn excluded_byte(&mut self, literal: &LiteralContext) -> AnyResult<Byte> {
+		let mut value: usize = 0;
+		for depth in 0..8 {
+			let bit: Bit = match literal.bit(value, depth) {
+				LiteralBit::Coded(context, node) => self.bit(context, BitKind::Literal, node)?,
+				LiteralBit::Known(bit) => bit,
+			};
+			value = value * 2 + usize::from(bit);
+		}
+		Ok(Byte::from(value as u8))
+	}
+
+	// after a miss of the primary context, the byte of the fallback context if any matched
+	fn fallback_byte(
+		&mut self,
+		info: &BridgedContextInfo,
+		fallback: Option<&BridgedContextInfo>,
+	) -> AnyResult<Option<Byte>> {
+		if let Some(fallback) = fallback {
+			for flag in 0..3 {
+				if !fallback.is_candidate(flag, info, self.primary_context.ranks()) {
+					continue;
+				}
+				let symbol: usize = usize::from(fallback.byte(flag));
+				let context: usize = fallback.fallback_context(flag);
+				if self.bit(context, BitKind::Third, symbol)? == Bit::Zero {
+					return Ok(Some(fallback.byte(flag)));
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	// the byte expected by the match model if any, and whether its flag tells it is the next byte
+	fn expected(&mut self, info: &BridgedContextInfo) -> AnyResult<Option<(Byte, Bit)>> {
+		if !OPTIONAL {
+			return Ok(None);
+		}
+		match self
+			.long_match
+			.as_ref()
+			.and_then(BridgedMatchModel::expected)
+		{
+			None => Ok(None),
+			Some((expected, length)) => {
+				let context: usize = info.match_context(expected, length);
+				let symbol: usize = usize::from(expected);
+				Ok(Some((expected, self.bit(context, BitKind::First, symbol)?)))
+			}
+		}
+	}
+
+	// decode the next byte, or None at the end of the stream
+	fn decode(&mut self) -> AnyResult<Option<Byte>> {
+		let fallback: Option<BridgedContextInfo> = match OPTIONAL {
+			false => None,
+			true => self
+				.fallback_context
+				.as_ref()
+				.map(|context: &BridgedPrimaryContext| {
+					BridgedContextInfo::from_context(self.layout, context)
+				}),
+		};
+		let info: BridgedContextInfo =
+			BridgedContextInfo::from_context(self.layout, &self.primary_context)
+				.with_fallback(fallback.as_ref());
+		let first: usize = usize::from(info.first_byte());
+		let second: usize = usize::from(info.second_byte());
+		let expected: Option<(Byte, Bit)> = self.expected(&info)?;
+		let (next_byte, matched): (Byte, ByteMatched) = match expected {
+			// the match model was right, and the byte is ranked like the encoder does
+			Some((expected, Bit::Zero)) => (
+				expected,
+				info.rank_of(expected, self.primary_context.ranks()),
+			),
+			_ => {
+				// the first byte needs no flag when the match model expected it in vain
+				let first_bit: Bit = match expected {
+					Some((expected, _)) if expected == info.first_byte() => Bit::One,
+					_ => self.bit(info.first_context(), BitKind::First, first)?,
+				};
+				match first_bit {
+					// match first
+					Bit::Zero => (info.first_byte(), ByteMatched::FIRST),
 					// match next
-					Bit::One => match self.bit(info.third_context())? {
-						// match second
-						Bit::Zero => (info.second_byte(), ByteMatched::SECOND),
-						// match third
-						Bit::One => (info.third_byte(), ByteMatched::THIRD),
+					Bit::One => match self.bit(info.second_context(), BitKind::Second, second)? {
+						// literal, unless the fallback context matched
+						Bit::Zero => match self.fallback_byte(&info, fallback.as_ref())? {
+							Some(next_byte) => (next_byte, ByteMatched::NONE),
+							None => {
+								let next_byte: Byte = match OPTIONAL && self.layout.has_exclusion()
+								{
+									false => self.byte(info.literal_context())?,
+									true => {
+										let literal: LiteralContext = info.excluding_literal(
+											fallback.as_ref(),
+											expected.map(|(expected, _)| expected),
+											self.primary_context.ranks(),
+										);
+										self.excluded_byte(&literal)?
+									}
+								};
+								if next_byte == info.first_byte() {
+									// eof
+									self.decoder.finish()?;
+									return Ok(None);
+								}
+								(next_byte, ByteMatched::NONE)
+							}
+						},
+						// match second or deeper, one flag per rank
+						Bit::One => {
+							let mut rank: usize = 1;
+							while rank < self.primary_context.ranks() - 1 {
+								let next: usize = usize::from(info.byte(rank + 1));
+								match self.bit(info.rank_context(rank), BitKind::Third, next)? {
+									Bit::Zero => break,
+									Bit::One => rank += 1,
+								}
+							}
+							(info.byte(rank), ByteMatched::from_rank(rank))
+						}
 					},
-				},
-			};
-			self.writer.write(next_byte.into())?;
-			self.primary_context
-				.matched(info.current_state(), next_byte, matched);
+				}
+			}
+		};
+		match OPTIONAL {
+			false => self
+				.primary_context
+				.plain_matched(info.current_state(), next_byte, matched),
+			true => self
+				.primary_context
+				.matched(info.current_state(), next_byte, matched),
+		}
+		if let (Some(context), Some(fallback)) = (&mut self.fallback_context, &fallback) {
+			context.matching(fallback.current_state(), next_byte);
+		}
+		if let (true, Some(model)) = (OPTIONAL, &mut self.long_match) {
+			model.push(next_byte);
 		}
+		if let (true, Some(model)) = (OPTIONAL, &mut self.mixing) {
+			model.push(next_byte);
+		}
+		Ok(Some(next_byte))
 	}
 }
 
 // -----------------------------------------------
 
-fn run_combined_context_decoder<const IO_BUFFER_SIZE: usize>(
-	reader: PipedReader<u8, IO_BUFFER_SIZE>,
-	writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+// the decoder of either kind of stream, picked once from the header
+enum StreamDecoder<R: Reader<u8>> {
+	Plain(ContextDecoder<R, StatePredictor, false>),
+	Refined(ContextDecoder<R, BitPredictor, true>),
+}
+
+pub struct CombinedContextDecoder<R: Reader<u8>> {
+	decoder: StreamDecoder<R>,
+	// to start the next compressed stream with the same model
+	header: Header,
+}
+
+impl<R: Reader<u8>> CombinedContextDecoder<R> {
+	// the position is the offset of the compressed stream in the whole input
+	pub fn new(reader: R, header: &Header, position: u64) -> AnyResult<Self> {
+		let (contexts, predictor): (Models, StreamPredictor) = new_models(header)?;
+		let decoder: BitDecoder<R> = BitDecoder::new(reader, position, !header.is_legacy());
+		Ok(Self {
+			decoder: match predictor {
+				StreamPredictor::Plain(predictor) => {
+					StreamDecoder::Plain(ContextDecoder::new(contexts, predictor, decoder))
+				}
+				StreamPredictor::Refined(predictor) => {
+					StreamDecoder::Refined(ContextDecoder::new(contexts, predictor, decoder))
+				}
+			},
+			header: header.clone(),
+		})
+	}
+
+	fn bit_decoder(&mut self) -> &mut BitDecoder<R> {
+		match &mut self.decoder {
+			StreamDecoder::Plain(decoder) => &mut decoder.decoder,
+			StreamDecoder::Refined(decoder) => &mut decoder.decoder,
+		}
+	}
+
+	// the offset right after the compressed stream once decode returned None
+	pub fn position(&self) -> u64 {
+		match &self.decoder {
+			StreamDecoder::Plain(decoder) => decoder.decoder.position(),
+			StreamDecoder::Refined(decoder) => decoder.decoder.position(),
+		}
+	}
+
+	// read bytes stored between two compressed streams
+	pub fn read_raw(&mut self, buffer: &mut [u8]) -> AnyResult<()> {
+		let decoder: &mut BitDecoder<R> = self.bit_decoder();
+		for value in buffer.iter_mut() {
+			*value = decoder.read_raw()?;
+		}
+		Ok(())
+	}
+
+	// forget everything and start decoding the next compressed stream
+	pub fn reset(&mut self) -> AnyResult<()> {
+		let (contexts, predictor): (Models, StreamPredictor) = new_models(&self.header)?;
+		match (&mut self.decoder, predictor) {
+			(StreamDecoder::Plain(decoder), StreamPredictor::Plain(predictor)) => {
+				decoder.reset(contexts, predictor)
+			}
+			(StreamDecoder::Refined(decoder), StreamPredictor::Refined(predictor)) => {
+				decoder.reset(contexts, predictor)
+			}
+			// the same header always picks the same predictor
+			_ => unreachable!(),
+		}
+		Ok(())
+	}
+
+	// decode the next byte, or None at the end of the stream
+	pub fn decode(&mut self) -> AnyResult<Option<Byte>> {
+		match &mut self.decoder {
+			StreamDecoder::Plain(decoder) => decoder.decode(),
+			StreamDecoder::Refined(decoder) => decoder.decode(),
+		}
+	}
+}
+
+impl<R: Reader<u8>> Closable<R> for CombinedContextDecoder<R> {
+	fn close(self) -> AnyResult<R> {
+		match self.decoder {
+			StreamDecoder::Plain(decoder) => decoder.decoder.close(),
+			StreamDecoder::Refined(decoder) => decoder.decoder.close(),
+		}
+	}
+}
+
+// -----------------------------------------------
+
+// decode a single stream member starting at the given offset, and return the digest of the output
+// with the offset right after the compressed stream
+fn decode_stream<R: Reader<u8>, W: Writer<u8>>(
+	reader: R,
+	writer: &mut W,
+	header: &Header,
+	position: u64,
+) -> AnyResult<(Digest, u64)> {
+	let mut decoder: CombinedContextDecoder<R> =
+		CombinedContextDecoder::new(reader, header, position)?;
+	let mut digest: Digest = header.digest();
+	while let Some(next_byte) = decoder.decode()? {
+		writer.write(next_byte.into())?;
+		digest.update(next_byte.into());
+	}
+	let position: u64 = decoder.position();
+	decoder.close()?;
+	Ok((digest, position))
+}
+
+// decode every member of the input one after another, like gzip does
+fn run_member_decoder<const IO_BUFFER_SIZE: usize>(
+	mut reader: PipedReader<u8, IO_BUFFER_SIZE>,
+	mut writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+	threads: usize,
+	dictionary: Option<&Dictionary>,
 ) -> AnyResult<()> {
-	let decoder: CombinedContextDecoder<IO_BUFFER_SIZE> = CombinedContextDecoder {
-		primary_context: BridgedPrimaryContext::new(),
-		secondary_context: BridgedSecondaryContext::new(),
-		decoder: BitDecoder::new(reader),
-		writer,
-	};
-	decoder.decode()
+	let mut next_header: Option<Header> = read_member(&mut reader, 0)?;
+	if next_header.is_none() {
+		return Err(FormatError::NotSrx.into());
+	}
+	let mut position: u64 = 0;
+	while let Some(mut header) = next_header {
+		header.attach_dictionary(dictionary)?;
+		position += header.size();
+		let (digest, end): (Digest, u64) = match header.block_size() {
+			None => decode_stream(&mut reader, &mut writer, &header, position)?,
+			Some(_) => decode_blocks(&mut reader, &mut writer, &header, position, threads)?,
+		};
+		// verify the data of the member, then look for the next one
+		position = header.read_trailer(&mut reader, &digest, end)?;
+		next_header = if header.is_legacy() {
+			check_end(&mut reader, position)?;
+			None
+		} else {
+			read_member(&mut reader, position)?
+		};
+	}
+	reader.close()?;
+	writer.close()
 }
 
 // -----------------------------------------------
@@ -117,6 +425,8 @@ fn run_combined_context_decoder<const IO_BUFFER_SIZE: usize>(
 pub fn decode<R: Read + Send, W: Write + Send, const IO_BUFFER_SIZE: usize>(
 	reader: R,
 	writer: W,
+	threads: usize,
+	dictionary: Option<&Dictionary>,
 ) -> AnyResult<(R, W)> {
 	scope(|scope| {
 		let (input_writer, input_reader): (
@@ -129,13 +439,16 @@ pub fn decode<R: Read + Send, W: Write + Send, const IO_BUFFER_SIZE: usize>(
 		) = pipe::<u8, IO_BUFFER_SIZE>();
 		let file_reader: ScopedJoinHandle<AnyResult<R>> =
 			scope.spawn(|| run_file_reader(reader, input_writer));
-		let combined_context_decoder: ScopedJoinHandle<AnyResult<()>> =
-			scope.spawn(|| run_combined_context_decoder(input_reader, output_writer));
+		let member_decoder: ScopedJoinHandle<AnyResult<()>> =
+			scope.spawn(|| run_member_decoder(input_reader, output_writer, threads, dictionary));
 		let file_writer: ScopedJoinHandle<AnyResult<W>> =
 			scope.spawn(|| run_file_writer(output_reader, writer));
-		let returned_reader: R = thread_join(file_reader)?;
-		thread_join(combined_context_decoder)?;
+		// a failed thread breaks the pipes of the threads around it, so report the error of the
+		// output first, then the malformed input, then the error of the input
+		let returned_reader: AnyResult<R> = thread_join(file_reader);
+		let decoded: AnyResult<()> = thread_join(member_decoder);
 		let returned_writer: W = thread_join(file_writer)?;
-		Ok((returned_reader, returned_writer))
+		decoded?;
+		Ok((returned_reader?, returned_writer))
 	})
 }
@@ -16,11 +16,19 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use super::shared::{run_file_reader, run_file_writer, thread_join};
-use crate::basic::{pipe, AnyResult, Byte, Closable, PipedReader, PipedWriter, Reader, Writer};
-use crate::bridged_context::{BridgedContextInfo, BridgedPrimaryContext, BridgedSecondaryContext};
+use super::predictor::{BitPredictor, Predictor, StatePredictor, StreamPredictor};
+use super::shared::{run_digested_file_reader, run_file_writer, thread_join};
+use crate::basic::{
+	pipe, AnyResult, Byte, Closable, Digest, PipedReader, PipedWriter, Reader, Writer,
+};
+use crate::bridged_context::{
+	BridgedContextInfo, BridgedMatchModel, BridgedPrimaryContext, ContextLayout, LiteralContext,
+	PlainContextInfo, FALLBACK_CONTEXT_ORDER,
+};
+use crate::container::{Dictionary, Header};
+use crate::mixing::{BitKind, MixingModel};
 use crate::primary_context::ByteMatched;
-use crate::secondary_context::{Bit, BitEncoder, StateInfo};
+use crate::secondary_context::{Bit, BitEncoder};
 use std::io::{Read, Write};
 use std::thread::{scope, ScopedJoinHandle};
 
@@ -32,14 +40,8 @@ enum Message {
 	Byte(usize, Byte),
 }
 
-#[derive(Copy, Clone)]
-struct PackedMessage(u32);
-
-impl Default for PackedMessage {
-	fn default() -> Self {
-		Self(0)
-	}
-}
+#[derive(Copy, Clone, Default)]
+pub struct PackedMessage(u32);
 
 impl PackedMessage {
 	fn bit(context: usize, bit: Bit) -> Self {
@@ -61,74 +63,337 @@ impl PackedMessage {
 
 // -----------------------------------------------
 
-fn run_primary_context_encoder<const IO_BUFFER_SIZE: usize, const MESSAGE_BUFFER_SIZE: usize>(
-	mut reader: PipedReader<u8, IO_BUFFER_SIZE>,
-	mut writer: PipedWriter<PackedMessage, MESSAGE_BUFFER_SIZE>,
-) -> AnyResult<()> {
-	let mut context: BridgedPrimaryContext = BridgedPrimaryContext::new();
-	loop {
-		let info: BridgedContextInfo = BridgedContextInfo::new(
-			context.get_history(),
-			context.previous_byte(),
-			context.hash_value(),
-		);
-		match reader.read()? {
-			None => {
+// where the primary context encoder sends its flags and literals, with the kind of each flag and
+// the byte it stands for, which only the mixing model uses
+pub trait BitSink {
+	fn bit(&mut self, context: usize, kind: BitKind, symbol: usize, bit: Bit) -> AnyResult<()>;
+
+	fn byte(&mut self, context: usize, byte: Byte) -> AnyResult<()>;
+
+	// after every coded byte
+	fn push(&mut self, _byte: Byte) {}
+}
+
+// the fast level packs the flags into messages for the secondary context encoder
+impl<W: Writer<PackedMessage>> BitSink for W {
+	#[inline(always)]
+	fn bit(&mut self, context: usize, _kind: BitKind, _symbol: usize, bit: Bit) -> AnyResult<()> {
+		self.write(PackedMessage::bit(context, bit))
+	}
+
+	#[inline(always)]
+	fn byte(&mut self, context: usize, byte: Byte) -> AnyResult<()> {
+		self.write(PackedMessage::byte(context, byte))
+	}
+}
+
+// -----------------------------------------------
+
+// the encoders of the ranks, which code every byte with the flags of the primary context
+pub trait RankEncoder<W>: Closable<W> {
+	fn encode(&mut self, current_byte: Byte) -> AnyResult<()>;
+}
+
+// the encoder of the ranks of a plain stream, with the default model alone, which sends its flags
+// and literals straight to the secondary context encoder without checking for any other model
+pub struct PlainContextEncoder<W: Writer<PackedMessage>> {
+	context: BridgedPrimaryContext,
+	layout: ContextLayout,
+	writer: W,
+}
+
+impl<W: Writer<PackedMessage>> PlainContextEncoder<W> {
+	pub fn new(context: BridgedPrimaryContext, layout: ContextLayout, writer: W) -> Self {
+		Self {
+			context,
+			layout,
+			writer,
+		}
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.writer
+	}
+}
+
+impl<W: Writer<PackedMessage>> RankEncoder<W> for PlainContextEncoder<W> {
+	#[inline(always)]
+	fn encode(&mut self, current_byte: Byte) -> AnyResult<()> {
+		let info: PlainContextInfo = PlainContextInfo::new(&self.layout, &self.context);
+		let writer: &mut W = &mut self.writer;
+		match self
+			.context
+			.plain_matching(info.current_state(), current_byte)
+		{
+			ByteMatched::FIRST => {
+				writer.write(PackedMessage::bit(info.first_context(), Bit::Zero))?;
+			}
+			ByteMatched::SECOND => {
+				writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
+				writer.write(PackedMessage::bit(info.second_context(), Bit::One))?;
+				writer.write(PackedMessage::bit(info.third_context(), Bit::Zero))?;
+			}
+			ByteMatched::THIRD => {
+				writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
+				writer.write(PackedMessage::bit(info.second_context(), Bit::One))?;
+				writer.write(PackedMessage::bit(info.third_context(), Bit::One))?;
+			}
+			// narrow histories match no deeper rank
+			_ => {
 				writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
 				writer.write(PackedMessage::bit(info.second_context(), Bit::Zero))?;
-				writer.write(PackedMessage::byte(
-					info.literal_context(),
-					info.first_byte(),
-				))?;
-				reader.close()?;
-				writer.close()?;
-				return Ok(());
+				writer.write(PackedMessage::byte(info.literal_context(), current_byte))?;
 			}
-			Some(current_byte) => {
-				match context.matching(info.current_state(), Byte::from(current_byte)) {
-					ByteMatched::FIRST => {
-						writer.write(PackedMessage::bit(info.first_context(), Bit::Zero))?;
-					}
-					ByteMatched::NONE => {
-						writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
-						writer.write(PackedMessage::bit(info.second_context(), Bit::Zero))?;
-						writer.write(PackedMessage::byte(
-							info.literal_context(),
-							Byte::from(current_byte),
-						))?;
-					}
-					ByteMatched::SECOND => {
-						writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
-						writer.write(PackedMessage::bit(info.second_context(), Bit::One))?;
-						writer.write(PackedMessage::bit(info.third_context(), Bit::Zero))?;
-					}
-					ByteMatched::THIRD => {
-						writer.write(PackedMessage::bit(info.first_context(), Bit::One))?;
-						writer.write(PackedMessage::bit(info.second_context(), Bit::One))?;
-						writer.write(PackedMessage::bit(info.third_context(), Bit::One))?;
+		}
+		Ok(())
+	}
+}
+
+impl<W: Writer<PackedMessage>> Closable<W> for PlainContextEncoder<W> {
+	fn close(mut self) -> AnyResult<W> {
+		// eof is a literal equal to the first byte, which can never be coded as a literal
+		let info: PlainContextInfo = PlainContextInfo::new(&self.layout, &self.context);
+		self.writer
+			.write(PackedMessage::bit(info.first_context(), Bit::One))?;
+		self.writer
+			.write(PackedMessage::bit(info.second_context(), Bit::Zero))?;
+		self.writer.write(PackedMessage::byte(
+			info.literal_context(),
+			info.first_byte(),
+		))?;
+		Ok(self.writer)
+	}
+}
+
+// -----------------------------------------------
+
+// the encoder of the ranks with any of the optional models, whose flags and literals carry what
+// the mixing model needs to know
+pub struct PrimaryContextEncoder<W: BitSink> {
+	context: BridgedPrimaryContext,
+	fallback: Option<BridgedPrimaryContext>,
+	long_match: Option<BridgedMatchModel>,
+	layout: ContextLayout,
+	writer: W,
+}
+
+impl<W: BitSink> PrimaryContextEncoder<W> {
+	pub fn new(
+		context: BridgedPrimaryContext,
+		fallback: Option<BridgedPrimaryContext>,
+		long_match: Option<BridgedMatchModel>,
+		layout: ContextLayout,
+		writer: W,
+	) -> Self {
+		Self {
+			context,
+			fallback,
+			long_match,
+			layout,
+			writer,
+		}
+	}
+
+	#[inline(always)]
+	fn info(&self) -> (BridgedContextInfo, Option<BridgedContextInfo>) {
+		let fallback: Option<BridgedContextInfo> =
+			self.fallback
+				.as_ref()
+				.map(|context: &BridgedPrimaryContext| {
+					BridgedContextInfo::from_context(self.layout, context)
+				});
+		let info: BridgedContextInfo = BridgedContextInfo::from_context(self.layout, &self.context)
+			.with_fallback(fallback.as_ref());
+		(info, fallback)
+	}
+
+	// the flag of the byte expected by the match model if any, which is returned, telling whether
+	// it is the current byte, coded like a first flag
+	fn expected(
+		&mut self,
+		info: &BridgedContextInfo,
+		current_byte: Option<Byte>,
+	) -> AnyResult<Option<Byte>> {
+		match self
+			.long_match
+			.as_ref()
+			.and_then(BridgedMatchModel::expected)
+		{
+			None => Ok(None),
+			Some((expected, length)) => {
+				let missed: bool = Some(expected) != current_byte;
+				let context: usize = info.match_context(expected, length);
+				let symbol: usize = usize::from(expected);
+				self.writer
+					.bit(context, BitKind::First, symbol, Bit::from(missed))?;
+				Ok(Some(expected))
+			}
+		}
+	}
+
+	// after a miss of the primary context, the flags of the ranks of the fallback context if any,
+	// until the matched one, then the byte itself unless one matched, the first flag being skipped
+	// when the match model already excluded the first byte, and with exclusion the bits of the
+	// byte that can only be one way
+	fn miss(
+		&mut self,
+		info: &BridgedContextInfo,
+		fallback: Option<&BridgedContextInfo>,
+		fallback_rank: Option<usize>,
+		current_byte: Byte,
+		expected: Option<Byte>,
+	) -> AnyResult<()> {
+		let excluded: bool = expected == Some(info.first_byte());
+		let first: usize = usize::from(info.first_byte());
+		let second: usize = usize::from(info.second_byte());
+		let writer: &mut W = &mut self.writer;
+		if !excluded {
+			writer.bit(info.first_context(), BitKind::First, first, Bit::One)?;
+		}
+		writer.bit(info.second_context(), BitKind::Second, second, Bit::Zero)?;
+		if let Some(fallback) = fallback {
+			for flag in 0..3 {
+				if !fallback.is_candidate(flag, info, self.context.ranks()) {
+					continue;
+				}
+				// each coded with the byte it stands for
+				let deeper: bool = match fallback_rank {
+					None => true,
+					Some(rank) => rank > flag,
+				};
+				let symbol: usize = usize::from(fallback.byte(flag));
+				let context: usize = fallback.fallback_context(flag);
+				writer.bit(context, BitKind::Third, symbol, Bit::from(deeper))?;
+				if !deeper {
+					return Ok(());
+				}
+			}
+		}
+		if !self.layout.has_exclusion() {
+			return writer.byte(info.literal_context(), current_byte);
+		}
+		let literal: LiteralContext =
+			info.excluding_literal(fallback, expected, self.context.ranks());
+		for (context, node, bit) in literal.coded_bits(current_byte) {
+			writer.bit(context, BitKind::Literal, node, bit)?;
+		}
+		Ok(())
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.writer
+	}
+}
+
+impl<W: BitSink> RankEncoder<W> for PrimaryContextEncoder<W> {
+	fn encode(&mut self, current_byte: Byte) -> AnyResult<()> {
+		let (info, fallback): (BridgedContextInfo, Option<BridgedContextInfo>) = self.info();
+		let ranks: usize = self.context.ranks();
+		let first: usize = usize::from(info.first_byte());
+		let second: usize = usize::from(info.second_byte());
+		let matched: ByteMatched = self.context.matching(info.current_state(), current_byte);
+		let fallback_rank: Option<usize> = match (&mut self.fallback, &fallback) {
+			(Some(context), Some(fallback)) => context
+				.matching(fallback.current_state(), current_byte)
+				.rank(),
+			_ => None,
+		};
+		let expected: Option<Byte> = self.expected(&info, Some(current_byte))?;
+		if let Some(model) = &mut self.long_match {
+			model.push(current_byte);
+		}
+		let excluded: bool = expected == Some(info.first_byte());
+		let writer: &mut W = &mut self.writer;
+		match matched.rank() {
+			// the match model was right
+			_ if expected == Some(current_byte) => {}
+			Some(0) => {
+				writer.bit(info.first_context(), BitKind::First, first, Bit::Zero)?;
+			}
+			None => self.miss(
+				&info,
+				fallback.as_ref(),
+				fallback_rank,
+				current_byte,
+				expected,
+			)?,
+			Some(rank) => {
+				if !excluded {
+					writer.bit(info.first_context(), BitKind::First, first, Bit::One)?;
+				}
+				writer.bit(info.second_context(), BitKind::Second, second, Bit::One)?;
+				// then one flag per rank until the matched one, the last rank needing none, each
+				// coded with the next byte it stands for
+				for flag in 1..ranks - 1 {
+					let deeper: bool = rank > flag;
+					let next: usize = usize::from(info.byte(flag + 1));
+					writer.bit(
+						info.rank_context(flag),
+						BitKind::Third,
+						next,
+						Bit::from(deeper),
+					)?;
+					if !deeper {
+						break;
 					}
 				}
 			}
 		}
+		self.writer.push(current_byte);
+		Ok(())
+	}
+}
+
+impl<W: BitSink> Closable<W> for PrimaryContextEncoder<W> {
+	fn close(mut self) -> AnyResult<W> {
+		// eof is a literal equal to the first byte, which can never be coded as a literal
+		let (info, fallback): (BridgedContextInfo, Option<BridgedContextInfo>) = self.info();
+		let expected: Option<Byte> = self.expected(&info, None)?;
+		self.miss(&info, fallback.as_ref(), None, info.first_byte(), expected)?;
+		Ok(self.writer)
 	}
 }
 
 // -----------------------------------------------
 
-struct SecondaryContextEncoder<const IO_BUFFER_SIZE: usize, const MESSAGE_BUFFER_SIZE: usize> {
-	context: BridgedSecondaryContext,
-	reader: PipedReader<PackedMessage, MESSAGE_BUFFER_SIZE>,
-	encoder: BitEncoder<IO_BUFFER_SIZE>,
+fn run_primary_context_encoder<
+	E: RankEncoder<PipedWriter<PackedMessage, MESSAGE_BUFFER_SIZE>>,
+	const IO_BUFFER_SIZE: usize,
+	const MESSAGE_BUFFER_SIZE: usize,
+>(
+	mut reader: PipedReader<u8, IO_BUFFER_SIZE>,
+	mut encoder: E,
+) -> AnyResult<()> {
+	while let Some(current_byte) = reader.read()? {
+		encoder.encode(Byte::from(current_byte))?;
+	}
+	reader.close()?;
+	encoder.close()?.close()
+}
+
+// -----------------------------------------------
+
+pub struct SecondaryContextEncoder<W: Writer<u8>, P: Predictor> {
+	predictor: P,
+	encoder: BitEncoder<W>,
 }
 
-impl<const IO_BUFFER_SIZE: usize, const MESSAGE_BUFFER_SIZE: usize>
-	SecondaryContextEncoder<IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE>
-{
+impl<W: Writer<u8>, P: Predictor> SecondaryContextEncoder<W, P> {
+	pub fn new(predictor: P, writer: W) -> Self {
+		Self {
+			predictor,
+			encoder: BitEncoder::new(writer),
+		}
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		self.encoder.get_mut()
+	}
+
 	#[inline(always)]
 	fn bit(&mut self, context_index: usize, bit: Bit) -> AnyResult<()> {
-		let current_state: StateInfo = self.context.get_info(context_index);
-		self.context.update(current_state, context_index, bit);
-		self.encoder.bit(current_state.prediction(), bit)
+		let prediction: u32 = self.predictor.code(context_index, bit);
+		self.encoder.bit(prediction, bit)
 	}
 
 	fn byte(&mut self, context_index: usize, byte: Byte) -> AnyResult<()> {
@@ -148,37 +413,282 @@ impl<const IO_BUFFER_SIZE: usize, const MESSAGE_BUFFER_SIZE: usize>
 		// oke
 		return Ok(());
 	}
+}
 
-	fn encode(mut self) -> AnyResult<()> {
-		loop {
-			match self.reader.read()? {
-				None => {
-					self.reader.close()?;
-					self.encoder.close()?;
-					return Ok(());
-				}
-				Some(message) => match message.get() {
-					Message::Bit(context_index, bit) => self.bit(context_index, bit)?,
-					Message::Byte(context_index, value) => self.byte(context_index, value)?,
-				},
-			}
+impl<W: Writer<u8>, P: Predictor> Writer<PackedMessage> for SecondaryContextEncoder<W, P> {
+	#[inline(always)]
+	fn write(&mut self, message: PackedMessage) -> AnyResult<()> {
+		match message.get() {
+			Message::Bit(context_index, bit) => self.bit(context_index, bit),
+			Message::Byte(context_index, value) => self.byte(context_index, value),
 		}
 	}
 }
 
+impl<W: Writer<u8>, P: Predictor> Closable<W> for SecondaryContextEncoder<W, P> {
+	fn close(self) -> AnyResult<W> {
+		self.encoder.close()
+	}
+}
+
 // -----------------------------------------------
 
-fn run_secondary_context_encoder<const IO_BUFFER_SIZE: usize, const MESSAGE_BUFFER_SIZE: usize>(
-	reader: PipedReader<PackedMessage, MESSAGE_BUFFER_SIZE>,
+fn run_secondary_context_encoder<
+	P: Predictor,
+	const IO_BUFFER_SIZE: usize,
+	const MESSAGE_BUFFER_SIZE: usize,
+>(
+	mut reader: PipedReader<PackedMessage, MESSAGE_BUFFER_SIZE>,
 	writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+	predictor: P,
 ) -> AnyResult<()> {
-	let encoder: SecondaryContextEncoder<IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE> =
-		SecondaryContextEncoder {
-			context: BridgedSecondaryContext::new(),
-			reader,
+	let mut encoder: SecondaryContextEncoder<PipedWriter<u8, IO_BUFFER_SIZE>, P> =
+		SecondaryContextEncoder::new(predictor, writer);
+	while let Some(message) = reader.read()? {
+		encoder.write(message)?;
+	}
+	reader.close()?;
+	encoder.close()?.close()
+}
+
+// -----------------------------------------------
+
+// the max level codes every bit on a single thread, as the mixing model needs to know the bytes
+// behind the flags
+pub struct MixingEncoder<W: Writer<u8>> {
+	predictor: BitPredictor,
+	model: Box<MixingModel>,
+	encoder: BitEncoder<W>,
+}
+
+impl<W: Writer<u8>> MixingEncoder<W> {
+	fn new(predictor: BitPredictor, model: Box<MixingModel>, writer: W) -> Self {
+		Self {
+			predictor,
+			model,
 			encoder: BitEncoder::new(writer),
-		};
-	encoder.encode()
+		}
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		self.encoder.get_mut()
+	}
+}
+
+impl<W: Writer<u8>> BitSink for MixingEncoder<W> {
+	#[inline(always)]
+	fn bit(&mut self, context: usize, kind: BitKind, symbol: usize, bit: Bit) -> AnyResult<()> {
+		let refined: u32 = self.predictor.predict(context);
+		self.predictor.update(bit);
+		let prediction: u32 = self.model.predict(refined, kind, symbol);
+		self.model.update(bit);
+		self.encoder.bit(prediction, bit)
+	}
+
+	fn byte(&mut self, context: usize, byte: Byte) -> AnyResult<()> {
+		// the same binary tree as the secondary context encoder
+		let high: usize = (usize::from(byte) >> 4) | 16;
+		for shift in (0..4).rev() {
+			let node: usize = high >> (shift + 1);
+			self.bit(
+				context + node,
+				BitKind::Literal,
+				node,
+				Bit::from(high >> shift & 1),
+			)?;
+		}
+		let low_node: usize = 15 * (high - 15);
+		let low: usize = (usize::from(byte) & 15) | 16;
+		for shift in (0..4).rev() {
+			let node: usize = low_node + (low >> (shift + 1));
+			self.bit(
+				context + node,
+				BitKind::Literal,
+				node,
+				Bit::from(low >> shift & 1),
+			)?;
+		}
+		Ok(())
+	}
+
+	fn push(&mut self, byte: Byte) {
+		self.model.push(byte);
+	}
+}
+
+impl<W: Writer<u8>> Closable<W> for MixingEncoder<W> {
+	fn close(self) -> AnyResult<W> {
+		self.encoder.close()
+	}
+}
+
+// -----------------------------------------------
+
+// the encoder of either level, coding on the calling thread, plain streams with the default model
+// alone and fast ones with any of the optional models
+pub enum ContextEncoder<W: Writer<u8>> {
+	Plain(PlainContextEncoder<SecondaryContextEncoder<W, StatePredictor>>),
+	Fast(PrimaryContextEncoder<SecondaryContextEncoder<W, BitPredictor>>),
+	Max(PrimaryContextEncoder<MixingEncoder<W>>),
+}
+
+impl<W: Writer<u8>> ContextEncoder<W> {
+	pub fn new(header: &Header, writer: W) -> AnyResult<Self> {
+		Ok(Self::from_contexts(new_contexts(header)?, writer))
+	}
+
+	fn from_contexts(contexts: Contexts, writer: W) -> Self {
+		match (contexts.predictor, contexts.mixing) {
+			(StreamPredictor::Plain(predictor), _) => Self::Plain(PlainContextEncoder::new(
+				contexts.primary,
+				contexts.layout,
+				SecondaryContextEncoder::new(predictor, writer),
+			)),
+			(StreamPredictor::Refined(predictor), None) => Self::Fast(PrimaryContextEncoder::new(
+				contexts.primary,
+				contexts.fallback,
+				contexts.long_match,
+				contexts.layout,
+				SecondaryContextEncoder::new(predictor, writer),
+			)),
+			(StreamPredictor::Refined(predictor), Some(model)) => {
+				Self::Max(PrimaryContextEncoder::new(
+					contexts.primary,
+					contexts.fallback,
+					contexts.long_match,
+					contexts.layout,
+					MixingEncoder::new(predictor, model, writer),
+				))
+			}
+		}
+	}
+
+	fn into_contexts(self) -> Contexts {
+		match self {
+			Self::Plain(encoder) => Contexts {
+				primary: encoder.context,
+				fallback: None,
+				long_match: None,
+				layout: encoder.layout,
+				predictor: StreamPredictor::Plain(encoder.writer.predictor),
+				mixing: None,
+			},
+			Self::Fast(encoder) => Contexts {
+				primary: encoder.context,
+				fallback: encoder.fallback,
+				long_match: encoder.long_match,
+				layout: encoder.layout,
+				predictor: StreamPredictor::Refined(encoder.writer.predictor),
+				mixing: None,
+			},
+			Self::Max(encoder) => Contexts {
+				primary: encoder.context,
+				fallback: encoder.fallback,
+				long_match: encoder.long_match,
+				layout: encoder.layout,
+				predictor: StreamPredictor::Refined(encoder.writer.predictor),
+				mixing: Some(encoder.writer.model),
+			},
+		}
+	}
+
+	#[inline(always)]
+	pub fn encode(&mut self, current_byte: Byte) -> AnyResult<()> {
+		match self {
+			Self::Plain(encoder) => encoder.encode(current_byte),
+			Self::Fast(encoder) => encoder.encode(current_byte),
+			Self::Max(encoder) => encoder.encode(current_byte),
+		}
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		match self {
+			Self::Plain(encoder) => encoder.get_mut().get_mut(),
+			Self::Fast(encoder) => encoder.get_mut().get_mut(),
+			Self::Max(encoder) => encoder.get_mut().get_mut(),
+		}
+	}
+}
+
+impl<W: Writer<u8>> Closable<W> for ContextEncoder<W> {
+	fn close(self) -> AnyResult<W> {
+		match self {
+			Self::Plain(encoder) => encoder.close()?.close(),
+			Self::Fast(encoder) => encoder.close()?.close(),
+			Self::Max(encoder) => encoder.close()?.close(),
+		}
+	}
+}
+
+// -----------------------------------------------
+
+fn run_mixing_encoder<const IO_BUFFER_SIZE: usize>(
+	mut reader: PipedReader<u8, IO_BUFFER_SIZE>,
+	writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+	contexts: Contexts,
+) -> AnyResult<()> {
+	let mut encoder: ContextEncoder<PipedWriter<u8, IO_BUFFER_SIZE>> =
+		ContextEncoder::from_contexts(contexts, writer);
+	while let Some(current_byte) = reader.read()? {
+		encoder.encode(Byte::from(current_byte))?;
+	}
+	reader.close()?;
+	encoder.close()?.close()
+}
+
+// -----------------------------------------------
+
+// the output of the encoders while they are primed
+struct Discard;
+
+impl Writer<u8> for Discard {
+	#[inline(always)]
+	fn write(&mut self, _value: u8) -> AnyResult<()> {
+		Ok(())
+	}
+}
+
+// the models of a compressed stream, with the fallback context, the match model and the exclusion
+// of the literals only if the header asks for them, the fixed predictions of the states alone for
+// plain streams and the mixing model only at the max level
+pub struct Contexts {
+	pub primary: BridgedPrimaryContext,
+	pub fallback: Option<BridgedPrimaryContext>,
+	pub long_match: Option<BridgedMatchModel>,
+	pub layout: ContextLayout,
+	pub predictor: StreamPredictor,
+	pub mixing: Option<Box<MixingModel>>,
+}
+
+// fresh contexts for a new compressed stream, primed with the preset dictionary if any by coding
+// it without keeping the output, the same way on both sides
+pub fn new_contexts(header: &Header) -> AnyResult<Contexts> {
+	let contexts: Contexts = Contexts {
+		primary: BridgedPrimaryContext::new(
+			header.primary_context_bits(),
+			header.context_order(),
+			header.is_wide(),
+			header.is_tagged(),
+		),
+		fallback: header
+			.has_fallback()
+			.then(|| BridgedPrimaryContext::direct(FALLBACK_CONTEXT_ORDER)),
+		long_match: header
+			.has_match_model()
+			.then(|| BridgedMatchModel::new(header.primary_context_bits())),
+		layout: ContextLayout::new(header),
+		predictor: StreamPredictor::new(header),
+		mixing: header.is_max().then(|| Box::new(MixingModel::new())),
+	};
+	let dictionary: &Dictionary = match header.dictionary()? {
+		None => return Ok(contexts),
+		Some(dictionary) => dictionary,
+	};
+	let mut encoder: ContextEncoder<Discard> = ContextEncoder::from_contexts(contexts, Discard);
+	for &value in dictionary.as_bytes() {
+		encoder.encode(Byte::from(value))?;
+	}
+	Ok(encoder.into_contexts())
 }
 
 // -----------------------------------------------
@@ -191,7 +701,58 @@ pub fn encode<
 >(
 	reader: R,
 	writer: W,
-) -> AnyResult<(R, W)> {
+	header: &Header,
+) -> AnyResult<(R, W, Digest)> {
+	let contexts: Contexts = new_contexts(header)?;
+	if contexts.mixing.is_some() {
+		return encode_max::<R, W, IO_BUFFER_SIZE>(reader, writer, contexts, header.digest());
+	}
+	// the plain streams get their own encoders, without any of the optional models
+	let Contexts {
+		primary,
+		fallback,
+		long_match,
+		layout,
+		predictor,
+		..
+	} = contexts;
+	match predictor {
+		StreamPredictor::Plain(predictor) => {
+			encode_fast::<R, W, _, _, IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE>(
+				reader,
+				writer,
+				|writer| PlainContextEncoder::new(primary, layout, writer),
+				predictor,
+				header.digest(),
+			)
+		}
+		StreamPredictor::Refined(predictor) => {
+			encode_fast::<R, W, _, _, IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE>(
+				reader,
+				writer,
+				|writer| PrimaryContextEncoder::new(primary, fallback, long_match, layout, writer),
+				predictor,
+				header.digest(),
+			)
+		}
+	}
+}
+
+// the primary context encoder is made on its own thread, around the sending end of the messages
+fn encode_fast<
+	R: Read + Send,
+	W: Write + Send,
+	E: RankEncoder<PipedWriter<PackedMessage, MESSAGE_BUFFER_SIZE>>,
+	P: Predictor + Send,
+	const IO_BUFFER_SIZE: usize,
+	const MESSAGE_BUFFER_SIZE: usize,
+>(
+	reader: R,
+	writer: W,
+	new_encoder: impl FnOnce(PipedWriter<PackedMessage, MESSAGE_BUFFER_SIZE>) -> E + Send,
+	predictor: P,
+	digest: Digest,
+) -> AnyResult<(R, W, Digest)> {
 	scope(|scope| {
 		let (input_writer, input_reader): (
 			PipedWriter<u8, IO_BUFFER_SIZE>,
@@ -205,18 +766,46 @@ pub fn encode<
 			PipedWriter<u8, IO_BUFFER_SIZE>,
 			PipedReader<u8, IO_BUFFER_SIZE>,
 		) = pipe::<u8, IO_BUFFER_SIZE>();
-		let file_reader: ScopedJoinHandle<AnyResult<R>> =
-			scope.spawn(|| run_file_reader(reader, input_writer));
+		let file_reader: ScopedJoinHandle<AnyResult<(R, Digest)>> =
+			scope.spawn(|| run_digested_file_reader(reader, input_writer, digest));
 		let primary_context_encoder: ScopedJoinHandle<AnyResult<()>> =
-			scope.spawn(|| run_primary_context_encoder(input_reader, message_writer));
+			scope.spawn(|| run_primary_context_encoder(input_reader, new_encoder(message_writer)));
 		let secondary_context_encoder: ScopedJoinHandle<AnyResult<()>> =
-			scope.spawn(|| run_secondary_context_encoder(message_reader, output_writer));
+			scope.spawn(|| run_secondary_context_encoder(message_reader, output_writer, predictor));
 		let file_writer: ScopedJoinHandle<AnyResult<W>> =
 			scope.spawn(|| run_file_writer(output_reader, writer));
-		let returned_reader: R = thread_join(file_reader)?;
+		let (returned_reader, digest): (R, Digest) = thread_join(file_reader)?;
 		thread_join(primary_context_encoder)?;
 		thread_join(secondary_context_encoder)?;
 		let returned_writer: W = thread_join(file_writer)?;
-		Ok((returned_reader, returned_writer))
+		Ok((returned_reader, returned_writer, digest))
+	})
+}
+
+fn encode_max<R: Read + Send, W: Write + Send, const IO_BUFFER_SIZE: usize>(
+	reader: R,
+	writer: W,
+	contexts: Contexts,
+	digest: Digest,
+) -> AnyResult<(R, W, Digest)> {
+	scope(|scope| {
+		let (input_writer, input_reader): (
+			PipedWriter<u8, IO_BUFFER_SIZE>,
+			PipedReader<u8, IO_BUFFER_SIZE>,
+		) = pipe::<u8, IO_BUFFER_SIZE>();
+		let (output_writer, output_reader): (
+			PipedWriter<u8, IO_BUFFER_SIZE>,
+			PipedReader<u8, IO_BUFFER_SIZE>,
+		) = pipe::<u8, IO_BUFFER_SIZE>();
+		let file_reader: ScopedJoinHandle<AnyResult<(R, Digest)>> =
+			scope.spawn(|| run_digested_file_reader(reader, input_writer, digest));
+		let mixing_encoder: ScopedJoinHandle<AnyResult<()>> =
+			scope.spawn(|| run_mixing_encoder(input_reader, output_writer, contexts));
+		let file_writer: ScopedJoinHandle<AnyResult<W>> =
+			scope.spawn(|| run_file_writer(output_reader, writer));
+		let (returned_reader, digest): (R, Digest) = thread_join(file_reader)?;
+		thread_join(mixing_encoder)?;
+		let returned_writer: W = thread_join(file_writer)?;
+		Ok((returned_reader, returned_writer, digest))
 	})
 }
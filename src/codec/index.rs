@@ -0,0 +1,247 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::{AnyResult, FormatError};
+use crate::container::{read_exact, Header};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// -----------------------------------------------
+
+const ENTRY_SIZE: u64 = 16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct IndexEntry {
+	// the offset of the block in the original data
+	pub original: u64,
+	// the offset of the block header in the compressed stream
+	pub compressed: u64,
+}
+
+// the blocks of a seekable stream, see the container header for the layout
+#[derive(Default)]
+pub struct BlockIndex {
+	entries: Vec<IndexEntry>,
+	length: u64,
+	// the offset of the end of blocks, only known in an index read from a stream
+	end: u64,
+}
+
+impl BlockIndex {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	// the length of the original data
+	pub fn length(&self) -> u64 {
+		self.length
+	}
+
+	// add the next block, given its original length and the offset of its block header
+	pub fn push(&mut self, length: u32, offset: u64) {
+		self.entries.push(IndexEntry {
+			original: self.length,
+			compressed: offset,
+		});
+		self.length += length as u64;
+	}
+
+	// the block holding the given original offset, with where the next block or the end of
+	// blocks starts
+	pub fn find(&self, offset: u64) -> Option<(IndexEntry, IndexEntry)> {
+		if offset >= self.length {
+			return None;
+		}
+		let index: usize = self
+			.entries
+			.partition_point(|entry| entry.original <= offset)
+			- 1;
+		let end: IndexEntry = self.entries.get(index + 1).copied().unwrap_or(IndexEntry {
+			original: self.length,
+			compressed: self.end,
+		});
+		Some((self.entries[index], end))
+	}
+
+	// write the block index at the given offset
+	pub fn write<W: Write>(&self, writer: &mut W, offset: u64) -> AnyResult<()> {
+		let mut buffer: Vec<u8> = Vec::with_capacity(self.entries.len() * 16 + 24);
+		buffer.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+		for entry in &self.entries {
+			buffer.extend_from_slice(&entry.original.to_le_bytes());
+			buffer.extend_from_slice(&entry.compressed.to_le_bytes());
+		}
+		buffer.extend_from_slice(&self.length.to_le_bytes());
+		buffer.extend_from_slice(&offset.to_le_bytes());
+		Ok(writer.write_all(&buffer)?)
+	}
+
+	// verify the block index at the given offset against the blocks read before it, the offsets
+	// in the index being relative to the start of the stream at the given base
+	pub fn check<F: FnMut() -> AnyResult<u64>>(
+		&self,
+		base: u64,
+		offset: u64,
+		mut read_u64: F,
+	) -> AnyResult<()> {
+		let mut matched: bool = read_u64()? == self.entries.len() as u64;
+		for entry in &self.entries {
+			matched = matched && read_u64()? == entry.original && read_u64()? == entry.compressed;
+		}
+		matched = matched && read_u64()? == self.length && read_u64()? == offset - base;
+		match matched {
+			true => Ok(()),
+			false => Err(FormatError::CorruptIndex { offset }.into()),
+		}
+	}
+
+	// read the block index from the end of a seekable stream which starts at the given position
+	pub fn read<R: Read + Seek>(reader: &mut R, header: &Header, base: u64) -> AnyResult<Self> {
+		// the smallest stream has the header, the end of blocks and an empty index
+		let end: u64 = reader.seek(SeekFrom::End(0))? - base;
+		let footer: u64 = match end.checked_sub(header.trailer_size() + 16) {
+			Some(footer) if footer >= header.size() + 16 => footer,
+			_ => return Err(FormatError::Truncated { offset: end }.into()),
+		};
+		let mut buffer: [u8; 16] = [0; 16];
+		reader.seek(SeekFrom::Start(base + footer))?;
+		read_exact(reader, &mut buffer, footer)?;
+		let length: u64 = u64::from_le_bytes(buffer[..8].try_into().unwrap());
+		let offset: u64 = u64::from_le_bytes(buffer[8..].try_into().unwrap());
+		if offset < header.size() + 8 || offset > footer - 8 {
+			return Err(FormatError::CorruptIndex { offset: footer }.into());
+		}
+
+		// the block count must fill the space up to the footer
+		let corrupt: FormatError = FormatError::CorruptIndex { offset };
+		reader.seek(SeekFrom::Start(base + offset))?;
+		read_exact(reader, &mut buffer[..8], offset)?;
+		let count: u64 = u64::from_le_bytes(buffer[..8].try_into().unwrap());
+		if count.checked_mul(ENTRY_SIZE) != Some(footer - offset - 8) {
+			return Err(corrupt.into());
+		}
+		let mut data: Vec<u8> = vec![0; (count * ENTRY_SIZE) as usize];
+		read_exact(reader, &mut data, offset + 8)?;
+		let entries: Vec<IndexEntry> = data
+			.chunks_exact(ENTRY_SIZE as usize)
+			.map(|chunk| IndexEntry {
+				original: u64::from_le_bytes(chunk[..8].try_into().unwrap()),
+				compressed: u64::from_le_bytes(chunk[8..].try_into().unwrap()),
+			})
+			.collect();
+
+		// blocks are not empty and go in order, from right after the header up to the end of
+		// blocks right before the index
+		let mut expected: IndexEntry = IndexEntry {
+			original: 0,
+			compressed: header.size(),
+		};
+		for (index, entry) in entries.iter().enumerate() {
+			if (index == 0 && *entry != expected)
+				|| entry.original < expected.original
+				|| entry.compressed < expected.compressed
+			{
+				return Err(corrupt.into());
+			}
+			expected = IndexEntry {
+				original: entry.original + 1,
+				compressed: entry.compressed + 9,
+			};
+		}
+		let end: u64 = offset - 8;
+		if (entries.is_empty() && length != 0)
+			|| expected.original > length
+			|| expected.compressed > end
+		{
+			return Err(corrupt.into());
+		}
+		Ok(Self {
+			entries,
+			length,
+			end,
+		})
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{BlockIndex, IndexEntry};
+	use crate::basic::{AnyError, AnyResult, FormatError};
+	use crate::container::Header;
+	use crate::Options;
+	use std::io::Cursor;
+
+	fn corrupt_index<T>(result: AnyResult<T>) -> bool {
+		matches!(
+			result,
+			Err(AnyError::Format(FormatError::CorruptIndex { .. }))
+		)
+	}
+
+	#[test]
+	fn test_block_index() -> AnyResult<()> {
+		let header: Header = Header::new(&Options::new().checksum(false).seekable(true));
+		let first: u64 = header.size();
+		let mut index: BlockIndex = BlockIndex::new();
+		for (length, offset) in [(1000, first), (1000, first + 100), (500, first + 200)] {
+			index.push(length, offset);
+		}
+		assert_eq!(index.length(), 2500);
+		let entry = |original: u64, compressed: u64| IndexEntry {
+			original,
+			compressed,
+		};
+		assert_eq!(
+			index.find(0),
+			Some((entry(0, first), entry(1000, first + 100)))
+		);
+		assert_eq!(index.find(2500), None);
+
+		// the index after the end of blocks, read back from the end of the stream
+		let mut stream: Vec<u8> = Vec::new();
+		header.write(&mut stream)?;
+		stream.resize(first as usize + 300 + 8, 0);
+		let offset: u64 = stream.len() as u64;
+		index.write(&mut stream, offset)?;
+		let read: BlockIndex = BlockIndex::read(&mut Cursor::new(&stream[..]), &header, 0)?;
+		assert_eq!(read.length(), 2500);
+		assert_eq!(
+			read.find(2499),
+			Some((entry(2000, first + 200), entry(2500, first + 300)))
+		);
+		let mut values = stream[offset as usize..]
+			.chunks_exact(8)
+			.map(|chunk: &[u8]| u64::from_le_bytes(chunk.try_into().unwrap()));
+		index.check(0, offset, || Ok(values.next().unwrap()))?;
+		assert!(corrupt_index(index.check(0, offset + 1, || Ok(0))));
+
+		// a count that does not fill the index, and a block that starts inside the one before it
+		let second: usize = offset as usize + 8 + 16 + 8;
+		for (position, value) in [(offset as usize, 2), (second, first + 8)] {
+			let mut corrupted: Vec<u8> = stream.clone();
+			corrupted[position..position + 8].copy_from_slice(&value.to_le_bytes());
+			assert!(corrupt_index(BlockIndex::read(
+				&mut Cursor::new(&corrupted[..]),
+				&header,
+				0
+			)));
+		}
+		Ok(())
+	}
+}
@@ -16,9 +16,21 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod block;
 mod decoder;
 mod encoder;
+mod index;
+mod predictor;
+mod seekable;
 mod shared;
+mod slice;
+mod stream;
+mod train;
 
+pub use self::block::{encode_blocks, encode_slice_blocks};
 pub use self::decoder::decode;
 pub use self::encoder::encode;
+pub use self::seekable::SeekableSrxReader;
+pub use self::slice::{decode_slice, encode_slice};
+pub use self::stream::{SrxReader, SrxWriter};
+pub use self::train::{match_stats, sample_options, train, MatchStats, SampleGain};
@@ -0,0 +1,230 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bridged_context::{
+	literal_depth, BridgedCounter16Context, BridgedCounter32Context, BridgedSecondaryContext,
+	ContextLayout, REFINEMENT_CONTEXTS, STATE_MAPS,
+};
+use crate::container::Header;
+use crate::mixing::{stretch, Apm, Mixer, INPUTS};
+use crate::secondary_context::{Bit, BitState, CellType, StateInfo, StateMap};
+
+// -----------------------------------------------
+
+// the prediction of every coded bit from its cell of the secondary context, which then learns the
+// bit of the last prediction
+pub trait Predictor {
+	fn predict(&mut self, context_index: usize) -> u32;
+
+	fn update(&mut self, bit: Bit);
+
+	// the prediction of a bit already known, as the encoder has it, learned at once
+	#[inline(always)]
+	fn code(&mut self, context_index: usize, bit: Bit) -> u32 {
+		let prediction: u32 = self.predict(context_index);
+		self.update(bit);
+		prediction
+	}
+}
+
+// -----------------------------------------------
+
+// the fixed prediction of the state of every cell, which is all the plain streams need
+pub struct StatePredictor {
+	context: BridgedSecondaryContext,
+	context_index: usize,
+	info: StateInfo,
+}
+
+impl StatePredictor {
+	pub fn new(size: usize) -> Self {
+		Self {
+			context: BridgedSecondaryContext::new(size),
+			context_index: 0,
+			info: BitState::default().get_info(),
+		}
+	}
+}
+
+impl Predictor for StatePredictor {
+	#[inline(always)]
+	fn predict(&mut self, context_index: usize) -> u32 {
+		self.context_index = context_index;
+		self.info = self.context.get_state(context_index).get_info();
+		self.info.prediction()
+	}
+
+	#[inline(always)]
+	fn update(&mut self, bit: Bit) {
+		self.context.update(self.info, self.context_index, bit);
+	}
+
+	#[inline(always)]
+	fn code(&mut self, context_index: usize, bit: Bit) -> u32 {
+		let info: StateInfo = self.context.get_state(context_index).get_info();
+		self.context.update(info, context_index, bit);
+		info.prediction()
+	}
+}
+
+// -----------------------------------------------
+
+// the cells of the secondary context, of the type recorded in the header
+enum Cells {
+	State(BridgedSecondaryContext, Option<Box<StateMap>>),
+	Counter16(BridgedCounter16Context),
+	Counter32(BridgedCounter32Context),
+}
+
+// the secondary context with the prediction of each bit, the fixed one of the state unless the
+// header asks for learned state probabilities or for counters, mixed with the prediction in the
+// context of the last byte for the literals coded with exclusion, then refined by the adaptive
+// probability map if any
+pub struct BitPredictor {
+	layout: ContextLayout,
+	cells: Cells,
+	apm: Option<Box<Apm>>,
+	// a set of weights for each bit of the literals coded with exclusion
+	literal_mixer: Option<Box<Mixer<8>>>,
+	// the cells of the last prediction, the ones that are updated
+	context_index: usize,
+	state: BitState,
+	order_1: Option<(usize, BitState)>,
+}
+
+impl BitPredictor {
+	pub fn new(header: &Header) -> Self {
+		let layout: ContextLayout = ContextLayout::new(header);
+		let size: usize = layout.size();
+		Self {
+			layout,
+			cells: match header.cell_type() {
+				CellType::State => Cells::State(
+					BridgedSecondaryContext::new(size),
+					header
+						.is_adaptive()
+						.then(|| Box::new(StateMap::new(STATE_MAPS))),
+				),
+				CellType::Counter16 { shift } => {
+					Cells::Counter16(BridgedCounter16Context::new(size, shift))
+				}
+				CellType::Counter32 { limit } => {
+					Cells::Counter32(BridgedCounter32Context::new(size, limit))
+				}
+			},
+			apm: header
+				.is_refined()
+				.then(|| Box::new(Apm::new(REFINEMENT_CONTEXTS))),
+			literal_mixer: header.has_exclusion().then(|| Box::new(Mixer::new())),
+			context_index: 0,
+			state: BitState::default(),
+			order_1: None,
+		}
+	}
+}
+
+impl Predictor for BitPredictor {
+	#[inline(always)]
+	fn predict(&mut self, context_index: usize) -> u32 {
+		self.context_index = context_index;
+		let mut prediction: u32 = match &mut self.cells {
+			Cells::State(context, state_maps) => {
+				self.state = context.get_state(context_index);
+				match state_maps {
+					None => self.state.get_info().prediction(),
+					Some(map) => map.predict(self.layout.state_map(context_index), self.state),
+				}
+			}
+			Cells::Counter16(context) => context.prediction(context_index),
+			Cells::Counter32(context) => context.prediction(context_index),
+		};
+		self.order_1 = None;
+		if let Some(mixer) = &mut self.literal_mixer {
+			if let Some(order_1) = self.layout.order_1_literal(context_index) {
+				// the learned state probabilities follow the main context only
+				let (state, order_1_prediction): (BitState, u32) = match &self.cells {
+					Cells::State(context, _) => {
+						let state: BitState = context.get_state(order_1);
+						(state, state.get_info().prediction())
+					}
+					Cells::Counter16(context) => (BitState::default(), context.prediction(order_1)),
+					Cells::Counter32(context) => (BitState::default(), context.prediction(order_1)),
+				};
+				self.order_1 = Some((order_1, state));
+				let inputs: [i32; INPUTS] = [
+					stretch((prediction >> 20).clamp(1, 4095)),
+					stretch((order_1_prediction >> 20).clamp(1, 4095)),
+					0,
+					0,
+					256,
+				];
+				let set: usize = literal_depth(context_index & 0xFF);
+				prediction = mixer.mix(inputs, set).clamp(1, 4095) << 20;
+			}
+		}
+		match &mut self.apm {
+			None => prediction,
+			Some(apm) => apm.refine(prediction, self.layout.refinement_context(context_index)),
+		}
+	}
+
+	// learn the bit of the last prediction
+	#[inline(always)]
+	fn update(&mut self, bit: Bit) {
+		match &mut self.cells {
+			Cells::State(context, state_maps) => {
+				context.update(self.state.get_info(), self.context_index, bit);
+				if let Some(map) = state_maps {
+					map.update(bit);
+				}
+			}
+			Cells::Counter16(context) => context.update(self.context_index, bit),
+			Cells::Counter32(context) => context.update(self.context_index, bit),
+		}
+		if let Some((order_1, state)) = self.order_1 {
+			match &mut self.cells {
+				Cells::State(context, _) => context.update(state.get_info(), order_1, bit),
+				Cells::Counter16(context) => context.update(order_1, bit),
+				Cells::Counter32(context) => context.update(order_1, bit),
+			}
+			if let Some(mixer) = &mut self.literal_mixer {
+				mixer.update(bit.into());
+			}
+		}
+		if let Some(apm) = &mut self.apm {
+			apm.update(bit);
+		}
+	}
+}
+
+// -----------------------------------------------
+
+// the predictor of a stream, picked once from its header
+pub enum StreamPredictor {
+	Plain(StatePredictor),
+	Refined(BitPredictor),
+}
+
+impl StreamPredictor {
+	pub fn new(header: &Header) -> Self {
+		match header.is_plain() {
+			true => Self::Plain(StatePredictor::new(ContextLayout::new(header).size())),
+			false => Self::Refined(BitPredictor::new(header)),
+		}
+	}
+}
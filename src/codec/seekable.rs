@@ -0,0 +1,230 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::block::{decode_block, CompressedBlock};
+use super::index::{BlockIndex, IndexEntry};
+use crate::basic::{AnyResult, FormatError};
+use crate::container::{read_exact, Header};
+use crate::Options;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+// -----------------------------------------------
+
+/// A [`Read`] and [`Seek`] adapter over a `.srx` stream compressed with
+/// [`Options::seekable`](crate::Options::seekable), which only decompresses the blocks holding
+/// the data being read.
+///
+/// Every block is checked against its recorded lengths, but the checksum of the whole data can
+/// only be verified by reading it from start to end with [`SrxReader`](crate::SrxReader). The
+/// block index is found from the end of the input, so the stream must be the last one of it.
+pub struct SeekableSrxReader<R: Read + Seek> {
+	reader: R,
+	header: Header,
+	index: BlockIndex,
+	// the position of the stream in the reader
+	base: u64,
+	// the position in the decompressed data
+	position: u64,
+	// the last decompressed block and its offset in the decompressed data
+	block: Vec<u8>,
+	block_offset: u64,
+}
+
+impl<R: Read + Seek> SeekableSrxReader<R> {
+	/// Create a decompressor for the stream starting at the current position of `reader`, reading
+	/// its header and its block index right away.
+	///
+	/// Fails with [`FormatError::NotSeekable`] if the stream has no block index.
+	pub fn new(reader: R) -> AnyResult<Self> {
+		Self::with_options(reader, &Options::new())
+	}
+
+	/// Like [`SeekableSrxReader::new`], with the [`dictionary`](Options::dictionary) the stream
+	/// was compressed with, the other options being ignored.
+	pub fn with_options(mut reader: R, options: &Options) -> AnyResult<Self> {
+		let base: u64 = reader.stream_position()?;
+		let mut header: Header = Header::read(&mut reader)?;
+		if !header.is_seekable() {
+			return Err(FormatError::NotSeekable.into());
+		}
+		header.attach_dictionary(options.dictionary.as_ref())?;
+		let index: BlockIndex = BlockIndex::read(&mut reader, &header, base)?;
+		Ok(Self {
+			reader,
+			header,
+			index,
+			base,
+			position: 0,
+			block: Vec::new(),
+			block_offset: 0,
+		})
+	}
+
+	/// The length of the decompressed data.
+	pub fn length(&self) -> u64 {
+		self.index.length()
+	}
+
+	/// Give the underlying reader back.
+	pub fn into_inner(self) -> R {
+		self.reader
+	}
+
+	// load the block of the given entry, which must end right where the next one starts
+	fn load_block(&mut self, entry: IndexEntry, end: IndexEntry) -> AnyResult<()> {
+		let offset: u64 = entry.compressed;
+		let mut buffer: [u8; 8] = [0; 8];
+		self.reader.seek(SeekFrom::Start(self.base + offset))?;
+		read_exact(&mut self.reader, &mut buffer, offset)?;
+		let block_length: u32 = u32::from_le_bytes(buffer[..4].try_into().unwrap());
+		let compressed_length: u32 = u32::from_le_bytes(buffer[4..].try_into().unwrap());
+		if block_length as u64 != end.original - entry.original
+			|| compressed_length == 0
+			|| offset + 8 + compressed_length as u64 != end.compressed
+		{
+			return Err(FormatError::CorruptIndex { offset }.into());
+		}
+		let mut data: Vec<u8> = vec![0; compressed_length as usize];
+		read_exact(&mut self.reader, &mut data, offset + 8)?;
+		self.block = decode_block(
+			&self.header,
+			CompressedBlock {
+				offset,
+				length: block_length,
+				data,
+			},
+		)?;
+		self.block_offset = entry.original;
+		Ok(())
+	}
+}
+
+impl<R: Read + Seek> Read for SeekableSrxReader<R> {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		if buffer.is_empty() {
+			return Ok(0);
+		}
+		let block_end: u64 = self.block_offset + self.block.len() as u64;
+		if self.position < self.block_offset || self.position >= block_end {
+			match self.index.find(self.position) {
+				None => return Ok(0),
+				Some((entry, end)) => self.load_block(entry, end)?,
+			}
+		}
+		let start: usize = (self.position - self.block_offset) as usize;
+		let length: usize = buffer.len().min(self.block.len() - start);
+		buffer[..length].copy_from_slice(&self.block[start..start + length]);
+		self.position += length as u64;
+		Ok(length)
+	}
+}
+
+impl<R: Read + Seek> Seek for SeekableSrxReader<R> {
+	fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+		let target: Option<u64> = match position {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(delta) => self.length().checked_add_signed(delta),
+			SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+		};
+		match target {
+			None => Err(io::Error::new(
+				ErrorKind::InvalidInput,
+				"Seek to a negative or overflowing position!",
+			)),
+			Some(target) => {
+				self.position = target;
+				Ok(target)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::SeekableSrxReader;
+	use crate::container::Header;
+	use crate::{
+		compress_slice, decompress, decompress_slice, AnyError, AnyResult, FormatError, Options,
+	};
+	use std::io::{Cursor, Read, Seek, SeekFrom};
+
+	#[test]
+	fn test_seekable() -> AnyResult<()> {
+		let data: Vec<u8> = (0..10000u32)
+			.map(|index: u32| b"seekable"[index as usize % 8] ^ (index / 1000) as u8)
+			.collect();
+		let options: Options = Options::new().block_size(1000).seekable(true);
+		let compressed: Vec<u8> = compress_slice(&data, &options)?;
+
+		let mut reader: SeekableSrxReader<Cursor<&[u8]>> =
+			SeekableSrxReader::new(Cursor::new(&compressed[..]))?;
+		assert_eq!(reader.length(), data.len() as u64);
+		for (offset, length) in [(0, 10), (999, 2), (4321, 3000), (9990, 10)] {
+			let mut buffer: Vec<u8> = vec![0; length];
+			reader.seek(SeekFrom::Start(offset as u64))?;
+			reader.read_exact(&mut buffer)?;
+			assert_eq!(buffer, &data[offset..offset + length]);
+		}
+		assert_eq!(reader.seek(SeekFrom::End(-5))?, data.len() as u64 - 5);
+		assert_eq!(reader.read_to_end(&mut Vec::new())?, 5);
+		assert!(reader.seek(SeekFrom::Current(-20000)).is_err());
+
+		// a stream without an index, and a stream with a wrong index entry
+		let plain: Vec<u8> = compress_slice(&data, &Options::new().block_size(1000))?;
+		assert!(matches!(
+			SeekableSrxReader::new(Cursor::new(&plain[..])),
+			Err(AnyError::Format(FormatError::NotSeekable))
+		));
+		// the compressed offset of the block before the last one
+		let mut corrupted: Vec<u8> = compressed.clone();
+		let position: usize = corrupted.len() - 4 - 16 - 16 - 8;
+		corrupted[position] ^= 1;
+		assert!(matches!(
+			decompress_slice(&corrupted, &options),
+			Err(AnyError::Format(FormatError::CorruptIndex { .. }))
+		));
+		assert!(matches!(
+			decompress(&corrupted[..], Vec::new(), &options),
+			Err(AnyError::Format(FormatError::CorruptIndex { .. }))
+		));
+		let mut reader: SeekableSrxReader<Cursor<&[u8]>> =
+			SeekableSrxReader::new(Cursor::new(&corrupted[..]))?;
+		assert_eq!(reader.read(&mut [0; 10])?, 10);
+		reader.seek(SeekFrom::Start(8500))?;
+		assert!(reader.read(&mut [0; 10]).is_err());
+
+		// a block must end right where the index says the next one starts
+		let first: usize = Header::read(&mut &compressed[..])?.size() as usize;
+		for compressed_length in [u32::MAX, 100] {
+			let mut forged: Vec<u8> = compressed.clone();
+			forged[first + 4..first + 8].copy_from_slice(&compressed_length.to_le_bytes());
+			let mut reader: SeekableSrxReader<Cursor<&[u8]>> =
+				SeekableSrxReader::new(Cursor::new(&forged[..]))?;
+			let error: std::io::Error = reader.read(&mut [0; 10]).unwrap_err();
+			assert_eq!(
+				error.to_string(),
+				FormatError::CorruptIndex {
+					offset: first as u64
+				}
+				.to_string()
+			);
+		}
+		Ok(())
+	}
+}
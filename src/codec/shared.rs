@@ -17,8 +17,8 @@
  */
 
 use crate::basic::{
-	AnyError, AnyResult, Closable, Consumer, FromProducer, PipedReader, PipedWriter, Producer,
-	ToConsumer,
+	AnyError, AnyResult, Closable, Consumer, Digest, FromProducer, PipedReader, PipedWriter,
+	Producer, ToConsumer,
 };
 use std::io::{Read, Write};
 use std::thread::ScopedJoinHandle;
@@ -43,6 +43,28 @@ pub fn run_file_reader<R: Read, const IO_BUFFER_SIZE: usize>(
 	Ok(reader.0)
 }
 
+// the input to encode is digested in the slices read from it, away from the loop of the encoder
+struct DigestedReader<R: Read>(R, Digest);
+
+impl<R: Read> Producer<u8> for DigestedReader<R> {
+	fn produce(&mut self, buffer: &mut [u8]) -> AnyResult<usize> {
+		let length: usize = self.0.read(buffer)?;
+		self.1.update_slice(&buffer[..length]);
+		Ok(length)
+	}
+}
+
+pub fn run_digested_file_reader<R: Read, const IO_BUFFER_SIZE: usize>(
+	std_reader: R,
+	mut writer: PipedWriter<u8, IO_BUFFER_SIZE>,
+	digest: Digest,
+) -> AnyResult<(R, Digest)> {
+	let mut reader: DigestedReader<R> = DigestedReader(std_reader, digest);
+	while writer.produce(&mut reader)? > 0 {}
+	writer.close()?;
+	Ok((reader.0, reader.1))
+}
+
 // -----------------------------------------------
 
 struct WrappedWriter<W: Write>(W);
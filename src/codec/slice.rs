@@ -0,0 +1,92 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::block::BlockDecoder;
+use super::encoder::ContextEncoder;
+use crate::basic::{AnyResult, Byte, Closable};
+use crate::container::Header;
+
+// -----------------------------------------------
+
+pub fn encode_slice(input: &[u8], header: &Header, output: Vec<u8>) -> AnyResult<Vec<u8>> {
+	let mut encoder: ContextEncoder<Vec<u8>> = ContextEncoder::new(header, output)?;
+	for &value in input {
+		encoder.encode(Byte::from(value))?;
+	}
+	encoder.close()
+}
+
+// the position is the offset of the compressed stream in the whole input, return the remaining
+// input after the compressed stream together with the output
+pub fn decode_slice<'a>(
+	input: &'a [u8],
+	header: &Header,
+	position: u64,
+	mut output: Vec<u8>,
+) -> AnyResult<(&'a [u8], Vec<u8>)> {
+	let mut decoder: BlockDecoder<&[u8]> = BlockDecoder::new(input, header, position)?;
+	while let Some(next_byte) = decoder.decode()? {
+		output.push(next_byte.into());
+	}
+	Ok((decoder.close()?, output))
+}
+
+#[cfg(test)]
+mod test {
+	use super::{decode_slice, encode_slice};
+	use crate::container::Header;
+	use crate::{compress, compress_slice, decompress_slice, AnyResult, Options};
+
+	#[test]
+	fn test_slice() -> AnyResult<()> {
+		let options: Options = Options::new();
+		for length in [0, 1, 100, 5000, 50000] {
+			let data: Vec<u8> = (0..length as u32)
+				.map(|index: u32| b"slice "[index as usize % 6] ^ (index >> 10) as u8)
+				.collect();
+
+			// the stream after the header is decoded up to its end, leaving what follows it
+			let header: Header = Header::new(&options);
+			let mut stream: Vec<u8> = Vec::new();
+			header.write(&mut stream)?;
+			let mut stream: Vec<u8> = encode_slice(&data, &header, stream)?;
+			stream.extend_from_slice(b"trailer");
+			let start: u64 = header.size();
+			let (rest, decoded): (&[u8], Vec<u8>) =
+				decode_slice(&stream[start as usize..], &header, start, Vec::new())?;
+			assert_eq!(rest, b"trailer");
+			assert_eq!(decoded, data);
+
+			// the same stream as the threaded coders, with or without blocks
+			let length_options: Options = options.clone().length(data.len() as u64);
+			for options in [
+				options.clone(),
+				length_options.clone(),
+				length_options.fit_memory(true),
+				options.clone().fit_memory(true).block_size(4000),
+			] {
+				let (_, expected): (&[u8], Vec<u8>) = compress(&data[..], Vec::new(), &options)?;
+				let compressed: Vec<u8> = compress_slice(&data, &options)?;
+				assert_eq!(compressed, expected);
+				assert_eq!(decompress_slice(&compressed, &options)?, data);
+			}
+		}
+		assert!(decompress_slice(b"not srx", &options).is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,271 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::block::BlockDecoder;
+use super::encoder::ContextEncoder;
+use crate::basic::{AnyError, AnyResult, Byte, Closable, Digest, IoReader, IoWriter};
+use crate::container::{check_end, read_member, Dictionary, Header};
+use crate::{Options, STREAM_BUFFER_SIZE};
+use std::io;
+use std::io::{Read, Write};
+
+// -----------------------------------------------
+
+type StreamEncoder<W> = ContextEncoder<IoWriter<W, STREAM_BUFFER_SIZE>>;
+
+/// A [`Write`] adapter that compresses everything written to it into a `.srx` stream.
+///
+/// The stream is completed by [`SrxWriter::finish`], or on drop while ignoring any error.
+pub struct SrxWriter<W: Write> {
+	header: Header,
+	encoder: Option<StreamEncoder<W>>,
+	digest: Digest,
+}
+
+impl<W: Write> SrxWriter<W> {
+	/// Create a compressor with the default options, writing the `.srx` header right away.
+	pub fn new(writer: W) -> AnyResult<Self> {
+		Self::with_options(writer, &Options::new())
+	}
+
+	/// Create a compressor, writing the `.srx` header to `writer` right away.
+	///
+	/// The block size and the block index are ignored, the adapter always writes a single stream.
+	pub fn with_options(mut writer: W, options: &Options) -> AnyResult<Self> {
+		let header: Header = Header::new(&Options {
+			block_size: None,
+			seekable: false,
+			..options.clone()
+		});
+		header.write(&mut writer)?;
+		let encoder: StreamEncoder<W> = ContextEncoder::new(&header, IoWriter::new(writer))?;
+		Ok(Self {
+			digest: header.digest(),
+			header,
+			encoder: Some(encoder),
+		})
+	}
+
+	/// Complete the compressed stream and give the underlying writer back.
+	pub fn finish(mut self) -> AnyResult<W> {
+		self.close_encoder()
+	}
+
+	fn close_encoder(&mut self) -> AnyResult<W> {
+		match self.encoder.take() {
+			None => Err(AnyError::from_string("The stream is already finished!")),
+			Some(encoder) => {
+				let mut writer: W = encoder.close()?.close()?;
+				self.header.write_trailer(&mut writer, &self.digest)?;
+				Ok(writer)
+			}
+		}
+	}
+
+	fn get_encoder(&mut self) -> io::Result<&mut StreamEncoder<W>> {
+		self.encoder
+			.as_mut()
+			.ok_or_else(|| io::Error::other("The stream is already finished!"))
+	}
+}
+
+impl<W: Write> Write for SrxWriter<W> {
+	fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		let encoder: &mut StreamEncoder<W> = self.get_encoder()?;
+		for &value in buffer {
+			encoder.encode(Byte::from(value))?;
+		}
+		self.digest.update_slice(buffer);
+		Ok(buffer.len())
+	}
+
+	// only the bytes already produced by the coder can be written out before the stream ends
+	fn flush(&mut self) -> io::Result<()> {
+		let encoder: &mut StreamEncoder<W> = self.get_encoder()?;
+		Ok(encoder.get_mut().flush()?)
+	}
+}
+
+impl<W: Write> Drop for SrxWriter<W> {
+	fn drop(&mut self) {
+		if self.encoder.is_some() {
+			let _error_ignored_ = self.close_encoder();
+		}
+	}
+}
+
+// -----------------------------------------------
+
+/// A [`Read`] adapter that lazily decompresses a `.srx` stream.
+///
+/// The recorded length and checksum are verified when the end of each member is reached, and
+/// concatenated members are decompressed one after another. Once a read fails, every later read
+/// fails with the same error.
+pub struct SrxReader<R: Read> {
+	header: Header,
+	decoder: Option<BlockDecoder<IoReader<R, STREAM_BUFFER_SIZE>>>,
+	digest: Digest,
+	dictionary: Option<Dictionary>,
+	// the error that stopped the decoding, which can not go on past it, so that it is not taken
+	// for the end of the stream by a caller that reads again
+	failure: Option<(io::ErrorKind, String)>,
+}
+
+impl<R: Read> SrxReader<R> {
+	/// Create a decompressor with the default options, reading and checking the `.srx` header
+	/// from `reader` right away.
+	pub fn new(reader: R) -> AnyResult<Self> {
+		Self::with_options(reader, &Options::new())
+	}
+
+	/// Create a decompressor, reading and checking the `.srx` header from `reader` right away.
+	///
+	/// Only the [`dictionary`](Options::dictionary) is used, for the members compressed with one.
+	pub fn with_options(mut reader: R, options: &Options) -> AnyResult<Self> {
+		let mut header: Header = Header::read(&mut reader)?;
+		header.attach_dictionary(options.dictionary.as_ref())?;
+		let decoder: BlockDecoder<IoReader<R, STREAM_BUFFER_SIZE>> =
+			BlockDecoder::new(IoReader::new(reader), &header, header.size())?;
+		Ok(Self {
+			digest: header.digest(),
+			header,
+			decoder: Some(decoder),
+			dictionary: options.dictionary.clone(),
+			failure: None,
+		})
+	}
+
+	// verify the member that just ended and start decoding the next one, if any
+	#[cold]
+	fn next_member(&mut self) -> AnyResult<()> {
+		if let Some(decoder) = self.decoder.take() {
+			let position: u64 = decoder.position();
+			let mut reader: IoReader<R, STREAM_BUFFER_SIZE> = decoder.close()?;
+			let position: u64 = self
+				.header
+				.read_trailer(&mut reader, &self.digest, position)?;
+			if self.header.is_legacy() {
+				check_end(&mut reader, position)?;
+			} else if let Some(mut header) = read_member(&mut reader, position)? {
+				header.attach_dictionary(self.dictionary.as_ref())?;
+				self.decoder = Some(BlockDecoder::new(
+					reader,
+					&header,
+					position + header.size(),
+				)?);
+				self.digest = header.digest();
+				self.header = header;
+			}
+		}
+		Ok(())
+	}
+
+	fn read_members(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		let mut length: usize = 0;
+		while length < buffer.len() {
+			let decoder = match &mut self.decoder {
+				None => break,
+				Some(decoder) => decoder,
+			};
+			let start: usize = length;
+			while length < buffer.len() {
+				match decoder.decode()? {
+					None => break,
+					Some(next_byte) => buffer[length] = next_byte.into(),
+				}
+				length += 1;
+			}
+			self.digest.update_slice(&buffer[start..length]);
+			if length < buffer.len() {
+				self.next_member()?;
+			}
+		}
+		Ok(length)
+	}
+}
+
+impl<R: Read> Read for SrxReader<R> {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		if let Some((kind, message)) = &self.failure {
+			return Err(io::Error::new(*kind, message.clone()));
+		}
+		let result: io::Result<usize> = self.read_members(buffer);
+		if let Err(error) = &result {
+			self.failure = Some((error.kind(), error.to_string()));
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{SrxReader, SrxWriter};
+	use crate::{compress_slice, AnyResult, Options};
+	use std::io;
+	use std::io::{Read, Write};
+
+	#[test]
+	fn test_adapters() -> AnyResult<()> {
+		let data: Vec<u8> = (0..50000u32)
+			.flat_map(|index: u32| format!("{} ", index * 7 % 1000).into_bytes())
+			.collect();
+		let expected: Vec<u8> = compress_slice(&data, &Options::new())?;
+
+		// writes of any size make the same stream as compressing the data at once
+		let mut writer: SrxWriter<Vec<u8>> = SrxWriter::new(Vec::new())?;
+		for chunk in data.chunks(777) {
+			writer.write_all(chunk)?;
+		}
+		writer.flush()?;
+		assert_eq!(writer.finish()?, expected);
+
+		let mut reader: SrxReader<&[u8]> = SrxReader::new(&expected[..])?;
+		let mut decompressed: Vec<u8> = Vec::new();
+		let mut buffer: [u8; 1000] = [0; 1000];
+		loop {
+			match reader.read(&mut buffer)? {
+				0 => break,
+				length => decompressed.extend_from_slice(&buffer[..length]),
+			}
+		}
+		assert_eq!(decompressed, data);
+		assert_eq!(reader.read(&mut buffer)?, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn test_reader_failure() -> AnyResult<()> {
+		let data: Vec<u8> = b"symbol ranking ".repeat(1000);
+		let mut compressed: Vec<u8> = compress_slice(&data, &Options::new())?;
+		*compressed.last_mut().unwrap() ^= 0x80;
+		let truncated: &[u8] = &compressed[..compressed.len() / 2];
+
+		// a reader that failed keeps failing, instead of reporting the end of the stream
+		for input in [&compressed[..], truncated] {
+			let mut reader: SrxReader<&[u8]> = SrxReader::new(input)?;
+			let error: io::Error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+			assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+			for _ in 0..2 {
+				let again: io::Error = reader.read(&mut [0; 100]).unwrap_err();
+				assert_eq!(again.kind(), error.kind());
+				assert_eq!(again.to_string(), error.to_string());
+			}
+		}
+		Ok(())
+	}
+}
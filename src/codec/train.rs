@@ -0,0 +1,294 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::{AnyResult, Byte};
+use crate::bridged_context::{BridgedContextInfo, BridgedPrimaryContext, ContextLayout};
+use crate::container::{Dictionary, Header};
+use crate::primary_context::ByteMatched;
+use crate::Options;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// -----------------------------------------------
+
+// the length of the byte sequences counted across the samples, and of the candidate segments
+const KEY_LENGTH: usize = 8;
+const SEGMENT_LENGTH: usize = 64;
+
+/// How many bytes of a sample each rank of the symbol ranking model predicted.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct MatchStats {
+	/// Bytes predicted as the most likely next byte.
+	pub first: u64,
+	/// Bytes predicted as the second most likely next byte.
+	pub second: u64,
+	/// Bytes predicted as the third most likely next byte, or a less likely one with wide
+	/// histories.
+	pub third: u64,
+	/// Bytes coded as literals.
+	pub missed: u64,
+	/// Bytes whose context evicted the history of another context from its bucket, which is only
+	/// known with [`tagged`](crate::Options::tagged) histories. This counts the contexts that
+	/// lost their history, not the hash collisions, which the tags make rare.
+	pub evictions: u64,
+}
+
+impl MatchStats {
+	/// The number of bytes counted.
+	pub fn total(&self) -> u64 {
+		self.first + self.second + self.third + self.missed
+	}
+
+	fn add(&mut self, matched: ByteMatched) {
+		match matched {
+			ByteMatched::FIRST => self.first += 1,
+			ByteMatched::SECOND => self.second += 1,
+			ByteMatched::NONE => self.missed += 1,
+			_ => self.third += 1,
+		}
+	}
+}
+
+// -----------------------------------------------
+
+// rank the bytes in the primary context and tell how every one of them was predicted
+fn rank_bytes<F: FnMut(ByteMatched)>(
+	context: &mut BridgedPrimaryContext,
+	layout: ContextLayout,
+	bytes: &[u8],
+	mut visit: F,
+) {
+	for &value in bytes {
+		let info: BridgedContextInfo = BridgedContextInfo::from_context(layout, context);
+		visit(context.matching(info.current_state(), Byte::from(value)));
+	}
+}
+
+// run the primary context alone over the dictionary of the header if any, the way the encoder
+// primes it, then over the sample, tell how every byte of the sample was predicted, and return how
+// many contexts of the sample evicted the history of another one
+fn run_model<F: FnMut(ByteMatched)>(sample: &[u8], header: &Header, visit: F) -> AnyResult<u64> {
+	let layout: ContextLayout = ContextLayout::new(header);
+	let mut context: BridgedPrimaryContext = BridgedPrimaryContext::new(
+		header.primary_context_bits(),
+		header.context_order(),
+		header.is_wide(),
+		header.is_tagged(),
+	);
+	if let Some(dictionary) = header.dictionary()? {
+		rank_bytes(&mut context, layout, dictionary.as_bytes(), |_: ByteMatched| ());
+	}
+	// the dictionary may have evicted contexts before the sample
+	let primed: u64 = context.evictions();
+	rank_bytes(&mut context, layout, sample, visit);
+	Ok(context.evictions() - primed)
+}
+
+pub fn match_stats(sample: &[u8], header: &Header) -> AnyResult<MatchStats> {
+	let mut stats: MatchStats = MatchStats::default();
+	stats.evictions = run_model(sample, header, |matched: ByteMatched| stats.add(matched))?;
+	Ok(stats)
+}
+
+// -----------------------------------------------
+
+// roughly the cost in bits of a byte predicted with the given rank
+fn miss_weight(matched: ByteMatched) -> u64 {
+	match matched {
+		ByteMatched::FIRST => 0,
+		ByteMatched::SECOND => 2,
+		ByteMatched::NONE => 8,
+		_ => 3,
+	}
+}
+
+fn key_at(sample: &[u8], end: usize) -> u64 {
+	let mut key: [u8; KEY_LENGTH] = [0; KEY_LENGTH];
+	key.copy_from_slice(&sample[end - KEY_LENGTH..end]);
+	u64::from_le_bytes(key)
+}
+
+// the options of the model that codes a sample alone, fitted to its length like the small payloads
+// a dictionary is made for, both when training and when measuring the dictionary
+pub fn sample_options(sample: &[u8]) -> Options {
+	Options::new().length(sample.len() as u64).fit_memory(true)
+}
+
+// the state of the greedy choice of the segments of the samples that go into the dictionary
+struct Trainer<'a> {
+	samples: &'a [&'a [u8]],
+	// the cost of every byte of every sample when the model is run over the sample alone
+	weights: Vec<Vec<u64>>,
+	// the number of samples holding each sequence, or zero once the dictionary holds it
+	counts: HashMap<u64, u64>,
+}
+
+impl<'a> Trainer<'a> {
+	fn new(samples: &'a [&'a [u8]]) -> AnyResult<Self> {
+		let mut counts: HashMap<u64, u64> = HashMap::new();
+		for sample in samples {
+			let keys: HashSet<u64> = (KEY_LENGTH..=sample.len())
+				.map(|end: usize| key_at(sample, end))
+				.collect();
+			for key in keys {
+				*counts.entry(key).or_insert(0) += 1;
+			}
+		}
+		let mut weights: Vec<Vec<u64>> = Vec::with_capacity(samples.len());
+		for sample in samples {
+			let header: Header = Header::new(&sample_options(sample));
+			let mut sample_weights: Vec<u64> = Vec::with_capacity(sample.len());
+			run_model(sample, &header, |matched: ByteMatched| {
+				sample_weights.push(miss_weight(matched))
+			})?;
+			weights.push(sample_weights);
+		}
+		Ok(Self {
+			samples,
+			weights,
+			counts,
+		})
+	}
+
+	fn segment(&self, sample: usize, start: usize) -> &'a [u8] {
+		let sample: &'a [u8] = self.samples[sample];
+		&sample[start..(start + SEGMENT_LENGTH).min(sample.len())]
+	}
+
+	// every byte the model missed counts once for every other sample where the sequence before it
+	// appears too, unless the dictionary already holds that sequence
+	fn score(&self, sample: usize, start: usize) -> u64 {
+		let end: usize = start + self.segment(sample, start).len();
+		(start + KEY_LENGTH..=end)
+			.map(|index: usize| {
+				let count: u64 = self.counts[&key_at(self.samples[sample], index)];
+				self.weights[sample][index - 1] * count.saturating_sub(1)
+			})
+			.sum()
+	}
+
+	// the sequences of the segment no longer help once the dictionary holds them
+	fn take(&mut self, sample: usize, start: usize, length: usize) -> &'a [u8] {
+		let bytes: &'a [u8] = &self.segment(sample, start)[..length];
+		for end in start + KEY_LENGTH..=start + length {
+			self.counts.insert(key_at(self.samples[sample], end), 0);
+		}
+		bytes
+	}
+}
+
+// choose segments of the samples greedily, the best first, until the dictionary is full: the model
+// is run over every sample alone to find the bytes it misses, and a segment is worth as much as
+// the misses it would turn into hits in the other samples
+pub fn train(samples: &[&[u8]], size: usize) -> AnyResult<Dictionary> {
+	let mut trainer: Trainer = Trainer::new(samples)?;
+
+	// scores only go down as segments are chosen, so a segment whose score is still the best
+	// after being updated is the best one
+	let mut heap: BinaryHeap<(u64, Reverse<(usize, usize)>)> = BinaryHeap::new();
+	for (sample, bytes) in samples.iter().enumerate() {
+		for start in (0..bytes.len()).step_by(SEGMENT_LENGTH) {
+			heap.push((trainer.score(sample, start), Reverse((sample, start))));
+		}
+	}
+	let mut chosen: Vec<&[u8]> = Vec::new();
+	let mut length: usize = 0;
+	while let Some((score, Reverse((sample, start)))) = heap.pop() {
+		if score == 0 || length >= size {
+			break;
+		}
+		let current: u64 = trainer.score(sample, start);
+		if current < score {
+			heap.push((current, Reverse((sample, start))));
+			continue;
+		}
+		let segment_length: usize = trainer.segment(sample, start).len().min(size - length);
+		chosen.push(trainer.take(sample, start, segment_length));
+		length += segment_length;
+	}
+
+	// the model favors what it saw last, so the best segments go at the end
+	Ok(Dictionary::new(
+		chosen.into_iter().rev().flatten().copied().collect(),
+	))
+}
+
+// -----------------------------------------------
+
+/// How much a dictionary helps to compress a sample, see
+/// [`measure_dictionary`](crate::measure_dictionary).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SampleGain {
+	/// The length of the sample.
+	pub length: u64,
+	/// The compressed size of the sample without the dictionary.
+	pub plain_size: u64,
+	/// The compressed size of the sample with the dictionary.
+	pub primed_size: u64,
+	/// How the model predicted the sample without the dictionary.
+	pub plain: MatchStats,
+	/// How the model predicted the sample with the dictionary.
+	pub primed: MatchStats,
+}
+
+#[cfg(test)]
+mod test {
+	use super::{match_stats, sample_options, train, MatchStats, SEGMENT_LENGTH};
+	use crate::basic::AnyResult;
+	use crate::container::{Dictionary, Header};
+	use crate::Options;
+
+	#[test]
+	fn test_train() -> AnyResult<()> {
+		// a segment shared by every sample, then bytes of each sample alone
+		let shared: Vec<u8> = (0..SEGMENT_LENGTH as u32)
+			.map(|index: u32| (index * 89 % 251) as u8)
+			.collect();
+		let mut seed: u32 = 0x12345678;
+		let samples: Vec<Vec<u8>> = (0..8)
+			.map(|_| {
+				let mut sample: Vec<u8> = shared.clone();
+				sample.extend((0..200).map(|_| {
+					seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+					(seed >> 16) as u8
+				}));
+				sample
+			})
+			.collect();
+		let references: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+
+		// only the shared segment helps the other samples, however large the dictionary
+		for size in [SEGMENT_LENGTH, 1000] {
+			let dictionary: Dictionary = train(&references[1..], size)?;
+			assert_eq!(dictionary.as_bytes(), shared);
+		}
+		let cut: Dictionary = train(&references[1..], 10)?;
+		assert_eq!(cut.as_bytes(), &shared[..10]);
+		assert!(train(&[], 1000)?.as_bytes().is_empty());
+
+		// which the sample left out of the training predicts better with
+		let dictionary: Dictionary = train(&references[1..], 1000)?;
+		let options: Options = sample_options(&samples[0]);
+		let plain: MatchStats = match_stats(&samples[0], &Header::new(&options))?;
+		let primed: MatchStats =
+			match_stats(&samples[0], &Header::new(&options.dictionary(dictionary)))?;
+		assert_eq!(primed.total(), samples[0].len() as u64);
+		assert!(primed.first > plain.first + SEGMENT_LENGTH as u64 / 2);
+		Ok(())
+	}
+}
@@ -0,0 +1,231 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::header::{read_exact, Header};
+use crate::basic::{AnyResult, FormatError, Reader};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// -----------------------------------------------
+
+const KIND_FILE: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+
+// -----------------------------------------------
+
+/// A file or a directory stored in an archive made by [`create_archive`](crate::create_archive).
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ArchiveEntry {
+	/// The relative path of the entry, with `/` between its components.
+	pub path: String,
+	/// Whether the entry is a directory, which has no data.
+	pub is_directory: bool,
+	/// The offset of the data of the entry in the decompressed data.
+	pub offset: u64,
+	/// The length of the data of the entry.
+	pub length: u64,
+}
+
+// -----------------------------------------------
+
+// extracting an archive must not write outside of the target directory
+fn is_valid_path(path: &str) -> bool {
+	!path.is_empty()
+		&& !path.contains(['\\', '\0'])
+		&& path
+			.split('/')
+			.all(|component| !component.is_empty() && component != "." && component != "..")
+}
+
+// the catalog of an archive, written after the trailer:
+//   entry count (u64), then for each entry:
+//     kind (u8, 0 for a file and 1 for a directory), path length (u16), path (UTF-8),
+//     offset of the data (u64), length of the data (u64)
+//   then the size of the catalog itself (u64), so that it can be found from the end of the file
+// the data of the entries follow each other in the decompressed data
+pub fn write_catalog<W: Write>(writer: &mut W, entries: &[ArchiveEntry]) -> AnyResult<()> {
+	let mut buffer: Vec<u8> = Vec::new();
+	buffer.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+	for entry in entries {
+		debug_assert!(is_valid_path(&entry.path) && entry.path.len() <= u16::MAX as usize);
+		buffer.push(match entry.is_directory {
+			true => KIND_DIRECTORY,
+			false => KIND_FILE,
+		});
+		buffer.extend_from_slice(&(entry.path.len() as u16).to_le_bytes());
+		buffer.extend_from_slice(entry.path.as_bytes());
+		buffer.extend_from_slice(&entry.offset.to_le_bytes());
+		buffer.extend_from_slice(&entry.length.to_le_bytes());
+	}
+	buffer.extend_from_slice(&(buffer.len() as u64).to_le_bytes());
+	Ok(writer.write_all(&buffer)?)
+}
+
+fn read_bytes<R: Reader<u8>>(
+	reader: &mut R,
+	buffer: &mut [u8],
+	position: &mut u64,
+) -> AnyResult<()> {
+	for value in buffer.iter_mut() {
+		*value = reader
+			.read()?
+			.ok_or(FormatError::Truncated { offset: *position })?;
+		*position += 1;
+	}
+	Ok(())
+}
+
+// read and verify the catalog at the given offset, and return its entries with the offset right
+// after the catalog and the length of the data of all the entries
+pub fn read_catalog<R: Reader<u8>>(
+	reader: &mut R,
+	offset: u64,
+) -> AnyResult<(Vec<ArchiveEntry>, u64, u64)> {
+	let corrupt: FormatError = FormatError::CorruptCatalog { offset };
+	let mut position: u64 = offset;
+	let mut buffer: [u8; 8] = [0; 8];
+	read_bytes(reader, &mut buffer, &mut position)?;
+	let count: u64 = u64::from_le_bytes(buffer);
+	let mut entries: Vec<ArchiveEntry> = Vec::new();
+	let mut length: u64 = 0;
+	for _ in 0..count {
+		let mut fixed: [u8; 3] = [0; 3];
+		read_bytes(reader, &mut fixed, &mut position)?;
+		let mut path: Vec<u8> = vec![0; u16::from_le_bytes([fixed[1], fixed[2]]) as usize];
+		read_bytes(reader, &mut path, &mut position)?;
+		let mut data: [u8; 16] = [0; 16];
+		read_bytes(reader, &mut data, &mut position)?;
+		let entry: ArchiveEntry = ArchiveEntry {
+			path: String::from_utf8(path).map_err(|_| corrupt.clone())?,
+			is_directory: match fixed[0] {
+				KIND_FILE => false,
+				KIND_DIRECTORY => true,
+				_ => return Err(corrupt.into()),
+			},
+			offset: u64::from_le_bytes(data[..8].try_into().unwrap()),
+			length: u64::from_le_bytes(data[8..].try_into().unwrap()),
+		};
+		if !is_valid_path(&entry.path)
+			|| entry.offset != length
+			|| (entry.is_directory && entry.length != 0)
+		{
+			return Err(corrupt.into());
+		}
+		length = length.checked_add(entry.length).ok_or(corrupt.clone())?;
+		entries.push(entry);
+	}
+	let size: u64 = position - offset;
+	read_bytes(reader, &mut buffer, &mut position)?;
+	if u64::from_le_bytes(buffer) != size {
+		return Err(corrupt.into());
+	}
+	Ok((entries, position, length))
+}
+
+// read the catalog from the end of an archive which starts at the given position
+pub fn find_catalog<R: Read + Seek>(
+	reader: &mut R,
+	header: &Header,
+	base: u64,
+) -> AnyResult<Vec<ArchiveEntry>> {
+	let end: u64 = reader.seek(SeekFrom::End(0))? - base;
+	// the smallest archive has the header, the trailer and an empty catalog
+	let footer: u64 = match end.checked_sub(8) {
+		Some(footer) if footer >= header.size() + header.trailer_size() + 8 => footer,
+		_ => return Err(FormatError::Truncated { offset: end }.into()),
+	};
+	let mut buffer: [u8; 8] = [0; 8];
+	reader.seek(SeekFrom::Start(base + footer))?;
+	read_exact(reader, &mut buffer, footer)?;
+	let offset: u64 = match footer.checked_sub(u64::from_le_bytes(buffer)) {
+		Some(offset) if offset >= header.size() + header.trailer_size() => offset,
+		_ => return Err(FormatError::CorruptCatalog { offset: footer }.into()),
+	};
+	let mut data: Vec<u8> = vec![0; (end - offset) as usize];
+	reader.seek(SeekFrom::Start(base + offset))?;
+	read_exact(reader, &mut data, offset)?;
+	let (entries, _, length): (Vec<ArchiveEntry>, u64, u64) = read_catalog(&mut &data[..], offset)?;
+	if header
+		.original_length()
+		.is_some_and(|expected| expected != length)
+	{
+		return Err(FormatError::CorruptCatalog { offset }.into());
+	}
+	Ok(entries)
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{is_valid_path, read_catalog, write_catalog, ArchiveEntry};
+	use crate::basic::{AnyError, AnyResult, FormatError};
+
+	#[test]
+	fn test_catalog() -> AnyResult<()> {
+		let entry = |path: &str, is_directory: bool, offset: u64, length: u64| ArchiveEntry {
+			path: path.to_string(),
+			is_directory,
+			offset,
+			length,
+		};
+		let entries: Vec<ArchiveEntry> = vec![
+			entry("tree", true, 0, 0),
+			entry("tree/a.txt", false, 0, 5),
+			entry("tree/empty", true, 5, 0),
+			entry("single", false, 5, 3),
+		];
+		let mut catalog: Vec<u8> = Vec::new();
+		write_catalog(&mut catalog, &entries)?;
+		let end: u64 = 100 + catalog.len() as u64;
+		assert_eq!(
+			read_catalog(&mut &catalog[..], 100)?,
+			(entries.clone(), end, 8)
+		);
+
+		// the paths can not escape the target directory
+		for path in ["a", "a/b", "a.b/.c"] {
+			assert!(is_valid_path(path));
+		}
+		for path in ["", "/a", "a/", "a//b", "..", "a/../b", "./a", "a\\b", "a\0"] {
+			assert!(!is_valid_path(path));
+		}
+		let position: usize = catalog.len() - 8 - 16 - 6;
+		assert_eq!(&catalog[position..position + 6], b"single");
+		let mut escaping: Vec<u8> = catalog.clone();
+		escaping[position..position + 3].copy_from_slice(b"../");
+
+		// the data of the entries follow each other, and the catalog ends with its size
+		let mut overlapping: Vec<u8> = catalog.clone();
+		overlapping[position + 6] = 4;
+		let mut resized: Vec<u8> = catalog.clone();
+		*resized.last_mut().unwrap() = 1;
+		for corrupted in [escaping, overlapping, resized] {
+			assert!(matches!(
+				read_catalog(&mut &corrupted[..], 100),
+				Err(AnyError::Format(FormatError::CorruptCatalog {
+					offset: 100
+				}))
+			));
+		}
+		assert!(matches!(
+			read_catalog(&mut &catalog[..catalog.len() - 1], 100),
+			Err(AnyError::Format(FormatError::Truncated { offset })) if offset == end - 1
+		));
+		Ok(())
+	}
+}
@@ -0,0 +1,66 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::Digest;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+// -----------------------------------------------
+
+/// A preset dictionary, given with [`Options::dictionary`](crate::Options::dictionary), which
+/// primes the model before compressing so that small inputs sharing content with it compress well
+/// from their first byte.
+///
+/// The header records the id of the dictionary, which is the CRC-32C of its content, and a stream
+/// compressed with a dictionary can only be decompressed with the same one. Cloning is cheap.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Dictionary {
+	id: u32,
+	data: Arc<[u8]>,
+}
+
+impl Dictionary {
+	/// Make a dictionary of the given content, which is typically a concatenation of samples.
+	pub fn new(data: Vec<u8>) -> Self {
+		Self {
+			id: Digest::from_slice(&data).checksum().unwrap_or_default(),
+			data: data.into(),
+		}
+	}
+
+	/// The id recorded in the header of the streams compressed with this dictionary.
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+
+	/// The content of the dictionary, which is coded without output before the data.
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.data
+	}
+}
+
+impl Debug for Dictionary {
+	fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+		formatter
+			.debug_struct("Dictionary")
+			.field("id", &format_args!("{:08X}", self.id))
+			.field("length", &self.data.len())
+			.finish()
+	}
+}
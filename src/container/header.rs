@@ -0,0 +1,949 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::catalog::{read_catalog, ArchiveEntry};
+use super::dictionary::Dictionary;
+use super::metadata::FileMetadata;
+use crate::basic::{AnyError, AnyResult, Digest, FormatError, Reader};
+use crate::bridged_context::{
+	literal_context_bits, LITERAL_CONTEXT_BITS, MAX_PRIMARY_CONTEXT_BITS,
+	MAX_PRIMARY_CONTEXT_ORDER, MIN_LITERAL_CONTEXT_BITS, MIN_PRIMARY_CONTEXT_BITS,
+	PRIMARY_CONTEXT_BITS,
+};
+use crate::secondary_context::CellType;
+use crate::{Options, SRX_MAGIC};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+// -----------------------------------------------
+
+// v0.3 files: the magic bytes directly followed by the compressed stream
+const LEGACY_VERSION: u8 = 0;
+// version 2 only adds the extended flags, so streams without any are still written as version 1,
+// which older decoders can read
+const FLAGS_VERSION: u8 = 1;
+const CURRENT_VERSION: u8 = 2;
+
+const FLAG_LENGTH: u16 = 1 << 0;
+const FLAG_CHECKSUM: u16 = 1 << 1;
+const FLAG_BLOCKS: u16 = 1 << 2;
+const FLAG_INDEX: u16 = 1 << 3;
+const FLAG_CATALOG: u16 = 1 << 4;
+const FLAG_METADATA: u16 = 1 << 5;
+const FLAG_DICTIONARY: u16 = 1 << 6;
+const FLAG_MAX: u16 = 1 << 7;
+const FLAG_APM: u16 = 1 << 8;
+const FLAG_ADAPTIVE: u16 = 1 << 9;
+const FLAG_CELLS: u16 = 1 << 10;
+const FLAG_ORDER: u16 = 1 << 11;
+const FLAG_WIDE: u16 = 1 << 12;
+const FLAG_FALLBACK: u16 = 1 << 13;
+const FLAG_MATCH: u16 = 1 << 14;
+const KNOWN_FLAGS: u16 = FLAG_LENGTH
+	| FLAG_CHECKSUM
+	| FLAG_BLOCKS
+	| FLAG_INDEX
+	| FLAG_CATALOG
+	| FLAG_METADATA
+	| FLAG_DICTIONARY
+	| FLAG_MAX
+	| FLAG_APM
+	| FLAG_ADAPTIVE
+	| FLAG_CELLS
+	| FLAG_ORDER
+	| FLAG_WIDE
+	| FLAG_FALLBACK
+	| FLAG_MATCH;
+
+const EXTENDED_EXCLUSION: u8 = 1 << 0;
+const EXTENDED_TAGGED: u8 = 1 << 1;
+const KNOWN_EXTENDED_FLAGS: u8 = EXTENDED_EXCLUSION | EXTENDED_TAGGED;
+
+// the kinds of counter cells
+const CELLS_COUNTER16: u8 = 1;
+const CELLS_COUNTER32: u8 = 2;
+
+// the block size of seekable streams when none is given
+const DEFAULT_BLOCK_SIZE: u32 = 8 << 20;
+
+// a fitted stream of known length gets no more than 64 histories per byte coded into one model,
+// the dictionary included, so that small inputs do not pay for setting up a model of the full level
+const HISTORIES_PER_BYTE: u64 = 64;
+
+// -----------------------------------------------
+
+// magic (3 bytes), version (1 byte), then for versions 1 and 2:
+//   flags (u16), primary context bits (u8), literal context bits (u8, from 6 to 14),
+//   extended flags (u8, only in version 2),
+//   original length (u64, if FLAG_LENGTH), block size (u32, if FLAG_BLOCKS),
+//   id of the preset dictionary (u32, if FLAG_DICTIONARY),
+//   kind of the counter cells (u8, 1 for 16-bit and 2 for 32-bit) and their shift or limit (u16,
+//   if FLAG_CELLS, otherwise the cells are bit history states),
+//   order of the primary context hash (u8, from 1 to 8, if FLAG_ORDER, otherwise the hash rolls)
+//   metadata of the original file (if FLAG_METADATA, see the metadata module)
+// the compressed stream follows, which with FLAG_BLOCKS is a sequence of independent blocks:
+//   original length (u32), compressed length (u32), compressed stream of the block
+// ended by a block with both lengths set to zero, then with FLAG_INDEX the block index:
+//   block count (u64), then for each block: original offset (u64), offset of the block (u64),
+//   then the original length (u64) and the offset of the block index itself (u64)
+// and finally the trailer:
+//   CRC-32C of the original data (u32, if FLAG_CHECKSUM)
+// followed with FLAG_CATALOG by the catalog of the archived files, see the catalog module
+// with FLAG_MAX, every bit is coded with the prediction of the mixing model instead, and with
+// FLAG_APM the predictions of the secondary context are refined by an adaptive probability map,
+// which with FLAG_ADAPTIVE are learned for every state instead of taken from the state table
+// with FLAG_WIDE, the histories of the primary context rank 7 bytes instead of 3, with
+// FLAG_FALLBACK the misses of the primary context are ranked again in an order-2 context, and with
+// FLAG_MATCH every byte is first checked against the byte expected by the long-range match model
+// with EXTENDED_EXCLUSION, the literals exclude the bytes already ruled out and are coded in a mix
+// of the contexts of the last byte and of the last 2 bytes, and with EXTENDED_TAGGED the histories
+// of the primary context are kept in buckets of 3 with the check tags of their contexts
+// all integers are little endian
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Header {
+	version: u8,
+	flags: u16,
+	primary_context_bits: u8,
+	literal_context_bits: u8,
+	extended_flags: u8,
+	original_length: Option<u64>,
+	block_size: Option<u32>,
+	dictionary_id: Option<u32>,
+	cell_type: CellType,
+	context_order: Option<u8>,
+	metadata: Option<FileMetadata>,
+	// the dictionary itself, given when compressing or attached before decompressing
+	dictionary: Option<Dictionary>,
+}
+
+impl Header {
+	pub fn new(options: &Options) -> Self {
+		let mut flags: u16 = 0;
+		if options.length.is_some() {
+			flags |= FLAG_LENGTH;
+		}
+		if options.checksum {
+			flags |= FLAG_CHECKSUM;
+		}
+		let block_size: Option<u32> = match options.seekable {
+			true => Some(options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE)),
+			false => options.block_size,
+		};
+		if block_size.is_some() {
+			flags |= FLAG_BLOCKS;
+		}
+		if options.seekable {
+			flags |= FLAG_INDEX;
+		}
+		if options.metadata.is_some() {
+			flags |= FLAG_METADATA;
+		}
+		if options.dictionary.is_some() {
+			flags |= FLAG_DICTIONARY;
+		}
+		if options.max {
+			flags |= FLAG_MAX;
+		}
+		if options.apm {
+			flags |= FLAG_APM;
+		}
+		if options.cell_type != CellType::State {
+			flags |= FLAG_CELLS;
+		} else if options.adaptive {
+			flags |= FLAG_ADAPTIVE;
+		}
+		if options.context_order.is_some() {
+			flags |= FLAG_ORDER;
+		}
+		if options.wide {
+			flags |= FLAG_WIDE;
+		}
+		if options.fallback {
+			flags |= FLAG_FALLBACK;
+		}
+		if options.long_match {
+			flags |= FLAG_MATCH;
+		}
+		let mut extended_flags: u8 = 0;
+		if options.exclusion {
+			extended_flags |= EXTENDED_EXCLUSION;
+		}
+		if options.tagged {
+			extended_flags |= EXTENDED_TAGGED;
+		}
+		let memory_level: u8 = match (options.fit_memory, options.length) {
+			(false, _) | (true, None) => options.memory_level,
+			(true, Some(length)) => {
+				let dictionary: &[u8] = options
+					.dictionary
+					.as_ref()
+					.map_or(&[], Dictionary::as_bytes);
+				let coded: u64 =
+					length.min(block_size.map_or(u64::MAX, u64::from)) + dictionary.len() as u64;
+				let histories: u64 = coded.saturating_mul(HISTORIES_PER_BYTE).max(1);
+				let fitting: u8 = (u64::BITS - (histories - 1).leading_zeros()) as u8;
+				fitting.clamp(MIN_PRIMARY_CONTEXT_BITS, options.memory_level)
+			}
+		};
+		Self {
+			version: match extended_flags {
+				0 => FLAGS_VERSION,
+				_ => CURRENT_VERSION,
+			},
+			flags,
+			primary_context_bits: memory_level,
+			literal_context_bits: literal_context_bits(memory_level),
+			extended_flags,
+			original_length: options.length,
+			block_size,
+			dictionary_id: options.dictionary.as_ref().map(Dictionary::id),
+			cell_type: options.cell_type,
+			context_order: options.context_order,
+			metadata: options.metadata.clone(),
+			dictionary: options.dictionary.clone(),
+		}
+	}
+
+	fn legacy() -> Self {
+		Self {
+			version: LEGACY_VERSION,
+			flags: 0,
+			primary_context_bits: PRIMARY_CONTEXT_BITS,
+			literal_context_bits: LITERAL_CONTEXT_BITS,
+			extended_flags: 0,
+			original_length: None,
+			block_size: None,
+			dictionary_id: None,
+			cell_type: CellType::State,
+			context_order: None,
+			metadata: None,
+			dictionary: None,
+		}
+	}
+
+	// mark the stream as an archive, which is followed by a catalog
+	pub fn with_catalog(mut self) -> Self {
+		debug_assert!(!self.is_seekable());
+		self.flags |= FLAG_CATALOG;
+		self
+	}
+
+	// the primary context holds 2^bits histories
+	pub fn primary_context_bits(&self) -> u8 {
+		self.primary_context_bits
+	}
+
+	// the literal context holds 2^bits contexts of 256 cells
+	pub fn literal_context_bits(&self) -> u8 {
+		self.literal_context_bits
+	}
+
+	// the primary context hashes the last bytes of this order, or rolls its hash if none
+	pub fn context_order(&self) -> Option<u8> {
+		self.context_order
+	}
+
+	pub fn original_length(&self) -> Option<u64> {
+		self.original_length
+	}
+
+	pub fn metadata(&self) -> Option<&FileMetadata> {
+		self.metadata.as_ref()
+	}
+
+	// give the dictionary to decompress with, which must be the one the stream was compressed
+	// with if any, and is ignored otherwise
+	pub fn attach_dictionary(&mut self, dictionary: Option<&Dictionary>) -> AnyResult<()> {
+		if let Some(expected) = self.dictionary_id {
+			match dictionary {
+				Some(dictionary) if dictionary.id() == expected => {
+					self.dictionary = Some(dictionary.clone());
+				}
+				_ => {
+					return Err(FormatError::DictionaryMismatch {
+						expected,
+						actual: dictionary.map(Dictionary::id),
+					}
+					.into());
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// the dictionary to prime the model with, which must have been attached when decompressing
+	pub fn dictionary(&self) -> AnyResult<Option<&Dictionary>> {
+		match (self.dictionary_id, &self.dictionary) {
+			(Some(expected), None) => Err(FormatError::DictionaryMismatch {
+				expected,
+				actual: None,
+			}
+			.into()),
+			(_, dictionary) => Ok(dictionary.as_ref()),
+		}
+	}
+
+	// the maximum original length of a block, for streams made of independent blocks
+	pub fn block_size(&self) -> Option<u32> {
+		self.block_size
+	}
+
+	// whether the blocks are followed by a block index
+	pub fn is_seekable(&self) -> bool {
+		self.flags & FLAG_INDEX != 0
+	}
+
+	// whether the trailer is followed by the catalog of an archive
+	pub fn is_archive(&self) -> bool {
+		self.flags & FLAG_CATALOG != 0
+	}
+
+	// the size of the trailer in bytes, not counting the catalog
+	pub fn trailer_size(&self) -> u64 {
+		match self.flags & FLAG_CHECKSUM {
+			0 => 0,
+			_ => 4,
+		}
+	}
+
+	// whether the stream is coded at the max level, with the mixing model
+	pub fn is_max(&self) -> bool {
+		self.flags & FLAG_MAX != 0
+	}
+
+	// whether the predictions are refined by an adaptive probability map
+	pub fn is_refined(&self) -> bool {
+		self.flags & FLAG_APM != 0
+	}
+
+	// whether the probabilities of the bit history states are learned instead of fixed
+	pub fn is_adaptive(&self) -> bool {
+		self.flags & FLAG_ADAPTIVE != 0
+	}
+
+	// whether the histories of the primary context rank 7 bytes instead of 3
+	pub fn is_wide(&self) -> bool {
+		self.flags & FLAG_WIDE != 0
+	}
+
+	// whether the misses of the primary context fall back to a lower order context
+	pub fn has_fallback(&self) -> bool {
+		self.flags & FLAG_FALLBACK != 0
+	}
+
+	// whether the bytes are first checked against the long-range match model
+	pub fn has_match_model(&self) -> bool {
+		self.flags & FLAG_MATCH != 0
+	}
+
+	// whether the contexts of the primary context are told apart by check tags
+	pub fn is_tagged(&self) -> bool {
+		self.extended_flags & EXTENDED_TAGGED != 0
+	}
+
+	// whether the literals exclude the bytes already ruled out, in the contexts of the last bytes
+	pub fn has_exclusion(&self) -> bool {
+		self.extended_flags & EXTENDED_EXCLUSION != 0
+	}
+
+	pub fn cell_type(&self) -> CellType {
+		self.cell_type
+	}
+
+	// whether the stream is coded with the default symbol ranking model and the fixed predictions
+	// of the states alone, without any of the optional models around them, so that the coders can
+	// be picked once for the stream without checking for those models on every bit
+	pub fn is_plain(&self) -> bool {
+		self.context_order().is_none()
+			&& !self.is_wide()
+			&& !self.is_tagged()
+			&& !self.is_max()
+			&& !self.is_refined()
+			&& !self.is_adaptive()
+			&& !self.has_fallback()
+			&& !self.has_match_model()
+			&& !self.has_exclusion()
+			&& self.cell_type == CellType::State
+	}
+
+	// v0.3 streams are padded by the decoder, so their end can not be checked
+	pub fn is_legacy(&self) -> bool {
+		self.version == LEGACY_VERSION
+	}
+
+	// the size of the header in bytes, which is the offset of the compressed stream
+	pub fn size(&self) -> u64 {
+		match self.version {
+			LEGACY_VERSION => 4,
+			_ => self.to_bytes().len() as u64,
+		}
+	}
+
+	// a digest of the original data, which only computes the CRC when the trailer records it
+	pub fn digest(&self) -> Digest {
+		match self.flags & FLAG_CHECKSUM {
+			0 => Digest::without_checksum(),
+			_ => Digest::new(),
+		}
+	}
+
+	// the CRC of a digest made by `digest`, if the trailer records one
+	fn checksum(&self, digest: &Digest) -> Option<u32> {
+		match self.flags & FLAG_CHECKSUM {
+			0 => None,
+			_ => Some(digest.checksum().unwrap_or_default()),
+		}
+	}
+
+	fn check_length(&self, digest: &Digest) -> AnyResult<()> {
+		match self.original_length {
+			Some(expected) if expected != digest.length() => Err(FormatError::LengthMismatch {
+				expected,
+				actual: digest.length(),
+			}
+			.into()),
+			_ => Ok(()),
+		}
+	}
+
+	// check the length of the compressed data and write the trailer
+	pub fn write_trailer<W: Write>(&self, writer: &mut W, digest: &Digest) -> AnyResult<()> {
+		self.check_length(digest)?;
+		if let Some(checksum) = self.checksum(digest) {
+			writer.write_all(&checksum.to_le_bytes())?;
+		}
+		Ok(())
+	}
+
+	// read the trailer at the given offset right after the compressed stream, verify the
+	// decompressed data and return the offset right after the trailer and the catalog
+	pub fn read_trailer<R: Reader<u8>>(
+		&self,
+		reader: &mut R,
+		digest: &Digest,
+		offset: u64,
+	) -> AnyResult<u64> {
+		self.check_length(digest)?;
+		let mut offset: u64 = offset;
+		if let Some(actual) = self.checksum(digest) {
+			let mut buffer: [u8; 4] = [0; 4];
+			for value in buffer.iter_mut() {
+				*value = reader.read()?.ok_or(FormatError::Truncated { offset })?;
+				offset += 1;
+			}
+			let expected: u32 = u32::from_le_bytes(buffer);
+			if expected != actual {
+				return Err(FormatError::ChecksumMismatch { expected, actual }.into());
+			}
+		}
+		if self.is_archive() {
+			let (_, end, length): (Vec<ArchiveEntry>, u64, u64) = read_catalog(reader, offset)?;
+			if length != digest.length() {
+				return Err(FormatError::CorruptCatalog { offset }.into());
+			}
+			return Ok(end);
+		}
+		Ok(offset)
+	}
+
+	pub fn write<W: Write>(&self, writer: &mut W) -> AnyResult<()> {
+		debug_assert!(self.version != LEGACY_VERSION);
+		Ok(writer.write_all(&self.to_bytes())?)
+	}
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut buffer: Vec<u8> = Vec::with_capacity(16);
+		buffer.extend_from_slice(SRX_MAGIC);
+		buffer.push(self.version);
+		buffer.extend_from_slice(&self.flags.to_le_bytes());
+		buffer.push(self.primary_context_bits);
+		buffer.push(self.literal_context_bits);
+		if self.version >= CURRENT_VERSION {
+			buffer.push(self.extended_flags);
+		}
+		if let Some(length) = self.original_length {
+			buffer.extend_from_slice(&length.to_le_bytes());
+		}
+		if let Some(block_size) = self.block_size {
+			buffer.extend_from_slice(&block_size.to_le_bytes());
+		}
+		if let Some(dictionary_id) = self.dictionary_id {
+			buffer.extend_from_slice(&dictionary_id.to_le_bytes());
+		}
+		match self.cell_type {
+			CellType::State => {}
+			CellType::Counter16 { shift } => {
+				buffer.push(CELLS_COUNTER16);
+				buffer.extend_from_slice(&(shift as u16).to_le_bytes());
+			}
+			CellType::Counter32 { limit } => {
+				buffer.push(CELLS_COUNTER32);
+				buffer.extend_from_slice(&limit.to_le_bytes());
+			}
+		}
+		if let Some(order) = self.context_order {
+			buffer.push(order);
+		}
+		if let Some(metadata) = &self.metadata {
+			metadata.write(&mut buffer);
+		}
+		buffer
+	}
+
+	pub fn read<R: Read>(reader: &mut R) -> AnyResult<Self> {
+		let mut magic: [u8; 4] = [0; 4];
+		match read_exact(reader, &mut magic, 0) {
+			Err(AnyError::Format(FormatError::Truncated { .. })) => {
+				return Err(FormatError::NotSrx.into());
+			}
+			result => result?,
+		}
+		if !magic.starts_with(SRX_MAGIC) {
+			return Err(FormatError::NotSrx.into());
+		}
+		match magic[3] {
+			LEGACY_VERSION => Ok(Self::legacy()),
+			version @ (FLAGS_VERSION | CURRENT_VERSION) => Self::read_flagged(reader, version),
+			version => Err(FormatError::UnsupportedVersion(version).into()),
+		}
+	}
+
+	fn read_flagged<R: Read>(reader: &mut R, version: u8) -> AnyResult<Self> {
+		let mut fixed: [u8; 4] = [0; 4];
+		read_exact(reader, &mut fixed, 4)?;
+		let flags: u16 = u16::from_le_bytes([fixed[0], fixed[1]]);
+		if flags & !KNOWN_FLAGS != 0 {
+			return Err(unsupported(format!("unknown flags 0x{:04X}", flags)));
+		}
+		if flags & (FLAG_INDEX | FLAG_BLOCKS) == FLAG_INDEX {
+			return Err(unsupported("block index without blocks".to_string()));
+		}
+		if flags & (FLAG_INDEX | FLAG_CATALOG) == FLAG_INDEX | FLAG_CATALOG {
+			return Err(unsupported("block index in an archive".to_string()));
+		}
+		if flags & (FLAG_ADAPTIVE | FLAG_CELLS) == FLAG_ADAPTIVE | FLAG_CELLS {
+			return Err(unsupported(
+				"learned state probabilities with counters".to_string(),
+			));
+		}
+		let primary_context_bits: u8 = fixed[2];
+		if !(MIN_PRIMARY_CONTEXT_BITS..=MAX_PRIMARY_CONTEXT_BITS).contains(&primary_context_bits) {
+			return Err(unsupported(format!(
+				"primary context of 2^{} entries",
+				primary_context_bits
+			)));
+		}
+		let literal_context_bits: u8 = fixed[3];
+		if !(MIN_LITERAL_CONTEXT_BITS..=LITERAL_CONTEXT_BITS).contains(&literal_context_bits) {
+			return Err(unsupported(format!(
+				"literal context of 2^{} entries",
+				literal_context_bits
+			)));
+		}
+		let mut offset: u64 = 8;
+		let extended_flags: u8 = if version >= CURRENT_VERSION {
+			let mut buffer: [u8; 1] = [0; 1];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 1;
+			buffer[0]
+		} else {
+			0
+		};
+		if extended_flags & !KNOWN_EXTENDED_FLAGS != 0 {
+			return Err(unsupported(format!(
+				"unknown extended flags 0x{:02X}",
+				extended_flags
+			)));
+		}
+		let original_length: Option<u64> = if flags & FLAG_LENGTH != 0 {
+			let mut buffer: [u8; 8] = [0; 8];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 8;
+			Some(u64::from_le_bytes(buffer))
+		} else {
+			None
+		};
+		let block_size: Option<u32> = if flags & FLAG_BLOCKS != 0 {
+			let mut buffer: [u8; 4] = [0; 4];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 4;
+			match u32::from_le_bytes(buffer) {
+				0 => return Err(unsupported("blocks of 0 bytes".to_string())),
+				block_size => Some(block_size),
+			}
+		} else {
+			None
+		};
+		let dictionary_id: Option<u32> = if flags & FLAG_DICTIONARY != 0 {
+			let mut buffer: [u8; 4] = [0; 4];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 4;
+			Some(u32::from_le_bytes(buffer))
+		} else {
+			None
+		};
+		let cell_type: CellType = if flags & FLAG_CELLS != 0 {
+			let mut buffer: [u8; 3] = [0; 3];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 3;
+			let parameter: u16 = u16::from_le_bytes([buffer[1], buffer[2]]);
+			let cell_type: CellType = match buffer[0] {
+				CELLS_COUNTER16 => CellType::Counter16 {
+					shift: parameter.min(u8::MAX as u16) as u8,
+				},
+				CELLS_COUNTER32 => CellType::Counter32 { limit: parameter },
+				kind => return Err(unsupported(format!("cells of kind {}", kind))),
+			};
+			if !cell_type.is_valid() {
+				return Err(unsupported(format!("{:?} cells", cell_type)));
+			}
+			cell_type
+		} else {
+			CellType::State
+		};
+		let context_order: Option<u8> = if flags & FLAG_ORDER != 0 {
+			let mut buffer: [u8; 1] = [0; 1];
+			read_exact(reader, &mut buffer, offset)?;
+			offset += 1;
+			match buffer[0] {
+				order @ 1..=MAX_PRIMARY_CONTEXT_ORDER => Some(order),
+				order => return Err(unsupported(format!("context of order {}", order))),
+			}
+		} else {
+			None
+		};
+		let metadata: Option<FileMetadata> = if flags & FLAG_METADATA != 0 {
+			Some(FileMetadata::read(|length: usize| {
+				let mut buffer: Vec<u8> = vec![0; length];
+				read_exact(reader, &mut buffer, offset)?;
+				offset += length as u64;
+				Ok(buffer)
+			})?)
+		} else {
+			None
+		};
+		Ok(Self {
+			version,
+			flags,
+			primary_context_bits,
+			literal_context_bits,
+			extended_flags,
+			original_length,
+			block_size,
+			dictionary_id,
+			cell_type,
+			context_order,
+			metadata,
+			dictionary: None,
+		})
+	}
+}
+
+// -----------------------------------------------
+
+// read the header of the member of a concatenated stream at the given offset, or None if the input
+// ends there; anything else than a header after the first member is trailing data
+pub fn read_member<R: Reader<u8>>(reader: &mut R, offset: u64) -> AnyResult<Option<Header>> {
+	let first: u8 = match reader.read()? {
+		None => return Ok(None),
+		Some(value) => value,
+	};
+	match Header::read(&mut [first].chain(ReaderAdapter(reader))) {
+		Ok(header) => Ok(Some(header)),
+		Err(AnyError::Format(FormatError::NotSrx)) if offset > 0 => {
+			Err(FormatError::TrailingData { offset }.into())
+		}
+		Err(AnyError::Format(FormatError::Truncated { offset: relative })) => {
+			Err(FormatError::Truncated {
+				offset: offset + relative,
+			}
+			.into())
+		}
+		Err(error) => Err(error),
+	}
+}
+
+// Header::read wants a Read, so feed it one byte at a time
+struct ReaderAdapter<'a, R: Reader<u8>>(&'a mut R);
+
+impl<'a, R: Reader<u8>> Read for ReaderAdapter<'a, R> {
+	fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+		match buffer.first_mut() {
+			None => Ok(0),
+			Some(value) => match self.0.read()? {
+				None => Ok(0),
+				Some(next_value) => {
+					*value = next_value;
+					Ok(1)
+				}
+			},
+		}
+	}
+}
+
+// make sure that the input ends at the given offset
+pub fn check_end<R: Reader<u8>>(reader: &mut R, offset: u64) -> AnyResult<()> {
+	match reader.read()? {
+		None => Ok(()),
+		Some(_) => Err(FormatError::TrailingData { offset }.into()),
+	}
+}
+
+// like Read::read_exact, but report where the input ends given the offset of the buffer
+pub fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8], offset: u64) -> AnyResult<()> {
+	let mut length: usize = 0;
+	while length < buffer.len() {
+		match reader.read(&mut buffer[length..]) {
+			Ok(0) => {
+				return Err(FormatError::Truncated {
+					offset: offset + length as u64,
+				}
+				.into());
+			}
+			Ok(read_length) => length += read_length,
+			Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+			Err(error) => return Err(error.into()),
+		}
+	}
+	Ok(())
+}
+
+#[cold]
+fn unsupported(reason: String) -> AnyError {
+	FormatError::UnsupportedHeader(reason).into()
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::Header;
+	use crate::basic::{AnyError, AnyResult, Digest, FormatError};
+	use crate::codec::encode_slice;
+	use crate::{decompress_slice, Dictionary, FileMetadata, Options, SRX_HEADER};
+	use std::time::SystemTime;
+
+	fn read_error(bytes: &[u8]) -> FormatError {
+		match Header::read(&mut &bytes[..]) {
+			Err(AnyError::Format(error)) => error,
+			Err(error) => panic!("unexpected error: {}", error),
+			Ok(header) => panic!("unexpected header: {:?}", header),
+		}
+	}
+
+	#[test]
+	fn test_header() -> AnyResult<()> {
+		let metadata: FileMetadata = FileMetadata {
+			name: "notes.txt".to_string(),
+			modified: SystemTime::UNIX_EPOCH,
+			mode: Some(0o644),
+			owner: None,
+		};
+		for options in [
+			Options::new(),
+			Options::new().checksum(false).length(12345),
+			Options::new().seekable(true).block_size(1000),
+			Options::new().metadata(metadata),
+			Options::new().level(3).apm(true).long_match(true),
+			Options::new().context_order(4).tagged(true),
+			Options::new().exclusion(true),
+		] {
+			let header: Header = Header::new(&options);
+			let mut bytes: Vec<u8> = Vec::new();
+			header.write(&mut bytes)?;
+			assert_eq!(bytes.len() as u64, header.size());
+			// version 1 unless some extended flags need version 2
+			assert_eq!(bytes[3], 1 + u8::from(options.exclusion || options.tagged));
+			assert_eq!(Header::read(&mut &bytes[..])?, header);
+
+			// cut anywhere after the magic bytes
+			for length in 4..bytes.len() {
+				assert!(matches!(
+					read_error(&bytes[..length]),
+					FormatError::Truncated { .. }
+				));
+			}
+		}
+
+		// v0.3 files have no header after the magic bytes
+		let legacy: Header = Header::read(&mut &SRX_HEADER[..])?;
+		assert!(legacy.is_legacy());
+		assert_eq!(legacy.size(), 4);
+		let data: Vec<u8> = b"symbol ranking ".repeat(100);
+		let stream: Vec<u8> = encode_slice(&data, &legacy, SRX_HEADER.to_vec())?;
+		assert_eq!(decompress_slice(&stream, &Options::new())?, data);
+
+		let mut bytes: Vec<u8> = Vec::new();
+		Header::new(&Options::new()).write(&mut bytes)?;
+		assert_eq!(read_error(&bytes[..2]), FormatError::NotSrx);
+		assert_eq!(read_error(b"gzip"), FormatError::NotSrx);
+		bytes[3] = 0x7F;
+		assert_eq!(read_error(&bytes), FormatError::UnsupportedVersion(0x7F));
+		// unknown flags, of which the last bit is one
+		bytes[3] = 1;
+		bytes[5] |= 0x80;
+		assert!(matches!(
+			read_error(&bytes),
+			FormatError::UnsupportedHeader(_)
+		));
+
+		// the order of the context is the last byte of the header, from 1 to 8
+		let header: Header = Header::new(&Options::new().context_order(8));
+		let mut bytes: Vec<u8> = Vec::new();
+		header.write(&mut bytes)?;
+		assert_eq!(Header::read(&mut &bytes[..])?.context_order(), Some(8));
+		for order in [0, 9] {
+			*bytes.last_mut().unwrap() = order;
+			assert!(matches!(
+				read_error(&bytes),
+				FormatError::UnsupportedHeader(_)
+			));
+		}
+
+		// the length is checked against the data on either side
+		let header: Header = Header::new(&Options::new().length(5));
+		let mut trailer: Vec<u8> = Vec::new();
+		header.write_trailer(&mut trailer, &Digest::from_slice(b"12345"))?;
+		assert_eq!(
+			header.read_trailer(&mut &trailer[..], &Digest::from_slice(b"12345"), 0)?,
+			4
+		);
+		assert!(matches!(
+			header.write_trailer(&mut Vec::new(), &Digest::from_slice(b"1234")),
+			Err(AnyError::Format(FormatError::LengthMismatch {
+				expected: 5,
+				actual: 4
+			}))
+		));
+
+		// the exclusion and the check tags are the extended flags, which follow the 8 bytes of the
+		// fixed header, and the unknown ones are rejected
+		for (options, flags) in [
+			(Options::new().exclusion(true), 1),
+			(Options::new().tagged(true), 2),
+		] {
+			let header: Header = Header::new(&options);
+			let mut bytes: Vec<u8> = Vec::new();
+			header.write(&mut bytes)?;
+			assert_eq!(bytes[8], flags);
+			assert_eq!(Header::read(&mut &bytes[..])?, header);
+			bytes[8] |= 0x80;
+			assert!(matches!(
+				read_error(&bytes),
+				FormatError::UnsupportedHeader(_)
+			));
+		}
+
+		// the wide histories come with the second level, the exclusion without the max mode
+		let header: Header = Header::new(&Options::new().level(2));
+		assert!(header.is_wide() && header.has_exclusion() && !header.is_max());
+		let header: Header = Header::new(&Options::new().level(3).exclusion(false));
+		assert!(header.is_wide() && !header.has_exclusion() && header.is_max());
+		assert!(!Header::new(&Options::new().wide(true).level(1)).is_wide());
+
+		// only the default model is coded by the plain coders
+		let options: Options = Options::new();
+		assert!(Header::new(&options).is_plain());
+		assert!(Header::new(&options.clone().memory_level(16).block_size(1000)).is_plain());
+		for other in [
+			options.clone().context_order(2),
+			options.clone().wide(true),
+			options.clone().tagged(true),
+			options.clone().apm(true),
+			options.clone().fallback(true),
+			options.clone().long_match(true),
+			options.clone().exclusion(true),
+		] {
+			assert!(!Header::new(&other).is_plain());
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_memory_level() -> AnyResult<()> {
+		// the level is recorded in the header with the size of the literal context, and must be in
+		// range
+		let header: Header = Header::new(&Options::new().memory_level(16));
+		assert_eq!(header.primary_context_bits(), 16);
+		assert_eq!(header.literal_context_bits(), 6);
+		assert_eq!(Header::new(&Options::new()).literal_context_bits(), 14);
+		let mut bytes: Vec<u8> = Vec::new();
+		header.write(&mut bytes)?;
+		assert_eq!(Header::read(&mut &bytes[..])?, header);
+		for (position, bits) in [(6, 15), (6, 29), (7, 5), (7, 15)] {
+			let mut forged: Vec<u8> = bytes.clone();
+			forged[position] = bits;
+			assert!(matches!(
+				read_error(&forged),
+				FormatError::UnsupportedHeader(_)
+			));
+		}
+
+		// a fitted level leaves 64 histories per byte of a stream or block of known length, the
+		// dictionary included
+		let options: Options = Options::new();
+		let length: Options = options.clone().length(10000);
+		assert_eq!(Header::new(&length).primary_context_bits(), 24);
+		let fitting: Options = options.clone().fit_memory(true);
+		assert_eq!(Header::new(&fitting).primary_context_bits(), 24);
+		let fitted: Header = Header::new(&length.clone().fit_memory(true));
+		assert_eq!(fitted.primary_context_bits(), 20);
+		assert_eq!(fitted.literal_context_bits(), 10);
+		let primed: Options = length
+			.fit_memory(true)
+			.dictionary(Dictionary::new(vec![0; 10000]));
+		assert_eq!(Header::new(&primed).primary_context_bits(), 21);
+		let blocks: Options = fitting.clone().block_size(1000).length(1 << 30);
+		assert_eq!(Header::new(&blocks).primary_context_bits(), 16);
+		let large: Options = fitting.clone().length(1 << 20);
+		assert_eq!(Header::new(&large).primary_context_bits(), 24);
+		Ok(())
+	}
+
+	#[test]
+	fn test_dictionary() -> AnyResult<()> {
+		let dictionary: Dictionary = Dictionary::new(b"symbol ranking".to_vec());
+		let other: Dictionary = Dictionary::new(b"symbol ranking ".to_vec());
+		assert_ne!(dictionary.id(), other.id());
+		let mut bytes: Vec<u8> = Vec::new();
+		Header::new(&Options::new().dictionary(dictionary.clone())).write(&mut bytes)?;
+
+		// only the id is recorded, and the same dictionary must be given back to decompress
+		let mut header: Header = Header::read(&mut &bytes[..])?;
+		let missing: FormatError = FormatError::DictionaryMismatch {
+			expected: dictionary.id(),
+			actual: None,
+		};
+		assert!(matches!(header.dictionary(), Err(AnyError::Format(error)) if error == missing));
+		for (given, actual) in [(None, None), (Some(&other), Some(other.id()))] {
+			assert!(matches!(
+				header.attach_dictionary(given),
+				Err(AnyError::Format(FormatError::DictionaryMismatch { expected, actual: found }))
+					if expected == dictionary.id() && found == actual
+			));
+		}
+		header.attach_dictionary(Some(&dictionary))?;
+		assert_eq!(header.dictionary()?, Some(&dictionary));
+
+		// and is ignored by the streams without one
+		let mut plain: Header = Header::new(&Options::new());
+		plain.attach_dictionary(Some(&other))?;
+		assert_eq!(plain.dictionary()?, None);
+		Ok(())
+	}
+}
@@ -0,0 +1,309 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::{AnyError, AnyResult, FormatError};
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// -----------------------------------------------
+
+const FIELD_MODE: u8 = 1 << 0;
+const FIELD_OWNER: u8 = 1 << 1;
+
+// -----------------------------------------------
+
+/// The metadata of the original file, recorded in the header with
+/// [`Options::metadata`](crate::Options::metadata) and read back with
+/// [`read_metadata`](crate::read_metadata).
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct FileMetadata {
+	/// The name of the original file, without its directory.
+	pub name: String,
+	/// The last modification time.
+	pub modified: SystemTime,
+	/// The Unix permission bits, if the platform has them.
+	pub mode: Option<u32>,
+	/// The Unix user and group ids of the owner, if the platform has them.
+	pub owner: Option<(u32, u32)>,
+}
+
+impl FileMetadata {
+	/// Collect the metadata of the file at `path`.
+	pub fn from_path(path: &Path) -> AnyResult<Self> {
+		let metadata: fs::Metadata = fs::metadata(path)?;
+		#[cfg(unix)]
+		let (mode, owner): (Option<u32>, Option<(u32, u32)>) = {
+			use std::os::unix::fs::MetadataExt;
+			(
+				Some(metadata.mode() & 0o7777),
+				Some((metadata.uid(), metadata.gid())),
+			)
+		};
+		#[cfg(not(unix))]
+		let (mode, owner): (Option<u32>, Option<(u32, u32)>) = (None, None);
+		Ok(Self {
+			name: path
+				.file_name()
+				.map(|name| name.to_string_lossy().into_owned())
+				.unwrap_or_default(),
+			modified: metadata.modified()?,
+			mode,
+			owner,
+		})
+	}
+
+	/// Restore the modification time and the permissions of the file at `path`, and with
+	/// `same_owner` its owner, when allowed. The name is left to the caller.
+	///
+	/// Since the metadata comes from the compressed input, the owner is only restored when asked
+	/// for, and the set-user-id, set-group-id and sticky bits only with it.
+	pub fn apply(&self, path: &Path, same_owner: bool) -> AnyResult<()> {
+		// first, while the file can still be opened for writing
+		File::options()
+			.write(true)
+			.open(path)?
+			.set_modified(self.modified)?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::{chown, PermissionsExt};
+			// only the super user can give a file away, like tar and gzip just keep the current owner
+			if let (true, Some((uid, gid))) = (same_owner, self.owner) {
+				match chown(path, Some(uid), Some(gid)) {
+					Err(error) if error.kind() == ErrorKind::PermissionDenied => {}
+					result => result?,
+				}
+			}
+			// last, after chown which clears the set-user-id and set-group-id bits
+			if let Some(mode) = self.mode {
+				let mode: u32 = match same_owner {
+					false => mode & 0o777,
+					true => mode,
+				};
+				fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+			}
+		}
+		Ok(())
+	}
+
+	// mtime seconds since the Unix epoch (i64), mtime nanoseconds (u32), fields (u8),
+	// mode (u32, if FIELD_MODE), uid (u32) and gid (u32, if FIELD_OWNER), name length (u16),
+	// name (UTF-8)
+	pub fn write(&self, buffer: &mut Vec<u8>) {
+		let (seconds, nanoseconds): (i64, u32) =
+			match self.modified.duration_since(SystemTime::UNIX_EPOCH) {
+				Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+				Err(error) => {
+					let duration: Duration = error.duration();
+					match duration.subsec_nanos() {
+						0 => (-(duration.as_secs() as i64), 0),
+						nanoseconds => (
+							-(duration.as_secs() as i64) - 1,
+							1_000_000_000 - nanoseconds,
+						),
+					}
+				}
+			};
+		buffer.extend_from_slice(&seconds.to_le_bytes());
+		buffer.extend_from_slice(&nanoseconds.to_le_bytes());
+		let mut fields: u8 = 0;
+		if self.mode.is_some() {
+			fields |= FIELD_MODE;
+		}
+		if self.owner.is_some() {
+			fields |= FIELD_OWNER;
+		}
+		buffer.push(fields);
+		if let Some(mode) = self.mode {
+			buffer.extend_from_slice(&mode.to_le_bytes());
+		}
+		if let Some((uid, gid)) = self.owner {
+			buffer.extend_from_slice(&uid.to_le_bytes());
+			buffer.extend_from_slice(&gid.to_le_bytes());
+		}
+		let name: &[u8] = self.name_bytes();
+		buffer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+		buffer.extend_from_slice(name);
+	}
+
+	// read the fields with the given function, which is given how many bytes to read
+	pub fn read<F: FnMut(usize) -> AnyResult<Vec<u8>>>(mut read_bytes: F) -> AnyResult<Self> {
+		let fixed: Vec<u8> = read_bytes(13)?;
+		let seconds: i64 = i64::from_le_bytes(fixed[..8].try_into().unwrap());
+		let nanoseconds: u32 = u32::from_le_bytes(fixed[8..12].try_into().unwrap());
+		let fields: u8 = fixed[12];
+		if nanoseconds >= 1_000_000_000 || fields & !(FIELD_MODE | FIELD_OWNER) != 0 {
+			return Err(invalid("file metadata"));
+		}
+		let offset: Duration = Duration::new(seconds.unsigned_abs(), 0);
+		let modified: SystemTime = match seconds >= 0 {
+			true => SystemTime::UNIX_EPOCH.checked_add(offset),
+			false => SystemTime::UNIX_EPOCH.checked_sub(offset),
+		}
+		.and_then(|time| time.checked_add(Duration::new(0, nanoseconds)))
+		.ok_or_else(|| invalid("modification time"))?;
+		let mode: Option<u32> = match fields & FIELD_MODE {
+			0 => None,
+			_ => Some(u32::from_le_bytes(read_bytes(4)?.try_into().unwrap()) & 0o7777),
+		};
+		let owner: Option<(u32, u32)> = match fields & FIELD_OWNER {
+			0 => None,
+			_ => {
+				let ids: Vec<u8> = read_bytes(8)?;
+				Some((
+					u32::from_le_bytes(ids[..4].try_into().unwrap()),
+					u32::from_le_bytes(ids[4..].try_into().unwrap()),
+				))
+			}
+		};
+		let length: Vec<u8> = read_bytes(2)?;
+		let name: Vec<u8> = read_bytes(u16::from_le_bytes([length[0], length[1]]) as usize)?;
+		// the name may be used as the output file, so it must not point anywhere else
+		let name: String = String::from_utf8(name)
+			.ok()
+			.filter(|name| {
+				name.is_empty()
+					|| (name != "." && name != ".." && !name.contains(['/', '\\', '\0']))
+			})
+			.ok_or_else(|| invalid("file name"))?;
+		Ok(Self {
+			name,
+			modified,
+			mode,
+			owner,
+		})
+	}
+
+	// the name, cut at a character boundary if it is too long to be recorded
+	fn name_bytes(&self) -> &[u8] {
+		let mut length: usize = self.name.len().min(u16::MAX as usize);
+		while !self.name.is_char_boundary(length) {
+			length -= 1;
+		}
+		&self.name.as_bytes()[..length]
+	}
+}
+
+#[cold]
+fn invalid(what: &str) -> AnyError {
+	FormatError::UnsupportedHeader(format!("invalid {}", what)).into()
+}
+
+#[cfg(test)]
+mod test {
+	use super::FileMetadata;
+	use crate::basic::{AnyError, AnyResult, FormatError};
+	use std::path::PathBuf;
+	use std::time::{Duration, SystemTime};
+	use std::{env, fs, process};
+
+	fn read_back(bytes: &[u8]) -> AnyResult<FileMetadata> {
+		let mut rest: &[u8] = bytes;
+		let metadata: FileMetadata = FileMetadata::read(|length: usize| {
+			let (field, next): (&[u8], &[u8]) = rest.split_at(length);
+			rest = next;
+			Ok(field.to_vec())
+		})?;
+		assert!(rest.is_empty());
+		Ok(metadata)
+	}
+
+	#[test]
+	fn test_metadata() -> AnyResult<()> {
+		// before the epoch too, with or without the unix fields
+		let metadata: FileMetadata = FileMetadata {
+			name: "notes.txt".to_string(),
+			modified: SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+			mode: Some(0o644),
+			owner: Some((1000, 100)),
+		};
+		let old: FileMetadata = FileMetadata {
+			name: String::new(),
+			modified: SystemTime::UNIX_EPOCH - Duration::new(1000, 250),
+			mode: None,
+			owner: None,
+		};
+		for recorded in [metadata.clone(), old] {
+			let mut bytes: Vec<u8> = Vec::new();
+			recorded.write(&mut bytes);
+			assert_eq!(read_back(&bytes)?, recorded);
+		}
+
+		// the recorded name can not point to another directory
+		for name in ["../notes.txt", "a/b", "..", "a\\b"] {
+			let mut bytes: Vec<u8> = Vec::new();
+			FileMetadata {
+				name: name.to_string(),
+				..metadata.clone()
+			}
+			.write(&mut bytes);
+			assert!(matches!(
+				read_back(&bytes),
+				Err(AnyError::Format(FormatError::UnsupportedHeader(_)))
+			));
+		}
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_apply() -> AnyResult<()> {
+		let root: PathBuf = env::temp_dir().join(format!("srx-test-apply-{}", process::id()));
+		let _error_ignored_ = fs::remove_dir_all(&root);
+		fs::create_dir_all(&root)?;
+		let path: PathBuf = root.join("file");
+		fs::write(&path, b"data")?;
+		let current: FileMetadata = FileMetadata::from_path(&path)?;
+
+		// the set-id bits recorded in an untrusted input are dropped, and the owner kept
+		let recorded: FileMetadata = FileMetadata {
+			modified: SystemTime::UNIX_EPOCH,
+			mode: Some(0o6755),
+			owner: Some((u32::MAX - 1, u32::MAX - 1)),
+			..current.clone()
+		};
+		recorded.apply(&path, false)?;
+		let restored: FileMetadata = FileMetadata::from_path(&path)?;
+		assert_eq!(restored.modified, SystemTime::UNIX_EPOCH);
+		assert_eq!(restored.mode, Some(0o755));
+		assert_eq!(restored.owner, current.owner);
+
+		// unless the owner is restored too, which only the super user can do
+		let own: FileMetadata = FileMetadata {
+			mode: Some(0o2750),
+			..current.clone()
+		};
+		own.apply(&path, true)?;
+		let restored: FileMetadata = FileMetadata::from_path(&path)?;
+		assert_eq!(restored.mode, own.mode);
+
+		// a read-only file still gets its time
+		let read_only: FileMetadata = FileMetadata {
+			mode: Some(0o444),
+			..recorded
+		};
+		read_only.apply(&path, false)?;
+		let restored: FileMetadata = FileMetadata::from_path(&path)?;
+		assert_eq!(restored.modified, read_only.modified);
+		assert_eq!(restored.mode, read_only.mode);
+		fs::remove_dir_all(&root)?;
+		Ok(())
+	}
+}
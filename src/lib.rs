@@ -0,0 +1,557 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! srx: The fast Symbol Ranking based compressor.
+//!
+//! The [`compress`] and [`decompress`] functions wrap the multi-threaded symbol ranking codec
+//! and take care of the `.srx` container framing, so any [`Read`]/[`Write`] pair can be used.
+//! When the data is produced or consumed incrementally, [`SrxWriter`] and [`SrxReader`] do the
+//! same work on the calling thread as a [`Write`]/[`Read`] adapter, and [`compress_slice`] and
+//! [`decompress_slice`] do it for small in-memory buffers without spawning any thread.
+//! Streams compressed with a block index can be read at any offset with [`SeekableSrxReader`].
+//! Whole directory trees are stored in a single solid archive by [`create_archive`] and restored
+//! by [`extract_archive`]. Small inputs sharing content compress better with a [`Dictionary`],
+//! which [`train_dictionary`] builds from a corpus of samples.
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! let reader: File = File::open("input.txt")?;
+//! let writer: File = File::create("input.txt.srx")?;
+//! srx::compress(reader, writer, &srx::Options::new())?;
+//! # Ok::<(), srx::AnyError>(())
+//! ```
+
+#![allow(
+	clippy::needless_return,
+	clippy::upper_case_acronyms,
+	clippy::module_inception
+)]
+
+use crate::archive::{scan_tree, TreeReader, TreeWriter};
+use crate::basic::Digest;
+use crate::bridged_context::{
+	MAX_PRIMARY_CONTEXT_BITS, MAX_PRIMARY_CONTEXT_ORDER, MIN_PRIMARY_CONTEXT_BITS,
+	PRIMARY_CONTEXT_BITS,
+};
+use crate::codec::{
+	decode, decode_slice, encode, encode_blocks, encode_slice, encode_slice_blocks, match_stats,
+	sample_options, train,
+};
+use crate::container::{check_end, find_catalog, read_member, write_catalog, Header};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+mod archive;
+mod basic;
+mod bridged_context;
+mod codec;
+mod container;
+mod mixing;
+mod primary_context;
+mod secondary_context;
+#[cfg(test)]
+mod test;
+
+pub use crate::basic::{AnyError, AnyResult, FormatError};
+pub use crate::codec::{MatchStats, SampleGain, SeekableSrxReader, SrxReader, SrxWriter};
+pub use crate::container::{ArchiveEntry, Dictionary, FileMetadata};
+pub use crate::secondary_context::CellType;
+
+// -----------------------------------------------
+
+const IO_BUFFER_SIZE: usize = 0x400000;
+const MESSAGE_BUFFER_SIZE: usize = 0x40000;
+const STREAM_BUFFER_SIZE: usize = 0x10000;
+
+// -----------------------------------------------
+
+/// The magic bytes at the start of the headerless `.srx` files written by srx 0.3, which can still
+/// be decompressed. They are the [`SRX_MAGIC`] followed by the format version 0.
+pub const SRX_HEADER: &[u8; 4] = b"sRx\x00";
+
+/// The magic bytes at the start of every `.srx` file, followed by the format version byte.
+pub const SRX_MAGIC: &[u8; 3] = b"sRx";
+
+// -----------------------------------------------
+
+/// Options for [`compress`] and [`decompress`].
+#[derive(Clone, Debug)]
+pub struct Options {
+	length: Option<u64>,
+	checksum: bool,
+	block_size: Option<u32>,
+	threads: usize,
+	seekable: bool,
+	metadata: Option<FileMetadata>,
+	dictionary: Option<Dictionary>,
+	memory_level: u8,
+	fit_memory: bool,
+	context_order: Option<u8>,
+	max: bool,
+	apm: bool,
+	adaptive: bool,
+	cell_type: CellType,
+	wide: bool,
+	fallback: bool,
+	long_match: bool,
+	tagged: bool,
+	exclusion: bool,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Options {
+	/// Create the default options: no recorded length, with checksum, as a single stream.
+	pub fn new() -> Self {
+		Self {
+			length: None,
+			checksum: true,
+			block_size: None,
+			threads: 0,
+			seekable: false,
+			metadata: None,
+			dictionary: None,
+			memory_level: PRIMARY_CONTEXT_BITS,
+			fit_memory: false,
+			context_order: None,
+			max: false,
+			apm: false,
+			adaptive: false,
+			cell_type: CellType::State,
+			wide: false,
+			fallback: false,
+			long_match: false,
+			tagged: false,
+			exclusion: false,
+		}
+	}
+
+	/// Record the uncompressed length in the header, so that decompression can verify it.
+	///
+	/// Compression fails if the input turns out to have a different length.
+	pub fn length(mut self, length: u64) -> Self {
+		self.length = Some(length);
+		self
+	}
+
+	/// Append a CRC-32C of the uncompressed data, so that decompression can verify it.
+	pub fn checksum(mut self, checksum: bool) -> Self {
+		self.checksum = checksum;
+		self
+	}
+
+	/// Split the input into independent blocks of `block_size` bytes, which [`compress`] codes in
+	/// parallel. Each block starts with an empty model, so small blocks compress worse.
+	///
+	/// Panics if `block_size` is zero.
+	pub fn block_size(mut self, block_size: u32) -> Self {
+		assert!(block_size > 0, "The block size must not be zero!");
+		self.block_size = Some(block_size);
+		self
+	}
+
+	/// Append a block index so that [`SeekableSrxReader`] can decompress any part of the data
+	/// without decompressing what comes before it. This implies block mode, with blocks of 8 MiB
+	/// unless another block size is set.
+	pub fn seekable(mut self, seekable: bool) -> Self {
+		self.seekable = seekable;
+		self
+	}
+
+	/// Record the name, modification time, permissions and owner of the original file in the
+	/// header, so that they can be restored with [`read_metadata`] and [`FileMetadata::apply`],
+	/// the owner only on request.
+	pub fn metadata(mut self, metadata: FileMetadata) -> Self {
+		self.metadata = Some(metadata);
+		self
+	}
+
+	/// Prime the model with a preset dictionary before compressing, which must be given again to
+	/// decompress. Every block starts from the primed model in block mode.
+	pub fn dictionary(mut self, dictionary: Dictionary) -> Self {
+		self.dictionary = Some(dictionary);
+		self
+	}
+
+	/// Use a primary context of 2^`level` histories of 4 bytes each, from 16 (256 KiB) to 28
+	/// (1 GiB), instead of 24 (64 MiB). The literals of the secondary context scale with it up to
+	/// level 24, so that the whole model takes about 2 MiB at level 16, 10 MiB more than the
+	/// primary context from level 24, and twice as much with 32-bit counter
+	/// [cells](Options::cell_type). The level is recorded in the header and decompression needs the
+	/// same amount of memory, per thread in block mode. Large inputs compress better with higher
+	/// levels, while small ones compress as well with less memory, which
+	/// [`fit_memory`](Options::fit_memory) picks from their length.
+	///
+	/// Panics if `level` is out of range.
+	pub fn memory_level(mut self, level: u8) -> Self {
+		assert!(
+			(MIN_PRIMARY_CONTEXT_BITS..=MAX_PRIMARY_CONTEXT_BITS).contains(&level),
+			"The memory level must be from 16 to 28!"
+		);
+		self.memory_level = level;
+		self
+	}
+
+	/// Lower the [`memory_level`](Options::memory_level) to leave 64 histories per byte of a
+	/// stream or block and of the dictionary, down to 16, so that a 1 KB input does not pay for
+	/// setting up 64 MiB. The level is fitted to the recorded [`length`](Options::length), so
+	/// this has no effect without it.
+	pub fn fit_memory(mut self, fit_memory: bool) -> Self {
+		self.fit_memory = fit_memory;
+		self
+	}
+
+	/// Rank the next byte in the context of exactly the last `order` bytes, from 1 to 8, instead
+	/// of a rolling hash in which older bytes gradually fade out. The order is recorded in the
+	/// header. The rolling hash usually suits text best, while binary data often compresses
+	/// better with a low order.
+	///
+	/// Panics if `order` is out of range.
+	pub fn context_order(mut self, order: u8) -> Self {
+		assert!(
+			(1..=MAX_PRIMARY_CONTEXT_ORDER).contains(&order),
+			"The context order must be from 1 to 8!"
+		);
+		self.context_order = Some(order);
+		self
+	}
+
+	/// Pick the model by compression level, from 1 (fast) to 3 (max), each being slower for a
+	/// better ratio on most data: 1 is the default symbol ranking model, 2 adds
+	/// [`wide`](Options::wide) histories and the [`exclusion`](Options::exclusion) of the
+	/// literals, and 3 also the [`max`](Options::max) level. Other options can still be added on
+	/// top of a level, or turned off after it.
+	///
+	/// Panics if `level` is out of range.
+	pub fn level(self, level: u8) -> Self {
+		assert!((1..=3).contains(&level), "The level must be from 1 to 3!");
+		self.wide(level >= 2).exclusion(level >= 2).max(level >= 3)
+	}
+
+	/// Compress at the max level, which mixes the prediction of the symbol ranking model with
+	/// order-1, order-2 and order-4 bit models. It compresses better but several times slower,
+	/// and always on a single thread per stream or block, with 33 MiB more memory whatever the
+	/// [`memory_level`](Options::memory_level). Decompression is as slow and as large, and
+	/// detects the level from the header.
+	pub fn max(mut self, max: bool) -> Self {
+		self.max = max;
+		self
+	}
+
+	/// Refine the predictions of the symbol ranking model with an adaptive probability map, which
+	/// learns how far they are off for the previous byte and match count, or for the bits of a
+	/// literal. It helps data that the fixed predictions of the model do not fit, at either level,
+	/// at the cost of some speed on both sides.
+	pub fn apm(mut self, apm: bool) -> Self {
+		self.apm = apm;
+		self
+	}
+
+	/// Learn the probability of a one for every bit history state of the symbol ranking model while
+	/// coding, instead of using the fixed prediction of the state. The states still follow the same
+	/// transitions. It works at either level and together with [`apm`](Options::apm), at the cost
+	/// of some speed on both sides.
+	pub fn adaptive(mut self, adaptive: bool) -> Self {
+		self.adaptive = adaptive;
+		self
+	}
+
+	/// Rank up to seven candidates for the next byte in every context instead of three, so that
+	/// fewer bytes are coded as literals when contexts are followed by four to seven different
+	/// bytes, as with low [`context_order`](Options::context_order)s. Their states also follow
+	/// how often the fourth candidate came up, and move a deeper one to the front with the
+	/// confidence of a byte seen once. The histories take 8 bytes each instead of 4, which doubles
+	/// the memory of the [`memory_level`](Options::memory_level) on both sides. It is part of
+	/// [`level`](Options::level) 2 and up, and decompression detects it from the header.
+	pub fn wide(mut self, wide: bool) -> Self {
+		self.wide = wide;
+		self
+	}
+
+	/// When the next byte is none of the candidates of its context, try the new candidates of the
+	/// last two bytes before coding a literal, and tell the model whether both contexts agree on
+	/// the first candidate. It helps most with a long [`context_order`](Options::context_order),
+	/// whose contexts are sparse, and a little with text, but the extra flags usually cost a little
+	/// on binary data. It takes 256 KiB more memory on both sides, and decompression detects it
+	/// from the header.
+	pub fn fallback(mut self, fallback: bool) -> Self {
+		self.fallback = fallback;
+		self
+	}
+
+	/// Find the last occurrence of the recent bytes in a window of the previous ones, as large as
+	/// the primary context has histories, and check every byte against the byte that followed it
+	/// before ranking it. Long repeats such as duplicated files or log blocks then cost almost
+	/// nothing, text compresses a little better, and data without long repeats a little worse.
+	/// It takes half as much memory again as the [`memory_level`](Options::memory_level), and
+	/// decompression detects it from the header.
+	pub fn long_match(mut self, long_match: bool) -> Self {
+		self.long_match = long_match;
+		self
+	}
+
+	/// Keep the histories of the symbol ranking model in buckets of three with an 8-bit check tag
+	/// for each, or 16-bit with [`wide`](Self::wide) histories, so that a context only takes over
+	/// the ranking of another one on a rare collision of both the hash and the tag.
+	/// A new context takes the slot of the least confident history of its bucket. The memory is
+	/// the same, which leaves room for three quarters of the histories, and decompression detects
+	/// it from the header. It helps most with a [`context_order`](Self::context_order) and a small
+	/// [`memory_level`](Self::memory_level), and costs a little speed and a little ratio on text
+	/// with the rolling hash.
+	pub fn tagged(mut self, tagged: bool) -> Self {
+		self.tagged = tagged;
+		self
+	}
+
+	/// Code the bytes that no model predicted with a mix of order-1 and order-2 contexts, which
+	/// know the bytes already ruled out by the ranks, the fallback context and the match model.
+	/// The bits only leading to those bytes are not coded at all, and the other bits are coded in
+	/// their own contexts while a ruled out byte is still possible, which learn to give it little
+	/// probability rather than none. It helps on most data, binary data with few repeats the most,
+	/// but makes compression up to twice as slow and takes 100 MiB more memory on both sides from
+	/// the [`memory_level`](Options::memory_level) 24, a sixteenth as much for every 4 levels
+	/// less, or twice as much with 32-bit counter [cells](Options::cell_type). Decompression
+	/// detects it from the header, which needs version 2 of the format.
+	pub fn exclusion(mut self, exclusion: bool) -> Self {
+		self.exclusion = exclusion;
+		self
+	}
+
+	/// Model each bit of the secondary context with cells of the given type instead of bit history
+	/// states, for example to compare them on a corpus. The type is recorded in the header. With
+	/// counters, [`adaptive`](Options::adaptive) is ignored.
+	///
+	/// Panics if the shift or the limit of the counters is out of range.
+	pub fn cell_type(mut self, cell_type: CellType) -> Self {
+		assert!(
+			cell_type.is_valid(),
+			"The counter shift must be from 1 to 15 and the limit from 1 to 1023!"
+		);
+		self.cell_type = cell_type;
+		self
+	}
+
+	/// The number of worker threads to compress or decompress in block mode, or zero (the default)
+	/// to use every core.
+	pub fn threads(mut self, threads: usize) -> Self {
+		self.threads = threads;
+		self
+	}
+}
+
+// -----------------------------------------------
+
+fn encode_stream<R: Read + Send, W: Write + Send>(
+	reader: R,
+	mut writer: W,
+	header: &Header,
+	threads: usize,
+) -> AnyResult<(R, W)> {
+	header.write(&mut writer)?;
+	let (reader, mut writer, digest): (R, W, Digest) = match header.block_size() {
+		None => encode::<R, W, IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE>(reader, writer, header)?,
+		Some(_) => encode_blocks(reader, writer, header, threads)?,
+	};
+	header.write_trailer(&mut writer, &digest)?;
+	Ok((reader, writer))
+}
+
+/// Compress everything from `reader` into `writer`, framed by the `.srx` header and trailer.
+///
+/// With a block size, the input is split into independent blocks coded on a pool of threads.
+///
+/// The reader and the writer are given back once the compressed stream is complete.
+pub fn compress<R: Read + Send, W: Write + Send>(
+	reader: R,
+	writer: W,
+	options: &Options,
+) -> AnyResult<(R, W)> {
+	encode_stream(reader, writer, &Header::new(options), options.threads)
+}
+
+/// Compress files and directory trees into a solid archive written to `writer`.
+///
+/// The data of every file goes through a single compressed stream, followed by a catalog of the
+/// archived paths with their sizes and offsets. Paths keep their normal components, so `dir/a`
+/// and `/dir/a` are both archived as `dir/a`, and directories are archived recursively in sorted
+/// order. Plain [`decompress`] gives the data of all the files one after another. The
+/// [`seekable`](Options::seekable) and [`metadata`](Options::metadata) options are ignored.
+///
+/// Symbolic links are not followed: finding one, given or in a directory, fails the archive.
+///
+/// The writer is given back together with the catalog once the archive is complete.
+pub fn create_archive<W: Write + Send>(
+	paths: &[&Path],
+	writer: W,
+	options: &Options,
+) -> AnyResult<(W, Vec<ArchiveEntry>)> {
+	let scanned: Vec<(PathBuf, ArchiveEntry)> = scan_tree(paths)?;
+	let length: u64 = scanned.iter().map(|(_, entry)| entry.length).sum();
+	let options: Options = Options {
+		seekable: false,
+		metadata: None,
+		..options.clone()
+	}
+	.length(length);
+	let header: Header = Header::new(&options).with_catalog();
+	let (_, mut writer): (TreeReader, W) =
+		encode_stream(TreeReader::new(&scanned), writer, &header, options.threads)?;
+	let entries: Vec<ArchiveEntry> = scanned.into_iter().map(|(_, entry)| entry).collect();
+	write_catalog(&mut writer, &entries)?;
+	Ok((writer, entries))
+}
+
+/// Extract an archive made by [`create_archive`] from `reader` into `directory`.
+///
+/// The catalog is read from the end of the input first, so the archive must be the last stream
+/// of it. Fails with [`FormatError::NotArchive`] if the stream holds a single file. Existing files
+/// are overwritten. The reader is given back together with the catalog once every file is
+/// extracted.
+pub fn extract_archive<R: Read + Seek + Send>(
+	mut reader: R,
+	directory: &Path,
+	options: &Options,
+) -> AnyResult<(R, Vec<ArchiveEntry>)> {
+	let base: u64 = reader.stream_position()?;
+	let header: Header = Header::read(&mut reader)?;
+	if !header.is_archive() {
+		return Err(FormatError::NotArchive.into());
+	}
+	let entries: Vec<ArchiveEntry> = find_catalog(&mut reader, &header, base)?;
+	reader.seek(SeekFrom::Start(base))?;
+	let (reader, writer): (R, TreeWriter) =
+		decompress(reader, TreeWriter::new(directory, entries.clone()), options)?;
+	writer.finish()?;
+	Ok((reader, entries))
+}
+
+/// Decompress a `.srx` stream from `reader` into `writer`.
+///
+/// Fails with a [`FormatError`] if the header is missing, has an unknown version or asks for
+/// unsupported parameters, if the input is truncated or goes on after the end of the stream, or
+/// if the decompressed data does not match the recorded length or checksum, or if the stream was
+/// compressed with a preset dictionary and the same one is not given. Like gzip, several
+/// streams concatenated together are decompressed one after another into the same output. Files
+/// compressed in block mode are decompressed on a pool of threads. The reader and the writer are
+/// given back once the decompressed data is complete.
+pub fn decompress<R: Read + Send, W: Write + Send>(
+	reader: R,
+	writer: W,
+	options: &Options,
+) -> AnyResult<(R, W)> {
+	decode::<R, W, IO_BUFFER_SIZE>(reader, writer, options.threads, options.dictionary.as_ref())
+}
+
+/// Read the header of a `.srx` stream from `reader` and return the metadata of the original file
+/// if it was recorded with [`Options::metadata`].
+///
+/// Only the header is read, so the reader has to be rewound before decompressing the stream.
+pub fn read_metadata<R: Read>(reader: &mut R) -> AnyResult<Option<FileMetadata>> {
+	Ok(Header::read(reader)?.metadata().cloned())
+}
+
+/// Compress an in-memory buffer on the calling thread.
+///
+/// The output is identical to what [`compress`] produces for the same input and options, without
+/// the cost of spawning the pipeline threads. Blocks are coded one after another. With the
+/// [`length`](Options::length) of the input and [`fit_memory`](Options::fit_memory), small
+/// buffers only cost a fraction of a millisecond per call, apart from the [`max`](Options::max)
+/// level.
+pub fn compress_slice(input: &[u8], options: &Options) -> AnyResult<Vec<u8>> {
+	let header: Header = Header::new(options);
+	let mut output: Vec<u8> = Vec::with_capacity(input.len() / 2 + 32);
+	header.write(&mut output)?;
+	let mut output: Vec<u8> = match header.block_size() {
+		None => encode_slice(input, &header, output)?,
+		Some(_) => encode_slice_blocks(input, &header, output)?,
+	};
+	let mut digest: Digest = header.digest();
+	digest.update_slice(input);
+	header.write_trailer(&mut output, &digest)?;
+	Ok(output)
+}
+
+/// Decompress an in-memory `.srx` buffer on the calling thread, including concatenated streams.
+pub fn decompress_slice(input: &[u8], options: &Options) -> AnyResult<Vec<u8>> {
+	let mut stream: &[u8] = input;
+	let mut header: Header = Header::read(&mut stream)?;
+	let capacity: u64 = header
+		.original_length()
+		.unwrap_or(0)
+		.min(input.len() as u64 * 256);
+	let mut output: Vec<u8> = Vec::with_capacity(capacity as usize);
+	loop {
+		header.attach_dictionary(options.dictionary.as_ref())?;
+		let start: usize = output.len();
+		let position: u64 = (input.len() - stream.len()) as u64;
+		let (mut trailer, decoded): (&[u8], Vec<u8>) =
+			decode_slice(stream, &header, position, output)?;
+		output = decoded;
+		let position: u64 = (input.len() - trailer.len()) as u64;
+		let mut digest: Digest = header.digest();
+		digest.update_slice(&output[start..]);
+		let position: u64 = header.read_trailer(&mut trailer, &digest, position)?;
+		if header.is_legacy() {
+			check_end(&mut trailer, position)?;
+			return Ok(output);
+		}
+		match read_member(&mut trailer, position)? {
+			None => return Ok(output),
+			Some(next_header) => header = next_header,
+		}
+		stream = trailer;
+	}
+}
+
+/// Build a preset dictionary of at most `size` bytes from a corpus of samples.
+///
+/// The symbol ranking model is run over every sample alone, at the memory level
+/// [fitted](Options::fit_memory) to the sample as by [`measure_dictionary`], and the dictionary is
+/// made of the segments of the samples holding the byte sequences that the model missed most,
+/// weighted by the number of other samples they appear in, so that priming the model with them
+/// turns the most literals and low ranked predictions of the other samples into first ranked hits.
+pub fn train_dictionary(samples: &[&[u8]], size: usize) -> AnyResult<Dictionary> {
+	train(samples, size)
+}
+
+/// Count the bytes of `data` predicted by each rank of the symbol ranking model with `options`,
+/// and the contexts that took the slot of another one when the histories are
+/// [`tagged`](Options::tagged).
+pub fn model_stats(data: &[u8], options: &Options) -> AnyResult<MatchStats> {
+	match_stats(data, &Header::new(options))
+}
+
+/// Measure how much `dictionary` helps to compress `sample`, by compressing it without and with
+/// the dictionary and counting the bytes predicted by each rank of the model both ways, with the
+/// length of the sample and its [fitted](Options::fit_memory) memory level.
+pub fn measure_dictionary(sample: &[u8], dictionary: &Dictionary) -> AnyResult<SampleGain> {
+	let options: Options = sample_options(sample);
+	let primed_options: Options = options.clone().dictionary(dictionary.clone());
+	Ok(SampleGain {
+		length: sample.len() as u64,
+		plain_size: compress_slice(sample, &options)?.len() as u64,
+		primed_size: compress_slice(sample, &primed_options)?.len() as u64,
+		plain: match_stats(sample, &Header::new(&options))?,
+		primed: match_stats(sample, &Header::new(&primed_options))?,
+	})
+}
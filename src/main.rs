@@ -16,60 +16,235 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::{AnyError, AnyResult};
-use crate::codec::{decode, encode};
+use srx::{
+	compress, create_archive, decompress, extract_archive, measure_dictionary, model_stats,
+	read_metadata, train_dictionary, AnyError, AnyResult, ArchiveEntry, CellType, Dictionary,
+	FileMetadata, MatchStats, Options, SampleGain, SeekableSrxReader,
+};
 use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::{Read, Seek, Write};
-use std::path::Path;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 use std::time::Instant;
 
-mod basic;
-mod bridged_context;
-mod codec;
-mod primary_context;
-mod secondary_context;
-
 // -----------------------------------------------
 
-const IO_BUFFER_SIZE: usize = 0x400000;
-const MESSAGE_BUFFER_SIZE: usize = 0x40000;
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Mode {
+	Compress,
+	Decompress,
+	Archive,
+	Extract,
+	Train,
+}
 
 // -----------------------------------------------
 
-const SRX_HEADER: &[u8; 4] = b"sRx\x00";
+// decompress only the given range of a seekable file
+fn extract(
+	reader: File,
+	mut writer: File,
+	options: &Options,
+	offset: u64,
+	length: u64,
+) -> AnyResult<(File, File)> {
+	let mut reader: SeekableSrxReader<File> = SeekableSrxReader::with_options(reader, options)?;
+	reader.seek(SeekFrom::Start(offset))?;
+	io::copy(&mut (&mut reader).take(length), &mut writer)?;
+	// report the whole file as the input
+	let mut reader: File = reader.into_inner();
+	reader.seek(SeekFrom::End(0))?;
+	Ok((reader, writer))
+}
 
-fn run(input_path: &Path, output_path: &Path, is_compress: bool) -> AnyResult<(u64, u64, f64)> {
+// with keep_metadata, the metadata of the file is recorded when compressing and restored when
+// decompressing, with the owner if same_owner, in which case the output path defaults to the
+// recorded name
+fn run(
+	input_path: &Path,
+	output_path: Option<&Path>,
+	is_compress: bool,
+	options: Options,
+	range: Option<(u64, u64)>,
+	keep_metadata: bool,
+	same_owner: bool,
+) -> AnyResult<(u64, u64)> {
 	// open file
 	let mut reader: File = File::open(input_path)?;
-	let mut writer: File = File::create(output_path)?;
-
-	// start the timer
-	let start: Instant = Instant::now();
+	let metadata: Option<FileMetadata> = match (keep_metadata, is_compress) {
+		(false, _) => None,
+		(true, true) => Some(FileMetadata::from_path(input_path)?),
+		(true, false) => {
+			let metadata: Option<FileMetadata> = read_metadata(&mut reader)?;
+			reader.rewind()?;
+			metadata
+		}
+	};
+	let output_path: PathBuf = match (output_path, &metadata) {
+		(Some(output_path), _) => output_path.to_path_buf(),
+		(None, Some(metadata)) if !metadata.name.is_empty() => {
+			input_path.with_file_name(&metadata.name)
+		}
+		(None, _) => return Err(AnyError::from_string("No file name recorded in the input!")),
+	};
+	if output_path == input_path {
+		return Err(AnyError::from_string(
+			"The output would overwrite the input!",
+		));
+	}
+	let writer: File = File::create(&output_path)?;
+	let options: Options = match (is_compress, &metadata) {
+		(false, _) => options,
+		(true, None) => options.length(reader.metadata()?.len()),
+		(true, Some(metadata)) => options
+			.length(reader.metadata()?.len())
+			.metadata(metadata.clone()),
+	};
 
 	// do the compression/decompression
 	let (mut done_reader, mut done_writer): (File, File) = if is_compress {
-		writer.write_all(SRX_HEADER)?;
-		encode::<File, File, IO_BUFFER_SIZE, MESSAGE_BUFFER_SIZE>(reader, writer)?
+		compress(reader, writer, &options)?
+	} else if let Some((offset, length)) = range {
+		extract(reader, writer, &options, offset, length)?
 	} else {
-		let mut buffer: [u8; 4] = [0; 4];
-		reader.read_exact(&mut buffer)?;
-		if !buffer.eq(SRX_HEADER) {
-			return Err(AnyError::from_string("Not a SRX compressed file!"));
-		}
-		decode::<File, File, IO_BUFFER_SIZE>(reader, writer)?
+		decompress(reader, writer, &options)?
 	};
 
-	// stop the timer and calculate the duration in seconds
-	let duration: f64 = start.elapsed().as_millis() as f64 / 1000.0;
-
 	// get the input and output size
 	let input_size: u64 = done_reader.stream_position()?;
 	let output_size: u64 = done_writer.stream_position()?;
 
+	// restore the metadata once the output is closed
+	drop(done_writer);
+	if let (false, Some(metadata)) = (is_compress, &metadata) {
+		metadata.apply(&output_path, same_owner)?;
+	}
+
 	// oke
-	Ok((input_size, output_size, duration))
+	Ok((input_size, output_size))
+}
+
+fn total_length(entries: &[ArchiveEntry]) -> u64 {
+	entries.iter().map(|entry| entry.length).sum()
+}
+
+// the sizes are those of the original files and of the archive
+fn run_archive(
+	archive_path: &Path,
+	paths: &[&Path],
+	is_archive: bool,
+	options: Options,
+) -> AnyResult<(u64, u64)> {
+	if is_archive {
+		let writer: File = File::create(archive_path)?;
+		let (mut done_writer, entries): (File, Vec<ArchiveEntry>) =
+			create_archive(paths, writer, &options)?;
+		Ok((total_length(&entries), done_writer.stream_position()?))
+	} else {
+		let reader: File = File::open(archive_path)?;
+		let directory: &Path = paths.first().copied().unwrap_or(Path::new("."));
+		let (mut done_reader, entries): (File, Vec<ArchiveEntry>) =
+			extract_archive(reader, directory, &options)?;
+		Ok((done_reader.stream_position()?, total_length(&entries)))
+	}
+}
+
+fn hit_rates(stats: &MatchStats) -> String {
+	let total: f64 = stats.total().max(1) as f64;
+	format!(
+		"{:.1}/{:.1}/{:.1}%",
+		stats.first as f64 / total * 100.0,
+		stats.second as f64 / total * 100.0,
+		stats.third as f64 / total * 100.0
+	)
+}
+
+// report how the model predicted the input file, and how often its contexts collided
+fn report_stats(path: &Path, options: Options, dictionary_path: Option<&Path>) -> AnyResult<()> {
+	let data: Vec<u8> = fs::read(path)?;
+	let stats: MatchStats = model_stats(&data, &with_dictionary(options, dictionary_path)?)?;
+	println!(
+		"FIRST/SECOND/THIRD hits {}, {} literals, {} evictions ({:.2}%)",
+		hit_rates(&stats),
+		stats.missed,
+		stats.evictions,
+		stats.evictions as f64 / stats.total().max(1) as f64 * 100.0
+	);
+	Ok(())
+}
+
+// train a dictionary on the samples and report how much it helps each of them, the sizes are those
+// of the samples and of the dictionary
+fn run_train(
+	dictionary_path: &Path,
+	paths: &[&Path],
+	size: usize,
+	held_out: bool,
+) -> AnyResult<(u64, u64)> {
+	let samples: Vec<Vec<u8>> = paths.iter().map(fs::read).collect::<io::Result<_>>()?;
+	let slices: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+	let dictionary: Dictionary = train_dictionary(&slices, size)?;
+	fs::write(dictionary_path, dictionary.as_bytes())?;
+	// with held out samples, each one is measured with a dictionary trained on the other ones,
+	// which trains once more per sample, unless it is alone
+	let held_out: bool = held_out && slices.len() > 1;
+	println!(
+		"sample: length -> compressed -> with dictionary ({}), FIRST/SECOND/THIRD hits",
+		match held_out {
+			true => "trained without the sample",
+			false => "in-sample",
+		}
+	);
+	for (index, (path, sample)) in paths.iter().zip(&slices).enumerate() {
+		let gain: SampleGain = match held_out {
+			true => {
+				let others: Vec<&[u8]> = [&slices[..index], &slices[index + 1..]].concat();
+				measure_dictionary(sample, &train_dictionary(&others, size)?)?
+			}
+			false => measure_dictionary(sample, &dictionary)?,
+		};
+		println!(
+			"{}: {} -> {} -> {} bytes ({:.2}% gain), hits {} -> {}",
+			path.display(),
+			gain.length,
+			gain.plain_size,
+			gain.primed_size,
+			(1.0 - gain.primed_size as f64 / gain.plain_size as f64) * 100.0,
+			hit_rates(&gain.plain),
+			hit_rates(&gain.primed)
+		);
+	}
+	let length: u64 = slices.iter().map(|sample| sample.len() as u64).sum();
+	Ok((length, dictionary.as_bytes().len() as u64))
+}
+
+fn with_dictionary(options: Options, path: Option<&Path>) -> AnyResult<Options> {
+	match path {
+		None => Ok(options),
+		Some(path) => Ok(options.dictionary(Dictionary::new(fs::read(path)?))),
+	}
+}
+
+fn parse_value<T: FromStr>(value: Option<&String>) -> Option<T> {
+	value.and_then(|value| value.parse::<T>().ok())
+}
+
+fn parse_cells(value: Option<&String>) -> Option<CellType> {
+	let cell_type: CellType = match value?.split_once(':') {
+		None if value? == "state" => CellType::State,
+		Some(("c16", shift)) => CellType::Counter16 {
+			shift: shift.parse().ok()?,
+		},
+		Some(("c32", limit)) => CellType::Counter32 {
+			limit: limit.parse().ok()?,
+		},
+		_ => return None,
+	};
+	cell_type.is_valid().then_some(cell_type)
 }
 
 fn help() -> ! {
@@ -77,8 +252,55 @@ fn help() -> ! {
 		"\
 		srx: The fast Symbol Ranking based compressor, version {}.\n\
 		Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)\n\n\
-		To   compress: srx c <input-file> <output-file>\n\
-		To decompress: srx d <input-file> <output-file>",
+		To   compress: srx c [options] <input-file> <output-file>\n\
+		To decompress: srx d [options] <input-file> [<output-file>]\n\
+		To    archive: srx a [options] <archive-file> <file-or-directory>...\n\
+		To    extract: srx x [options] <archive-file> [<directory>]\n\
+		To      train: srx train [options] -o <dictionary-file> <sample-file>...\n\n\
+		Options:\n  \
+		--no-checksum       do not store a checksum of the original data\n  \
+		-1, -2, -3          compression level: fast (default), wide with exclusion\n                      \
+		(about half as fast), or max with both (about 4x slower)\n  \
+		-m <level>          use 2^level primary context entries of 4 bytes, from 16 to\n                      \
+		28 (default: 24, 74 MiB in all), needed again to\n                      \
+		decompress\n  \
+		--fit-memory        lower the memory level to the size of the input, down\n                      \
+		to 16 for files under 1 KiB (c, a)\n  \
+		--order <bytes>     rank in the context of the last 1 to 8 bytes instead of a\n                      \
+		rolling hash\n  \
+		--max               mix order-1, order-2 and order-4 models into every bit,\n                      \
+		several times slower for a better ratio, with 33 MiB\n                      \
+		more memory\n  \
+		--apm               refine the predictions with an adaptive probability map\n  \
+		--adaptive          learn the prediction of every bit history state\n  \
+		--wide              rank 7 candidates per context instead of 3, with twice the\n                      \
+		memory of the level\n  \
+		--fallback          rank the misses again in the context of the last 2 bytes\n  \
+		--match             check every byte against the last match of the recent\n                      \
+		bytes first, with half the memory of the level again\n  \
+		--tagged            keep the primary context entries in buckets of 3 with a\n                      \
+		check tag each, so that contexts do not share entries\n  \
+		--exclusion         code the literals in order-1 and order-2 contexts without\n                      \
+		the bytes already ruled out, up to twice as slow, with\n                      \
+		100 MiB more memory at level 24\n  \
+		--cells <type>      model the bits with state (default), c16:<shift> for\n                      \
+		16-bit counters (shift 1 to 15) or c32:<limit> for 32-bit\n                      \
+		counters (limit 1 to 1023)\n  \
+		--block-size <MiB>  compress independent blocks of this size in parallel\n  \
+		--seekable          append a block index for random access (implies blocks)\n  \
+		--metadata          keep the file name, time, permissions and owner (c),\n                      \
+		restore them but the owner and the set-id bits, by default\n                      \
+		under the kept name (d)\n  \
+		--same-owner        restore the owner and the set-id bits too (d)\n  \
+		--dictionary <file> prime the model with a preset dictionary, which is needed\n                      \
+		again to decompress\n  \
+		--size <KiB>        maximum size of the trained dictionary (default: 64)\n  \
+		--held-out          report each sample with a dictionary trained without it,\n                      \
+		which trains once more per sample\n  \
+		--threads <count>   number of threads in block mode (default: all cores)\n  \
+		--offset <bytes>    decompress from this offset of a seekable file\n  \
+		--length <bytes>    decompress at most this many bytes of a seekable file\n  \
+		--stats             report the hit rates and the evictions of the model (c)",
 		env!("CARGO_PKG_VERSION")
 	);
 	exit(0);
@@ -88,20 +310,156 @@ fn main() {
 	let args: Vec<String> = env::args().collect();
 
 	// check and parse arguments
-	if args.len() != 4 {
+	if args.len() < 3 {
 		help()
 	}
-	let is_compress: bool = match args[1].as_str() {
-		"c" => true,
-		"d" => false,
+	let mode: Mode = match args[1].as_str() {
+		"c" => Mode::Compress,
+		"d" => Mode::Decompress,
+		"a" => Mode::Archive,
+		"x" => Mode::Extract,
+		"train" => Mode::Train,
 		_ => help(),
 	};
-	let input_path: &Path = Path::new(&args[2]);
-	let output_path: &Path = Path::new(&args[3]);
+	let mut options: Options = Options::new();
+	let mut offset: Option<u64> = None;
+	let mut length: Option<u64> = None;
+	let mut keep_metadata: bool = false;
+	let mut same_owner: bool = false;
+	let mut show_stats: bool = false;
+	let mut dictionary_path: Option<&Path> = None;
+	let mut train_output: Option<&Path> = None;
+	let mut train_size: Option<usize> = None;
+	let mut train_held_out: bool = false;
+	let mut paths: Vec<&Path> = Vec::new();
+	let mut arg_iter = args[2..].iter();
+	while let Some(arg) = arg_iter.next() {
+		match arg.as_str() {
+			"--no-checksum" => options = options.checksum(false),
+			"-m" => match parse_value::<u8>(arg_iter.next()) {
+				Some(level @ 16..=28) => options = options.memory_level(level),
+				_ => help(),
+			},
+			"--fit-memory" => options = options.fit_memory(true),
+			"--order" => match parse_value::<u8>(arg_iter.next()) {
+				Some(order @ 1..=8) => options = options.context_order(order),
+				_ => help(),
+			},
+			"-1" => options = options.level(1),
+			"-2" => options = options.level(2),
+			"-3" => options = options.level(3),
+			"--max" => options = options.max(true),
+			"--apm" => options = options.apm(true),
+			"--adaptive" => options = options.adaptive(true),
+			"--wide" => options = options.wide(true),
+			"--fallback" => options = options.fallback(true),
+			"--match" => options = options.long_match(true),
+			"--tagged" => options = options.tagged(true),
+			"--exclusion" => options = options.exclusion(true),
+			"--cells" => match parse_cells(arg_iter.next()) {
+				Some(cell_type) => options = options.cell_type(cell_type),
+				None => help(),
+			},
+			"--block-size" => match parse_value::<u32>(arg_iter.next()) {
+				Some(mebibytes @ 1..=4095) => options = options.block_size(mebibytes << 20),
+				_ => help(),
+			},
+			"--seekable" => options = options.seekable(true),
+			"--metadata" => keep_metadata = true,
+			"--same-owner" => same_owner = true,
+			"--stats" => show_stats = true,
+			"--dictionary" => match arg_iter.next() {
+				Some(path) => dictionary_path = Some(Path::new(path)),
+				None => help(),
+			},
+			"-o" => match arg_iter.next() {
+				Some(path) => train_output = Some(Path::new(path)),
+				None => help(),
+			},
+			"--size" => match parse_value::<usize>(arg_iter.next()) {
+				Some(kibibytes @ 1..=0x100000) => train_size = Some(kibibytes << 10),
+				_ => help(),
+			},
+			"--held-out" => train_held_out = true,
+			"--threads" => match parse_value::<usize>(arg_iter.next()) {
+				Some(threads) => options = options.threads(threads),
+				None => help(),
+			},
+			"--offset" => match parse_value::<u64>(arg_iter.next()) {
+				Some(value) => offset = Some(value),
+				None => help(),
+			},
+			"--length" => match parse_value::<u64>(arg_iter.next()) {
+				Some(value) => length = Some(value),
+				None => help(),
+			},
+			_ if arg.starts_with("--") => help(),
+			_ => paths.push(Path::new(arg)),
+		}
+	}
+	let path_count_ok: bool = match mode {
+		Mode::Compress => paths.len() == 2,
+		Mode::Decompress => paths.len() == 2 || (keep_metadata && paths.len() == 1),
+		Mode::Archive => paths.len() >= 2,
+		Mode::Extract => paths.len() == 1 || paths.len() == 2,
+		Mode::Train => !paths.is_empty() && train_output.is_some(),
+	};
+	if !path_count_ok {
+		help()
+	}
+	if (train_output.is_some() || train_size.is_some() || train_held_out) != (mode == Mode::Train)
+		|| (mode == Mode::Train && dictionary_path.is_some())
+	{
+		help()
+	}
+	if keep_metadata && !matches!(mode, Mode::Compress | Mode::Decompress) {
+		help()
+	}
+	if same_owner && !(keep_metadata && mode == Mode::Decompress) {
+		help()
+	}
+	if show_stats && mode != Mode::Compress {
+		help()
+	}
+	let range: Option<(u64, u64)> = match (offset, length) {
+		(None, None) => None,
+		_ if mode != Mode::Decompress => help(),
+		(offset, length) => Some((offset.unwrap_or(0), length.unwrap_or(u64::MAX))),
+	};
+	let is_compress: bool = matches!(mode, Mode::Compress | Mode::Archive | Mode::Train);
+
+	// start the timer
+	let start: Instant = Instant::now();
 
 	// run the compression
-	match run(input_path, output_path, is_compress) {
-		Ok((input_size, output_size, duration)) => {
+	let stats_options: Option<Options> = show_stats.then(|| options.clone());
+	let result: AnyResult<(u64, u64)> =
+		with_dictionary(options, dictionary_path).and_then(|options: Options| match mode {
+			Mode::Compress | Mode::Decompress => run(
+				paths[0],
+				paths.get(1).copied(),
+				is_compress,
+				options,
+				range,
+				keep_metadata,
+				same_owner,
+			),
+			Mode::Archive | Mode::Extract => {
+				run_archive(paths[0], &paths[1..], is_compress, options)
+			}
+			Mode::Train => run_train(
+				train_output.unwrap(),
+				&paths,
+				train_size.unwrap_or(64 << 10),
+				train_held_out,
+			),
+		});
+
+	// stop the timer and calculate the duration in seconds
+	let duration: f64 = start.elapsed().as_millis() as f64 / 1000.0;
+
+	match result {
+		Ok((input_size, output_size)) => {
 			// calculating and report
 			let (percentage, speed) = if is_compress {
 				(
@@ -114,10 +472,22 @@ fn main() {
 					output_size as f64 / duration / (1 << 20) as f64,
 				)
 			};
-			println!(
-				"{} -> {} ({:.2}%) in {:.2} seconds ({:.2} MiB/s)",
-				input_size, output_size, percentage, duration, speed
-			);
+			match mode {
+				Mode::Train => println!(
+					"{} bytes of samples -> {} bytes of dictionary in {:.2} seconds",
+					input_size, output_size, duration
+				),
+				_ => println!(
+					"{} -> {} ({:.2}%) in {:.2} seconds ({:.2} MiB/s)",
+					input_size, output_size, percentage, duration, speed
+				),
+			}
+			if let Some(options) = stats_options {
+				if let Err(error) = report_stats(paths[0], options, dictionary_path) {
+					println!("Error occurred! {}", error);
+					exit(1);
+				}
+			}
 		}
 		Err(error) => {
 			// something unexpected happened
@@ -0,0 +1,93 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::mixer::{squash, stretch};
+use crate::secondary_context::Bit;
+
+// -----------------------------------------------
+
+// the rate of the updates, as a shift of the error
+const RATE: u32 = 6;
+
+// an adaptive probability map, which learns for each small context the actual probability of a one
+// given the prediction, from 33 buckets of the stretched prediction interpolated together
+pub struct Apm {
+	table: Box<[u16]>,
+	// the bucket nearest to the last prediction, the one that is updated
+	index: usize,
+}
+
+impl Apm {
+	pub fn new(contexts: usize) -> Self {
+		let buckets: Vec<u16> = (0..33)
+			.map(|bucket: i32| (squash((bucket - 16) * 128) * 16) as u16)
+			.collect();
+		Self {
+			table: buckets.repeat(contexts).into_boxed_slice(),
+			index: 0,
+		}
+	}
+
+	// the refined prediction of a one, a quarter of the given one and three quarters of the learned
+	// one, in the same scale as the secondary context
+	#[inline(always)]
+	pub fn refine(&mut self, prediction: u32, context: usize) -> u32 {
+		let position: i32 = stretch((prediction >> 20).clamp(1, 4095)) + 2048;
+		let weight: u32 = (position & 127) as u32;
+		let index: usize = context * 33 + (position >> 7) as usize;
+		self.index = index + (weight >> 6) as usize;
+		let learned: u32 = (self.table[index] as u32 * (128 - weight)
+			+ self.table[index + 1] as u32 * weight)
+			>> 7;
+		(prediction >> 2) + learned * (3 << 14)
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, bit: Bit) {
+		let target: i32 = (u32::from(bit) as i32) << 16;
+		let value: i32 = self.table[self.index] as i32;
+		self.table[self.index] = (value + ((target - value) >> RATE)).clamp(64, 65535 - 64) as u16;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Apm;
+	use crate::secondary_context::Bit;
+
+	#[test]
+	fn test_refine() {
+		// the map starts from the given prediction
+		let mut apm: Apm = Apm::new(2);
+		let (half, low): (u32, u32) = (1 << 31, 1 << 27);
+		let initial: u32 = apm.refine(half, 0);
+		assert!(initial.abs_diff(half) < half >> 8);
+		assert!(apm.refine(low, 0).abs_diff(low) < low >> 4);
+
+		// and converges to the actual probability of a one in the context of the prediction, which
+		// makes three quarters of the refined one
+		for _ in 0..1000 {
+			apm.refine(half, 0);
+			apm.update(Bit::from(1));
+		}
+		assert!(apm.refine(half, 0) > 0xDE00_0000);
+		// while the buckets of other predictions and the other contexts keep theirs
+		assert!(apm.refine(low, 0).abs_diff(low) < low >> 4);
+		assert_eq!(apm.refine(half, 1), initial);
+	}
+}
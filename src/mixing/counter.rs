@@ -0,0 +1,67 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::reciprocals;
+
+// -----------------------------------------------
+
+// the reciprocals make a counter learn fast at first and then settle to a moving average over
+// the last LIMIT bits
+const LIMIT: u32 = 60;
+
+static RECIPROCALS: [u32; LIMIT as usize + 1] = reciprocals();
+
+// a table of adaptive probabilities of a one, each a u32 holding the probability in 16 bits and
+// the number of updates in the low bits; the probability is stored xor 0x8000 so that the table
+// is allocated as lazily zeroed memory with every probability at one half
+pub struct CounterTable {
+	counters: Box<[u32]>,
+	mask: usize,
+}
+
+impl CounterTable {
+	pub fn new(bits: u8) -> Self {
+		Self {
+			counters: vec![0; 1 << bits].into_boxed_slice(),
+			mask: (1 << bits) - 1,
+		}
+	}
+
+	// the slot of a hashed context
+	#[inline(always)]
+	pub fn slot(&self, hash: usize) -> usize {
+		hash & self.mask
+	}
+
+	// the 12 bits probability of a one in the slot
+	#[inline(always)]
+	pub fn probability(&self, slot: usize) -> u32 {
+		((self.counters[slot] >> 16) ^ 0x8000) >> 4
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, slot: usize, bit: bool) {
+		let counter: u32 = self.counters[slot];
+		let count: u32 = counter & 0xFFFF;
+		let probability: i64 = ((counter >> 16) ^ 0x8000) as i64;
+		let target: i64 = if bit { 65535 } else { 0 };
+		let probability: i64 =
+			probability + (((target - probability) * RECIPROCALS[count as usize] as i64) >> 16);
+		self.counters[slot] = (((probability as u32) ^ 0x8000) << 16) | (count + 1).min(LIMIT);
+	}
+}
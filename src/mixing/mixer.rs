@@ -0,0 +1,158 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// -----------------------------------------------
+
+// the logistic domain is the log odds scaled by 256 within -2047..=2047, and probabilities are
+// 12 bits, the same integer tables being used on both sides so that they always agree
+const SQUASH_POINTS: [i32; 33] = [
+	1, 2, 3, 6, 10, 16, 27, 45, 73, 120, 194, 310, 488, 747, 1101, 1546, 2047, 2549, 2994, 3348,
+	3607, 3785, 3901, 3975, 4024, 4050, 4068, 4079, 4085, 4089, 4092, 4093, 4094,
+];
+
+// the probability of the given log odds, interpolated between the points
+pub const fn squash(value: i32) -> i32 {
+	if value > 2047 {
+		return 4095;
+	}
+	if value < -2047 {
+		return 1;
+	}
+	let weight: i32 = value & 127;
+	let index: usize = ((value >> 7) + 16) as usize;
+	(SQUASH_POINTS[index] * (128 - weight) + SQUASH_POINTS[index + 1] * weight + 64) >> 7
+}
+
+const fn build_stretch() -> [i16; 4096] {
+	let mut table: [i16; 4096] = [0; 4096];
+	let mut next: usize = 0;
+	let mut value: i32 = -2047;
+	while value <= 2047 {
+		let probability: usize = squash(value) as usize;
+		while next <= probability {
+			table[next] = value as i16;
+			next += 1;
+		}
+		value += 1;
+	}
+	while next < 4096 {
+		table[next] = 2047;
+		next += 1;
+	}
+	table
+}
+
+// the inverse of squash
+static STRETCH: [i16; 4096] = build_stretch();
+
+#[inline(always)]
+pub fn stretch(probability: u32) -> i32 {
+	STRETCH[probability as usize] as i32
+}
+
+// -----------------------------------------------
+
+pub const INPUTS: usize = 5;
+
+// the rate of the updates, as a shift of the product of the input and the error
+const RATE: u32 = 12;
+
+// the bound of the weights, 256 times the full weight, so that a long run of skewed inputs can not
+// grow them without limit
+const MAX_WEIGHT: i32 = 1 << 24;
+
+// a set of weights for each kind of coded bit, the first input starting with the full weight so
+// that the mixer first gives the prediction of the secondary context
+pub struct Mixer<const SETS: usize> {
+	weights: Box<[[i32; INPUTS]]>,
+	inputs: [i32; INPUTS],
+	set: usize,
+	probability: i32,
+}
+
+impl<const SETS: usize> Mixer<SETS> {
+	pub fn new() -> Self {
+		let mut initial: [i32; INPUTS] = [0; INPUTS];
+		initial[0] = 1 << 16;
+		Self {
+			weights: vec![initial; SETS].into_boxed_slice(),
+			inputs: [0; INPUTS],
+			set: 0,
+			probability: 2048,
+		}
+	}
+
+	// mix the inputs in the logistic domain with the weights of the set, return a 12 bits
+	// probability of a one
+	#[inline(always)]
+	pub fn mix(&mut self, inputs: [i32; INPUTS], set: usize) -> u32 {
+		self.inputs = inputs;
+		self.set = set;
+		let weights: &[i32; INPUTS] = &self.weights[set];
+		let dot: i64 = (0..INPUTS)
+			.map(|index: usize| inputs[index] as i64 * weights[index] as i64)
+			.sum();
+		self.probability = squash((dot >> 16).clamp(-2047, 2047) as i32);
+		self.probability as u32
+	}
+
+	// move the weights of the last mix along the gradient of the coding cost
+	#[inline(always)]
+	pub fn update(&mut self, bit: bool) {
+		let error: i32 = ((bit as i32) << 12) - self.probability;
+		let weights: &mut [i32; INPUTS] = &mut self.weights[self.set];
+		for (weight, input) in weights.iter_mut().zip(self.inputs) {
+			*weight = (*weight + ((input * error) >> RATE)).clamp(-MAX_WEIGHT, MAX_WEIGHT);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Mixer, INPUTS, MAX_WEIGHT};
+
+	#[test]
+	fn test_weight_bound() {
+		// confident inputs that are always wrong keep pushing the weights, even once the mix
+		// saturates, up to the bound
+		let mut mixer: Mixer<1> = Mixer::new();
+		mixer.weights[0] = [-MAX_WEIGHT + 100, -MAX_WEIGHT + 100, 0, 0, 0];
+		let inputs: [i32; INPUTS] = [2047, 1000, 0, 0, 0];
+		for _ in 0..1000 {
+			assert_eq!(mixer.mix(inputs, 0), 1);
+			mixer.update(false);
+		}
+		assert_eq!(mixer.weights[0], [-MAX_WEIGHT, -MAX_WEIGHT, 0, 0, 0]);
+	}
+
+	#[test]
+	fn test_learning() {
+		// the second input tells the bit while the first one does not, so the mix learns to follow
+		// the second one
+		let mut mixer: Mixer<1> = Mixer::new();
+		for round in 0..2000 {
+			let bit: bool = round % 3 == 0;
+			let input: i32 = if bit { 1000 } else { -1000 };
+			mixer.mix([0, input, 0, 0, 0], 0);
+			mixer.update(bit);
+		}
+		assert!(mixer.weights[0][1] > 1 << 16);
+		assert!(mixer.mix([0, 1000, 0, 0, 0], 0) > 3900);
+		assert!(mixer.mix([0, -1000, 0, 0, 0], 0) < 200);
+	}
+}
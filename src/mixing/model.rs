@@ -0,0 +1,154 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::counter::CounterTable;
+use super::mixer::{stretch, Mixer, INPUTS};
+use crate::basic::Byte;
+use crate::secondary_context::Bit;
+
+// -----------------------------------------------
+
+// the kind of a coded bit, the flags being coded with the byte they stand for and the literals
+// with their node in the binary tree of the secondary context (1 to 255)
+#[derive(Copy, Clone)]
+pub enum BitKind {
+	First = 0,
+	Second = 1,
+	Third = 2,
+	Literal = 3,
+}
+
+const ORDER_1_BITS: u8 = 18;
+const ORDER_2_BITS: u8 = 22;
+const ORDER_4_BITS: u8 = 22;
+
+// first, second, third, then one set per bit of the literals
+const MIXER_SETS: usize = 3 + 8;
+
+// -----------------------------------------------
+
+// the context-mixing model of the max level, which refines the prediction of the secondary context
+// for each coded bit with order-1, order-2 and order-4 bit models
+pub struct MixingModel {
+	order_1: CounterTable,
+	order_2: CounterTable,
+	order_4: CounterTable,
+	mixer: Mixer<MIXER_SETS>,
+	// the last 4 coded bytes, the latest in the low bits
+	history: u32,
+	// the hashes of the contexts of the next byte, each leaving room for 1024 bit ids
+	order_2_base: usize,
+	order_4_base: usize,
+	// the slots of the current bit
+	slots: [usize; 3],
+}
+
+impl MixingModel {
+	pub fn new() -> Self {
+		Self {
+			order_1: CounterTable::new(ORDER_1_BITS),
+			order_2: CounterTable::new(ORDER_2_BITS),
+			order_4: CounterTable::new(ORDER_4_BITS),
+			mixer: Mixer::new(),
+			history: 0,
+			order_2_base: 0,
+			order_4_base: 0,
+			slots: [0; 3],
+		}
+	}
+
+	// the mixed prediction of a one, in the same scale as the secondary context
+	#[inline(always)]
+	pub fn predict(&mut self, prediction: u32, kind: BitKind, symbol: usize) -> u32 {
+		let bit_id: usize = ((kind as usize) << 8) | symbol;
+		self.slots = [
+			self.order_1
+				.slot(((self.history as usize & 0xFF) << 10) | bit_id),
+			self.order_2.slot(self.order_2_base + bit_id),
+			self.order_4.slot(self.order_4_base + bit_id),
+		];
+		let set: usize = match kind {
+			BitKind::Literal if symbol < 16 => 3 + depth(symbol),
+			BitKind::Literal => 7 + depth((symbol - 1) % 15 + 1),
+			_ => kind as usize,
+		};
+		let inputs: [i32; INPUTS] = [
+			stretch((prediction >> 20).clamp(1, 4095)),
+			stretch(self.order_1.probability(self.slots[0])),
+			stretch(self.order_2.probability(self.slots[1])),
+			stretch(self.order_4.probability(self.slots[2])),
+			256,
+		];
+		self.mixer.mix(inputs, set).clamp(1, 4095) << 20
+	}
+
+	// learn the bit of the last prediction
+	#[inline(always)]
+	pub fn update(&mut self, bit: Bit) {
+		let bit: bool = bit.into();
+		self.order_1.update(self.slots[0], bit);
+		self.order_2.update(self.slots[1], bit);
+		self.order_4.update(self.slots[2], bit);
+		self.mixer.update(bit);
+	}
+
+	// move the contexts to the next byte
+	#[inline(always)]
+	pub fn push(&mut self, byte: Byte) {
+		self.history = (self.history << 8) | u32::from(u8::from(byte));
+		self.order_2_base = hash(self.history & 0xFFFF, 2) << 10;
+		self.order_4_base = hash(self.history, 4) << 10;
+	}
+}
+
+// the depth of a node in a binary tree of 4 levels, from 0 for the root
+#[inline(always)]
+fn depth(node: usize) -> usize {
+	(usize::BITS - 1 - node.leading_zeros()) as usize
+}
+
+#[inline(always)]
+fn hash(context: u32, order: u32) -> usize {
+	((context as u64 | (order as u64) << 32).wrapping_mul(0x9E3779B97F4A7C15) >> 40) as usize
+}
+
+#[cfg(test)]
+mod test {
+	use super::{BitKind, MixingModel};
+	use crate::basic::Byte;
+	use crate::secondary_context::Bit;
+
+	#[test]
+	fn test_bit_models() {
+		// a literal bit that is always a one after the same byte, which the secondary context is
+		// unsure of, starts from its prediction and ends up predicted by the bit models
+		let mut model: MixingModel = MixingModel::new();
+		let unsure: u32 = 2048 << 20;
+		model.push(Byte::from(b'q'));
+		let initial: u32 = model.predict(unsure, BitKind::Literal, 1);
+		assert!((initial >> 20).abs_diff(2048) < 8);
+		for _ in 0..200 {
+			model.update(Bit::from(1));
+			model.push(Byte::from(b'q'));
+			model.predict(unsure, BitKind::Literal, 1);
+		}
+		assert!(model.predict(unsure, BitKind::Literal, 1) > 3900 << 20);
+		// while the other bits have learned nothing
+		assert_eq!(model.predict(unsure, BitKind::First, 1), initial);
+	}
+}
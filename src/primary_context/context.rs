@@ -16,56 +16,396 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::{Buffer, Byte};
-use super::history::{ByteHistory, HistoryState};
+use crate::basic::Byte;
+use super::history::{ByteHistory, HistoryCell, HistoryState};
 use super::matched::ByteMatched;
 
 // -----------------------------------------------
 
-pub struct PrimaryContext<const SIZE: usize> {
-	previous_byte: Byte,
-	hash_value: usize,
-	context: Buffer<ByteHistory, SIZE>,
+// histories are stored as raw u32, or u64 for wide ones, so that the buffer is allocated as lazily
+// zeroed memory
+enum Histories {
+	Narrow(Box<[u32]>),
+	Wide(Box<[u64]>),
+}
+
+// with check tags, the histories come in buckets of 3 followed by a cell holding their tags, the
+// tags taking a quarter of a cell each
+trait Cell: HistoryCell {
+	const TAG_BITS: u32;
+}
+
+impl Cell for u32 {
+	const TAG_BITS: u32 = 8;
+}
+
+impl Cell for u64 {
+	const TAG_BITS: u32 = 16;
+}
+
+// the slot of the context with the given tag in its bucket, or else the slot of an empty or the
+// least confident history, which is given to the context, together with whether another context
+// lost it
+#[inline(always)]
+fn tagged_slot<C: Cell>(bucket: &mut [C], tag: u64) -> (usize, bool) {
+	let tag_mask: u64 = (1 << C::TAG_BITS) - 1;
+	let tags: u64 = bucket[3].bits();
+	let tag_of = |way: usize| (tags >> (way as u32 * C::TAG_BITS)) & tag_mask;
+	if let Some(way) = (0..3).find(|&way: &usize| tag_of(way) == tag && bucket[way].bits() != 0) {
+		return (way, false);
+	}
+	let victim: usize = (0..3)
+		.min_by_key(|&way: &usize| ByteHistory::new(bucket[way]).get_state().match_count())
+		.unwrap_or(0);
+	let evicted: bool = bucket[victim].bits() != 0;
+	let shift: u32 = victim as u32 * C::TAG_BITS;
+	bucket[victim] = C::from_bits(0);
+	bucket[3] = C::from_bits((tags & !(tag_mask << shift)) | (tag << shift));
+	(victim, evicted)
+}
+
+// the history of a cell after the next byte
+#[inline(always)]
+fn updated<C: HistoryCell>(
+	cell: C,
+	current_state: HistoryState,
+	next_byte: Byte,
+	matched: ByteMatched,
+) -> C {
+	let mut history: ByteHistory<C> = ByteHistory::new(cell);
+	history.matched(current_state, next_byte, matched);
+	history.cell()
 }
 
-impl<const SIZE: usize> PrimaryContext<SIZE> {
-	// assert that SIZE is power of 2
-	const _SIZE_CHECK: () = assert!(SIZE != 0 && (SIZE & (SIZE - 1)) == 0);
+pub struct PrimaryContext {
+	hash_value: usize,
+	mask: usize,
+	// the slot of the current history, which is the hash itself without check tags, otherwise
+	// the low bits of the hash pick a bucket and the tag of the context is either the next bits
+	// of the hash of the last bytes or the top bits of a second rolling hash
+	slot: usize,
+	tagged: bool,
+	bucket_bits: u32,
+	check_value: u64,
+	tag_bits: u32,
+	// the number of contexts that took the slot of another one
+	evictions: u64,
+	// the last 8 bytes and the mask of those hashed with an explicit order, 0 for the rolling hash
+	recent_bytes: u64,
+	order_mask: u64,
+	hash_multiplier: u64,
+	hash_shift: u32,
+	context: Histories,
+	// the raw history of the current slot, loaded as soon as the slot is known so that the cache
+	// miss overlaps with the coding of the byte instead of stalling the next one
+	history: u64,
+}
 
-	pub fn new() -> Self {
+impl PrimaryContext {
+	// a context of 2^bits histories, chosen at runtime, hashing the last `order` bytes if any,
+	// otherwise rolling a hash in which old bytes fade out, ranking 7 bytes if wide, otherwise 3,
+	// and checking the tags of the contexts if tagged, which leaves room for 3/4 of the histories
+	pub fn new(bits: u8, order: Option<u8>, wide: bool, tagged: bool) -> Self {
+		debug_assert!(matches!(order, None | Some(1..=8)));
+		let size: usize = 1 << bits;
+		// the hash of the last bytes also covers the tags of the contexts, in place of the 2 bits of
+		// the buckets, while the rolling hash keeps its width, which is what makes its order
+		let tag_bits: u32 = match wide {
+			false => u32::TAG_BITS,
+			true => u64::TAG_BITS,
+		};
+		let hash_bits: u32 = match tagged && order.is_some() {
+			false => bits as u32,
+			true => bits as u32 - 2 + tag_bits,
+		};
 		Self {
-			previous_byte: Byte::from(0),
 			hash_value: 0,
-			context: Buffer::new(),
+			mask: (1 << hash_bits) - 1,
+			slot: 0,
+			tagged,
+			bucket_bits: bits as u32 - 2,
+			check_value: 0,
+			tag_bits,
+			evictions: 0,
+			recent_bytes: 0,
+			order_mask: order.map_or(0, |order| u64::MAX >> (64 - 8 * order as u32)),
+			hash_multiplier: 0x9E37_79B9_7F4A_7C15,
+			hash_shift: 64 - hash_bits,
+			context: match wide {
+				false => Histories::Narrow(vec![0; size].into_boxed_slice()),
+				true => Histories::Wide(vec![0; size].into_boxed_slice()),
+			},
+			history: 0,
 		}
 	}
 
-	pub fn get_history(&self) -> ByteHistory {
-		self.context[self.hash_value]
+	// a narrow context of order 1 or 2 indexed by the last bytes themselves
+	pub fn direct(order: u8) -> Self {
+		debug_assert!((1..=2).contains(&order));
+		let mut context: Self = Self::new(8 * order, Some(order), false, false);
+		context.hash_multiplier = 1 << context.hash_shift;
+		context
 	}
 
-	pub fn previous_byte(&self) -> Byte {
-		self.previous_byte
+	// the bytes ranked by the current history, in a wide one whatever its kind, and its state in
+	// the table of its kind
+	pub fn get_history(&self) -> (ByteHistory<u64>, HistoryState) {
+		match self.context {
+			Histories::Narrow(_) => {
+				let history: ByteHistory = self.plain_history();
+				(history.widen(), history.get_state())
+			}
+			Histories::Wide(_) => {
+				let history: ByteHistory<u64> = ByteHistory::new(self.history);
+				(history, history.get_state())
+			}
+		}
+	}
+
+	// the current history of a narrow context
+	#[inline(always)]
+	pub fn plain_history(&self) -> ByteHistory {
+		ByteHistory::new(self.history as u32)
+	}
+
+	// the number of bytes ranked by every history
+	pub fn ranks(&self) -> usize {
+		match self.context {
+			Histories::Narrow(_) => 3,
+			Histories::Wide(_) => 7,
+		}
+	}
+
+	// the last 2 bytes, the last one in the low byte
+	pub fn previous_bytes(&self) -> u16 {
+		self.recent_bytes as u16
 	}
 
 	pub fn hash_value(&self) -> usize {
 		self.hash_value
 	}
 
+	// the number of contexts that took the slot of another one, always 0 without check tags
+	pub fn evictions(&self) -> u64 {
+		self.evictions
+	}
+
 	pub fn matching(&mut self, current_state: HistoryState, next_byte: Byte) -> ByteMatched {
-		let current_history: &mut ByteHistory = &mut self.context[self.hash_value];
-		let matching_byte: ByteMatched = current_history.matching(current_state, next_byte);
-		self.previous_byte = next_byte;
-		self.hash_value = (self.hash_value * (5 << 5) + usize::from(next_byte) + 1) % SIZE;
-		debug_assert!(self.hash_value < SIZE);
+		let matching_byte: ByteMatched =
+			ByteHistory::new(self.history).rank_of(next_byte, self.ranks());
+		self.matched(current_state, next_byte, matching_byte);
 		return matching_byte;
 	}
 
 	pub fn matched(&mut self, current_state: HistoryState, next_byte: Byte, matched: ByteMatched) {
-		let current_history: &mut ByteHistory = &mut self.context[self.hash_value];
+		let history: u64 = self.history;
+		match &mut self.context {
+			Histories::Narrow(context) => {
+				context[self.slot] = updated(history as u32, current_state, next_byte, matched)
+			}
+			Histories::Wide(context) => {
+				context[self.slot] = updated(history, current_state, next_byte, matched)
+			}
+		}
+		self.next_hash(next_byte);
+	}
+
+	// the same for the default context alone, with narrow histories and the rolling hash without
+	// check tags, which the plain streams use without checking for the other kinds on every byte
+	#[inline(always)]
+	pub fn plain_matching(&mut self, current_state: HistoryState, next_byte: Byte) -> ByteMatched {
+		let mut current_history: ByteHistory = self.plain_history();
+		let matching_byte: ByteMatched = current_history.plain_matching(current_state, next_byte);
+		self.next_plain(current_history, next_byte);
+		return matching_byte;
+	}
+
+	#[inline(always)]
+	pub fn plain_matched(
+		&mut self,
+		current_state: HistoryState,
+		next_byte: Byte,
+		matched: ByteMatched,
+	) {
+		let mut current_history: ByteHistory = self.plain_history();
 		current_history.matched(current_state, next_byte, matched);
-		self.previous_byte = next_byte;
-		self.hash_value = (self.hash_value * (5 << 5) + usize::from(next_byte) + 1) % SIZE;
-		debug_assert!(self.hash_value < SIZE);
+		self.next_plain(current_history, next_byte);
+	}
+
+	#[inline(always)]
+	fn next_plain(&mut self, history: ByteHistory, next_byte: Byte) {
+		debug_assert!(self.order_mask == 0 && !self.tagged);
+		let context: &mut [u32] = match &mut self.context {
+			Histories::Narrow(context) => context,
+			Histories::Wide(_) => unreachable!(),
+		};
+		context[self.slot] = history.cell();
+		self.recent_bytes = (self.recent_bytes << 8) | u64::from(next_byte);
+		self.hash_value = (self.hash_value * (5 << 5) + usize::from(next_byte) + 1) & self.mask;
+		self.slot = self.hash_value;
+		self.history = u64::from(context[self.slot]);
+	}
+
+	#[inline(always)]
+	fn next_hash(&mut self, next_byte: Byte) {
+		self.recent_bytes = (self.recent_bytes << 8) | u64::from(next_byte);
+		if self.order_mask == 0 {
+			self.hash_value = (self.hash_value * (5 << 5) + usize::from(next_byte) + 1) & self.mask;
+			if self.tagged {
+				// the same shift makes the second hash forget the same bytes as the first one
+				self.check_value = (self.check_value * (7 << 5)
+					+ (u64::from(next_byte) + 1) * 0x2F0F_D2E1)
+					& self.mask as u64;
+			}
+		} else {
+			// the high bits of a multiplicative hash, shifted to the size of the context, which
+			// are the bytes themselves when they fill the context
+			let hash: u64 =
+				(self.recent_bytes & self.order_mask).wrapping_mul(self.hash_multiplier);
+			self.hash_value = (hash >> self.hash_shift) as usize;
+		}
+		self.slot = match self.tagged {
+			false => self.hash_value,
+			true => self.tagged_slot(),
+		};
+		self.history = match &self.context {
+			Histories::Narrow(context) => u64::from(context[self.slot]),
+			Histories::Wide(context) => context[self.slot],
+		};
+	}
+
+	fn tagged_slot(&mut self) -> usize {
+		let bucket: usize = (self.hash_value & ((1 << self.bucket_bits) - 1)) << 2;
+		let tag: u64 = match self.order_mask {
+			0 => self.check_value.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - self.tag_bits),
+			_ => (self.hash_value >> self.bucket_bits) as u64,
+		};
+		let (way, evicted): (usize, bool) = match &mut self.context {
+			Histories::Narrow(context) => tagged_slot(&mut context[bucket..bucket + 4], tag),
+			Histories::Wide(context) => tagged_slot(&mut context[bucket..bucket + 4], tag),
+		};
+		self.evictions += u64::from(evicted);
+		bucket + way
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{tagged_slot, Cell, PrimaryContext};
+	use crate::basic::Byte;
+	use crate::primary_context::{ByteHistory, ByteMatched, HistoryState};
+
+	fn feed(context: &mut PrimaryContext, bytes: &[u8]) {
+		for &byte in bytes {
+			let (_, state) = context.get_history();
+			context.matching(state, Byte::from(byte));
+		}
+	}
+
+	#[test]
+	fn test_context_order() {
+		// a context of order 3 finds the same history after the same last 3 bytes, whatever came
+		// before them
+		let mut context: PrimaryContext = PrimaryContext::new(20, Some(3), false, false);
+		feed(&mut context, b"symbol ranking");
+		let hash_value: usize = context.hash_value();
+		feed(&mut context, b"!");
+		feed(&mut context, b"compressor is sorting");
+		assert_eq!(context.hash_value(), hash_value);
+		assert_eq!(context.get_history().0.first_byte(), Byte::from(b'!'));
+
+		// while the rolling hash still tells them apart
+		let mut rolling: PrimaryContext = PrimaryContext::new(20, None, false, false);
+		feed(&mut rolling, b"symbol ranking");
+		let hash_value: usize = rolling.hash_value();
+		feed(&mut rolling, b"compressor is sorting");
+		assert_ne!(rolling.hash_value(), hash_value);
+
+		// a direct context is indexed by the last bytes themselves
+		let mut direct: PrimaryContext = PrimaryContext::direct(2);
+		feed(&mut direct, b"srx");
+		assert_eq!(direct.hash_value(), 0x7278);
+		assert_eq!(direct.previous_bytes(), 0x7278);
+		let mut direct: PrimaryContext = PrimaryContext::direct(1);
+		feed(&mut direct, b"srx");
+		assert_eq!(direct.hash_value(), 0x78);
+	}
+	#[test]
+	fn test_wide_histories() {
+		// after 8 different bytes, only a wide history still ranks the second one
+		for (wide, ranks, matched) in [
+			(false, 3, ByteMatched::NONE),
+			(true, 7, ByteMatched::SEVENTH),
+		] {
+			let mut context: PrimaryContext = PrimaryContext::new(16, Some(1), wide, false);
+			assert_eq!(context.ranks(), ranks);
+			feed(&mut context, b"-a-b-c-d-e-f-g-h-");
+			let (history, _) = context.get_history();
+			for (rank, &byte) in b"hgf".iter().enumerate() {
+				assert_eq!(history.byte(rank), Byte::from(byte));
+			}
+			assert_eq!(history.byte(6), Byte::from(if wide { b'b' } else { 0 }));
+			let (_, state) = context.get_history();
+			assert_eq!(context.matching(state, Byte::from(b'b')), matched);
+		}
+	}
+	// a history which matched its first byte the given number of times
+	fn confident<C: Cell>(matches: usize) -> C {
+		let mut history: ByteHistory<C> = ByteHistory::new(C::from_bits(0));
+		for _ in 0..matches {
+			let state: HistoryState = history.get_state();
+			history.matched(state, Byte::from(b'a'), ByteMatched::FIRST);
+		}
+		history.cell()
+	}
+
+	fn check_bucket<C: Cell>(tags: [u64; 3], tag: u64) {
+		let tag_mask: u64 = (1 << C::TAG_BITS) - 1;
+		let tag_of =
+			|bucket: &[C], way: usize| (bucket[3].bits() >> (way as u32 * C::TAG_BITS)) & tag_mask;
+		let mut bucket: [C; 4] = [
+			confident(8),
+			confident(2),
+			confident(5),
+			C::from_bits(tags[0] | tags[1] << C::TAG_BITS | tags[2] << (2 * C::TAG_BITS)),
+		];
+		assert!(bucket[1].bits() != 0);
+
+		// a context finds its history by its tag
+		assert_eq!(tagged_slot(&mut bucket, tags[1]), (1, false));
+		assert_eq!(tagged_slot(&mut bucket, tags[2]), (2, false));
+
+		// another one takes the least confident history
+		assert_eq!(tagged_slot(&mut bucket, tag), (1, true));
+		assert_eq!(bucket[1].bits(), 0);
+		assert_eq!(tag_of(&bucket, 0), tags[0]);
+		assert_eq!(tag_of(&bucket, 1), tag);
+		assert_eq!(tag_of(&bucket, 2), tags[2]);
+
+		// and an empty history, even with the same tag, is taken without evicting any
+		assert_eq!(tagged_slot(&mut bucket, tag), (1, false));
+		assert_eq!(tagged_slot(&mut bucket, tags[1]), (1, false));
+	}
+
+	#[test]
+	fn test_check_tags() {
+		check_bucket::<u32>([0x11, 0x22, 0x33], 0x44);
+		check_bucket::<u64>([0x1111, 0x2222, 0x3333], 0x4444);
+
+		// only tagged histories count the contexts that lost their slot, with the rolling hash too
+		let mut seed: u32 = 0x13579BDF;
+		let noise: Vec<u8> = (0..100000)
+			.map(|_| {
+				seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+				(seed >> 16) as u8
+			})
+			.collect();
+		for (order, tagged) in [(Some(3), false), (Some(3), true), (None, true)] {
+			let mut context: PrimaryContext = PrimaryContext::new(16, order, false, tagged);
+			feed(&mut context, &noise);
+			assert_eq!(context.evictions() > 0, tagged);
+		}
 	}
 }
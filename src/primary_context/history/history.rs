@@ -16,75 +16,173 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use super::state::{HistoryState, STATE_TABLE};
+use super::state::{HistoryState, STATE_TABLE, WIDE_STATE_TABLE};
 use crate::basic::Byte;
 use crate::primary_context::ByteMatched;
 
 // -----------------------------------------------
 
-#[derive(Clone, Copy)]
-pub struct ByteHistory(u32);
+// the raw value of a history: a narrow one ranks three bytes in a u32, a wide one seven in a u64,
+// each moving through its own table of states
+pub trait HistoryCell: Copy {
+	const STATES: &'static [HistoryState];
+
+	fn bits(self) -> u64;
+
+	fn from_bits(bits: u64) -> Self;
+}
+
+impl HistoryCell for u32 {
+	const STATES: &'static [HistoryState] = STATE_TABLE;
 
-impl Default for ByteHistory {
-	fn default() -> Self {
-		ByteHistory(0)
+	#[inline(always)]
+	fn bits(self) -> u64 {
+		u64::from(self)
+	}
+
+	// a narrow history drops the fourth byte that a miss pushes past the third rank
+	#[inline(always)]
+	fn from_bits(bits: u64) -> Self {
+		bits as u32
 	}
 }
 
-impl ByteHistory {
+impl HistoryCell for u64 {
+	const STATES: &'static [HistoryState] = WIDE_STATE_TABLE;
+
+	#[inline(always)]
+	fn bits(self) -> u64 {
+		self
+	}
+
+	#[inline(always)]
+	fn from_bits(bits: u64) -> Self {
+		bits
+	}
+}
+
+// the state in the low byte, then the ranked bytes
+#[derive(Clone, Copy)]
+pub struct ByteHistory<C: HistoryCell = u32>(C);
+
+impl<C: HistoryCell> ByteHistory<C> {
+	pub fn new(cell: C) -> Self {
+		Self(cell)
+	}
+
+	pub fn cell(self) -> C {
+		self.0
+	}
+
+	// the same ranked bytes in a wide history, for the code that handles both kinds, which keeps the
+	// state of a narrow one apart as it only makes sense in the table of narrow histories
+	pub fn widen(self) -> ByteHistory<u64> {
+		ByteHistory(self.0.bits())
+	}
+
 	pub fn first_byte(&self) -> Byte {
-		Byte::from((self.0 >> 8) & 0xFF)
+		Byte::from((self.0.bits() >> 8) & 0xFF)
 	}
 
 	pub fn second_byte(&self) -> Byte {
-		Byte::from((self.0 >> 16) & 0xFF)
+		Byte::from((self.0.bits() >> 16) & 0xFF)
 	}
 
 	pub fn third_byte(&self) -> Byte {
-		Byte::from(self.0 >> 24)
+		Byte::from((self.0.bits() >> 24) & 0xFF)
+	}
+
+	// the byte at the given rank, from 0 for the first one
+	pub fn byte(&self, rank: usize) -> Byte {
+		debug_assert!(rank < 7);
+		Byte::from((self.0.bits() >> (rank * 8 + 8)) & 0xFF)
 	}
 
 	pub fn get_state(&self) -> HistoryState {
-		STATE_TABLE[(self.0 & 0xFF) as usize]
+		C::STATES[(self.0.bits() & 0xFF) as usize]
 	}
 
-	pub fn matching(&mut self, current_state: HistoryState, next_byte: Byte) -> ByteMatched {
-		let mask: u32 = self.0 ^ (0x01_01_01_00 * u32::from(next_byte));
-		let matched: ByteMatched = if (mask & 0x00_00_FF_00) == 0 {
-			ByteMatched::FIRST
-		} else if (mask & 0x00_FF_00_00) == 0 {
-			ByteMatched::SECOND
-		} else if (mask & 0xFF_00_00_00) == 0 {
-			ByteMatched::THIRD
+	// the rank of the next byte among the first `ranks` bytes, 3 or 7
+	pub fn rank_of(&self, next_byte: Byte, ranks: usize) -> ByteMatched {
+		let mask: u64 = self.0.bits() ^ (0x01_01_01_01_01_01_01_00 * u64::from(next_byte));
+		// the high bit of the lowest zero byte past the state is exact, higher ones may be wrong
+		let zeros: u64 = mask.wrapping_sub(0x01_01_01_01_01_01_01_00) & !mask;
+		let rank: usize = ((zeros & 0x80_80_80_80_80_80_80_00).trailing_zeros() / 8) as usize - 1;
+		if rank < ranks {
+			ByteMatched::from_rank(rank)
 		} else {
 			ByteMatched::NONE
-		};
-		self.matched(current_state, next_byte, matched);
-		matched
+		}
 	}
 
 	pub fn matched(&mut self, current_state: HistoryState, next_byte: Byte, matched: ByteMatched) {
-		let byte_history: u32 = self.0;
-		debug_assert!(STATE_TABLE[(byte_history & 0xFF) as usize] == current_state);
-		let updated_history: u32 = match matched {
+		let byte_history: u64 = self.0.bits();
+		let next_byte: u64 = u64::from(next_byte);
+		debug_assert!(C::STATES[(byte_history & 0xFF) as usize] == current_state);
+		let updated_history: u64 = match matched {
 			ByteMatched::FIRST => {
 				// matched the first byte, keep the order of bytes
-				byte_history & 0xFF_FF_FF_00
+				byte_history & 0xFF_FF_FF_FF_FF_FF_FF_00
 			}
 			ByteMatched::SECOND => {
 				// matched the second byte, swap the first and the second place
-				(byte_history & 0xFF_00_00_00)
-					| (((byte_history & 0x00_00_FF_00) | u32::from(next_byte)) << 8)
+				(byte_history & 0xFF_FF_FF_FF_FF_00_00_00)
+					| (((byte_history & 0x00_00_00_00_00_00_FF_00) | next_byte) << 8)
 			}
 			ByteMatched::THIRD => {
 				// matched the third byte, move old first/second to second/third and set the first byte
-				((byte_history & 0x00_FF_FF_00) | u32::from(next_byte)) << 8
+				(byte_history & 0xFF_FF_FF_FF_00_00_00_00)
+					| (((byte_history & 0x00_00_00_00_00_FF_FF_00) | next_byte) << 8)
+			}
+			// likewise for the deeper ranks of wide histories
+			ByteMatched::FOURTH => {
+				(byte_history & 0xFF_FF_FF_00_00_00_00_00)
+					| (((byte_history & 0x00_00_00_00_FF_FF_FF_00) | next_byte) << 8)
+			}
+			ByteMatched::FIFTH => {
+				(byte_history & 0xFF_FF_00_00_00_00_00_00)
+					| (((byte_history & 0x00_00_00_FF_FF_FF_FF_00) | next_byte) << 8)
+			}
+			ByteMatched::SIXTH => {
+				(byte_history & 0xFF_00_00_00_00_00_00_00)
+					| (((byte_history & 0x00_00_FF_FF_FF_FF_FF_00) | next_byte) << 8)
 			}
-			ByteMatched::NONE => {
-				// not match, move old first/second to second/third and set the first byte
-				((byte_history & 0x00_FF_FF_00) | u32::from(next_byte)) << 8
+			ByteMatched::SEVENTH | ByteMatched::NONE => {
+				// not match, move every byte one place down and set the first byte, which pushes
+				// the last one out of a wide history and past the low 32 bits of a narrow one
+				((byte_history & 0x00_FF_FF_FF_FF_FF_FF_00) | next_byte) << 8
 			}
 		};
+		self.0 = C::from_bits(updated_history | current_state.next(matched) as u64);
+	}
+}
+
+impl ByteHistory {
+	// match the next byte against the three bytes of a narrow history, comparing them one after
+	// another as the plain streams did before the wide histories
+	#[inline(always)]
+	pub fn plain_matching(&mut self, current_state: HistoryState, next_byte: Byte) -> ByteMatched {
+		let byte_history: u32 = self.0;
+		debug_assert!(STATE_TABLE[(byte_history & 0xFF) as usize] == current_state);
+		let mask: u32 = byte_history ^ (0x01_01_01_00 * u32::from(next_byte));
+		let (matched, updated_history): (ByteMatched, u32) = if (mask & 0x00_00_FF_00) == 0 {
+			(ByteMatched::FIRST, byte_history & 0xFF_FF_FF_00)
+		} else if (mask & 0x00_FF_00_00) == 0 {
+			(
+				ByteMatched::SECOND,
+				(byte_history & 0xFF_00_00_00)
+					| (((byte_history & 0x00_00_FF_00) | u32::from(next_byte)) << 8),
+			)
+		} else {
+			(
+				match (mask & 0xFF_00_00_00) == 0 {
+					true => ByteMatched::THIRD,
+					false => ByteMatched::NONE,
+				},
+				((byte_history & 0x00_FF_FF_00) | u32::from(next_byte)) << 8,
+			)
+		};
 		self.0 = updated_history | current_state.next(matched) as u32;
+		matched
 	}
 }
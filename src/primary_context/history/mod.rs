@@ -18,8 +18,10 @@
 
 mod history;
 mod state;
+// generator of the state table, not held to the lints
 #[cfg(test)]
+#[allow(clippy::all, dead_code)]
 mod test;
 
-pub use self::history::ByteHistory;
+pub use self::history::{ByteHistory, HistoryCell};
 pub use self::state::HistoryState;
@@ -21,6 +21,7 @@ use crate::primary_context::ByteMatched;
 // -----------------------------------------------
 
 include!("state_table.inc");
+include!("wide_state_table.inc");
 
 // -----------------------------------------------
 
@@ -44,16 +45,42 @@ impl HistoryState {
 		)
 	}
 
+	// a state of a wide history, which also moves on a match of the fourth byte or of a deeper one
+	pub const fn wide(
+		first_count: u8,
+		next_if_first: u8,
+		next_if_second: u8,
+		next_if_third: u8,
+		next_if_fourth: u8,
+		next_if_deeper: u8,
+		next_if_miss: u8,
+	) -> Self {
+		Self(
+			(next_if_first as u64)
+				| ((next_if_second as u64) << 8)
+				| ((next_if_third as u64) << 16)
+				| ((next_if_miss as u64) << 24)
+				| ((first_count as u64) << 32)
+				| ((next_if_fourth as u64) << 40)
+				| ((next_if_deeper as u64) << 48),
+		)
+	}
+
 	pub fn next(&self, matched: ByteMatched) -> usize {
 		match matched {
 			ByteMatched::FIRST => (self.0 & 0xFF) as usize,
 			ByteMatched::SECOND => ((self.0 >> 8) & 0xFF) as usize,
 			ByteMatched::THIRD => ((self.0 >> 16) & 0xFF) as usize,
+			// only the states of wide histories move on the deeper ranks
+			ByteMatched::FOURTH => ((self.0 >> 40) & 0xFF) as usize,
+			ByteMatched::FIFTH | ByteMatched::SIXTH | ByteMatched::SEVENTH => {
+				((self.0 >> 48) & 0xFF) as usize
+			}
 			ByteMatched::NONE => ((self.0 >> 24) & 0xFF) as usize,
 		}
 	}
 
 	pub fn match_count(&self) -> usize {
-		(self.0 >> 32) as usize
+		((self.0 >> 32) & 0xFF) as usize
 	}
 }
@@ -16,8 +16,10 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::AnyResult;
-use super::state::{HistoryState, STATE_TABLE};
+use crate::basic::{AnyResult, Byte};
+use crate::primary_context::ByteMatched;
+use super::history::ByteHistory;
+use super::state::{HistoryState, STATE_TABLE, WIDE_STATE_TABLE};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::File;
@@ -32,11 +34,16 @@ struct StateInfo {
 	first: u8,
 	second: u8,
 	third: u8,
+	// only counted by wide histories
+	fourth: u8,
 }
 
 impl StateInfo {
 	fn id(&self) -> u64 {
-		((self.first as u64) << 16) | ((self.second as u64) << 8) | (self.third as u64)
+		((self.first as u64) << 24)
+			| ((self.second as u64) << 16)
+			| ((self.third as u64) << 8)
+			| (self.fourth as u64)
 	}
 }
 
@@ -52,6 +59,7 @@ impl Ord for StateInfo {
 			.cmp(&other.first)
 			.then(self.second.cmp(&other.second))
 			.then(self.third.cmp(&other.third))
+			.then(self.fourth.cmp(&other.fourth))
 	}
 }
 
@@ -63,6 +71,8 @@ struct PrimitiveState {
 	next_if_first: StateInfo,
 	next_if_second: StateInfo,
 	next_if_third: StateInfo,
+	next_if_fourth: StateInfo,
+	next_if_deeper: StateInfo,
 	next_if_miss: StateInfo,
 }
 
@@ -112,36 +122,41 @@ fn dec_nz(mut value: u8, max: u8) -> u8 {
 #[derive(Debug)]
 struct PrimitiveStateTable {
 	map: HashMap<StateInfo, PrimitiveState>,
+	// the max of every count, the fourth one being 0 for narrow histories, which never match the
+	// fourth byte or a deeper one
+	max: StateInfo,
 }
 
 impl PrimitiveStateTable {
-	const MAX_FIRST: u8 = 67;
-	const MAX_SECOND: u8 = 7;
-	const MAX_THIRD: u8 = 3;
+	const NARROW: StateInfo = StateInfo {
+		first: 67,
+		second: 7,
+		third: 3,
+		fourth: 0,
+	};
+	// a fourth count only fits in 256 states with lower counts, the third and the fourth ones only
+	// taking what is left of the second one
+	const WIDE: StateInfo = StateInfo {
+		first: 23,
+		second: 3,
+		third: 2,
+		fourth: 1,
+	};
 	// const MAX_MISS: u8 = 3;
 
-	fn new() -> Self {
+	fn new(max: StateInfo) -> Self {
 		Self {
 			map: HashMap::new(),
+			max,
 		}
 	}
 
-	fn state(
-		&mut self,
-		current_state: StateInfo,
-		next_if_first: StateInfo,
-		next_if_second: StateInfo,
-		next_if_third: StateInfo,
-		next_if_miss: StateInfo,
-	) -> bool {
-		let full_state = PrimitiveState {
-			current_state,
-			next_if_first,
-			next_if_second,
-			next_if_third,
-			next_if_miss,
-		};
-		if let Some(old_state) = self.map.insert(current_state, full_state) {
+	fn is_wide(&self) -> bool {
+		self.max.fourth > 0
+	}
+
+	fn state(&mut self, full_state: PrimitiveState) -> bool {
+		if let Some(old_state) = self.map.insert(full_state.current_state, full_state) {
 			assert_eq!(old_state, full_state, "State already exist!");
 			false
 		} else {
@@ -149,62 +164,89 @@ impl PrimitiveStateTable {
 		}
 	}
 
-	fn next_if_first(current: StateInfo) -> StateInfo {
+	fn next_if_first(&self, current: StateInfo) -> StateInfo {
 		if current.first <= 31 {
 			StateInfo {
-				first: increase(current.first, Self::MAX_FIRST),
-				second: dec_nz(current.second, Self::MAX_SECOND),
-				third: dec_nz(current.third, Self::MAX_THIRD),
+				first: increase(current.first, self.max.first),
+				second: dec_nz(current.second, self.max.second),
+				third: dec_nz(current.third, self.max.third),
+				fourth: dec_nz(current.fourth, self.max.fourth),
 			}
 		} else {
 			StateInfo {
-				first: increase(current.first, Self::MAX_FIRST),
+				first: increase(current.first, self.max.first),
 				second: 1,
 				third: 1,
+				fourth: range(1, self.max.fourth),
 			}
 		}
 	}
 
-	fn next_if_second(current: StateInfo) -> StateInfo {
+	fn next_if_second(&self, current: StateInfo) -> StateInfo {
 		StateInfo {
-			first: range(current.second, Self::MAX_FIRST),
-			second: range(current.first, Self::MAX_SECOND),
-			third: dec_nz(current.third, Self::MAX_THIRD),
+			first: range(current.second, self.max.first),
+			second: range(current.first, self.max.second),
+			third: dec_nz(current.third, self.max.third),
+			fourth: dec_nz(current.fourth, self.max.fourth),
 		}
 	}
 
-	fn next_if_third(current: StateInfo) -> StateInfo {
+	fn next_if_third(&self, current: StateInfo) -> StateInfo {
 		StateInfo {
-			first: range(current.third, Self::MAX_FIRST),
-			second: range(current.first, Self::MAX_SECOND),
-			third: dec_nz(current.second, Self::MAX_THIRD),
+			first: range(current.third, self.max.first),
+			second: range(current.first, self.max.second),
+			third: dec_nz(current.second, self.max.third),
+			fourth: dec_nz(current.fourth, self.max.fourth),
 		}
 	}
 
-	fn next_if_miss(current: StateInfo) -> StateInfo {
+	fn next_if_fourth(&self, current: StateInfo) -> StateInfo {
+		StateInfo {
+			first: range(current.fourth, self.max.first),
+			second: range(current.first, self.max.second),
+			third: dec_nz(current.second, self.max.third),
+			fourth: dec_nz(current.third, self.max.fourth),
+		}
+	}
+
+	// the fifth to seventh bytes are not counted, but they were seen at least once
+	fn next_if_deeper(&self, current: StateInfo) -> StateInfo {
+		StateInfo {
+			first: range(1, self.max.first),
+			second: range(current.first, self.max.second),
+			third: dec_nz(current.second, self.max.third),
+			fourth: dec_nz(current.third, self.max.fourth),
+		}
+	}
+
+	fn next_if_miss(&self, current: StateInfo) -> StateInfo {
 		StateInfo {
 			first: 0,
-			second: range(current.first, Self::MAX_SECOND),
-			third: dec_nz(current.second, Self::MAX_THIRD),
+			second: range(current.first, self.max.second),
+			third: dec_nz(current.second, self.max.third),
+			fourth: dec_nz(current.third, self.max.fourth),
 		}
 	}
 
 	fn state_auto(&mut self, current: StateInfo) {
-		let next_if_first: StateInfo = Self::next_if_first(current);
-		let next_if_second: StateInfo = Self::next_if_second(current);
-		let next_if_third: StateInfo = Self::next_if_third(current);
-		let next_if_miss: StateInfo = Self::next_if_miss(current);
-		if self.state(
-			current,
-			next_if_first,
-			next_if_second,
-			next_if_third,
-			next_if_miss,
-		) {
-			self.state_auto(next_if_first);
-			self.state_auto(next_if_second);
-			self.state_auto(next_if_third);
-			self.state_auto(next_if_miss);
+		let full_state: PrimitiveState = PrimitiveState {
+			current_state: current,
+			next_if_first: self.next_if_first(current),
+			next_if_second: self.next_if_second(current),
+			next_if_third: self.next_if_third(current),
+			next_if_fourth: self.next_if_fourth(current),
+			next_if_deeper: self.next_if_deeper(current),
+			next_if_miss: self.next_if_miss(current),
+		};
+		if self.state(full_state) {
+			self.state_auto(full_state.next_if_first);
+			self.state_auto(full_state.next_if_second);
+			self.state_auto(full_state.next_if_third);
+			if self.is_wide() {
+				self.state_auto(full_state.next_if_fourth);
+				self.state_auto(full_state.next_if_deeper);
+			}
+			self.state_auto(full_state.next_if_miss);
 		}
 	}
 
@@ -312,19 +354,21 @@ impl PrimitiveStateTable {
 
 // -----------------------------------------------
 
-#[test]
-fn test_and_generate_state_table() -> AnyResult<()> {
-	let mut table: PrimitiveStateTable = PrimitiveStateTable::new();
+// print the table of every state reachable from the empty history, sorted by their counts
+fn generate_state_table(max: StateInfo, name: &str) -> Vec<HistoryState> {
+	let mut table: PrimitiveStateTable = PrimitiveStateTable::new(max);
 	table.state_auto(StateInfo {
 		first: 0,
 		second: 0,
 		third: 0,
+		fourth: 0,
 	});
 
 	// table.export()?;
 
 	let mut states: Vec<&PrimitiveState> = table.map.values().collect();
 	states.sort_by_key(|x| x.current_state);
+	assert!(states.len() <= 256, "Too many states!");
 
 	let mut states_index: HashMap<&StateInfo, usize> = HashMap::new();
 	for (index, &state) in states.iter().enumerate() {
@@ -333,7 +377,8 @@ fn test_and_generate_state_table() -> AnyResult<()> {
 
 	// create next states array
 	println!(
-		"pub const STATE_TABLE: &[HistoryState] = &[ // length = {}",
+		"pub const {}: &[HistoryState] = &[ // length = {}",
+		name,
 		states.len()
 	);
 	let mut state_table: Vec<HistoryState> = Vec::new();
@@ -341,33 +386,106 @@ fn test_and_generate_state_table() -> AnyResult<()> {
 		let first_count = state.current_state.first;
 		let second_count = state.current_state.second;
 		let third_count = state.current_state.third;
+		let fourth_count = state.current_state.fourth;
 		let &next_if_first = states_index.get(&state.next_if_first).unwrap();
 		let &next_if_second = states_index.get(&state.next_if_second).unwrap();
 		let &next_if_third = states_index.get(&state.next_if_third).unwrap();
 		let &next_if_miss = states_index.get(&state.next_if_miss).unwrap();
-		state_table.push(HistoryState::new(
-			first_count,
-			next_if_first as u8,
-			next_if_second as u8,
-			next_if_third as u8,
-			next_if_miss as u8,
-		));
-		println!(
-			"\tHistoryState::new({:2}, {:3}, {:3}, {:3}, {:3}), // {:3}, {:2}, {:2}, {:2}",
-			first_count,
-			next_if_first,
-			next_if_second,
-			next_if_third,
-			next_if_miss,
-			index,
-			first_count,
-			second_count,
-			third_count,
-		);
+		if table.is_wide() {
+			let &next_if_fourth = states_index.get(&state.next_if_fourth).unwrap();
+			let &next_if_deeper = states_index.get(&state.next_if_deeper).unwrap();
+			state_table.push(HistoryState::wide(
+				first_count,
+				next_if_first as u8,
+				next_if_second as u8,
+				next_if_third as u8,
+				next_if_fourth as u8,
+				next_if_deeper as u8,
+				next_if_miss as u8,
+			));
+			println!(
+				"\tHistoryState::wide({:2}, {:3}, {:3}, {:3}, {:3}, {:3}, {:3}), // {:3}, {:2}, {:2}, {:2}, {:2}",
+				first_count,
+				next_if_first,
+				next_if_second,
+				next_if_third,
+				next_if_fourth,
+				next_if_deeper,
+				next_if_miss,
+				index,
+				first_count,
+				second_count,
+				third_count,
+				fourth_count,
+			);
+		} else {
+			state_table.push(HistoryState::new(
+				first_count,
+				next_if_first as u8,
+				next_if_second as u8,
+				next_if_third as u8,
+				next_if_miss as u8,
+			));
+			println!(
+				"\tHistoryState::new({:2}, {:3}, {:3}, {:3}, {:3}), // {:3}, {:2}, {:2}, {:2}",
+				first_count,
+				next_if_first,
+				next_if_second,
+				next_if_third,
+				next_if_miss,
+				index,
+				first_count,
+				second_count,
+				third_count,
+			);
+		}
 	}
 	println!("];");
+	state_table
+}
 
+#[test]
+fn test_and_generate_state_table() -> AnyResult<()> {
+	let state_table: Vec<HistoryState> =
+		generate_state_table(PrimitiveStateTable::NARROW, "STATE_TABLE");
 	debug_assert!(state_table.eq(STATE_TABLE));
+	Ok(())
+}
 
+#[test]
+fn test_and_generate_wide_state_table() -> AnyResult<()> {
+	let state_table: Vec<HistoryState> =
+		generate_state_table(PrimitiveStateTable::WIDE, "WIDE_STATE_TABLE");
+	debug_assert!(state_table.eq(WIDE_STATE_TABLE));
 	Ok(())
 }
+
+#[test]
+fn test_byte_history() {
+	// a narrow history is the plain u32 it is stored as
+	assert_eq!(std::mem::size_of::<ByteHistory>(), 4);
+	assert_eq!(std::mem::size_of::<ByteHistory<u64>>(), 8);
+
+	let mut narrow: ByteHistory = ByteHistory::new(0);
+	let mut wide: ByteHistory<u64> = ByteHistory::new(0);
+	for value in [1, 2, 1, 3, 2, 4, 5, 6, 7, 8] {
+		// the plain ranking of a narrow history is the generic one, with three bytes
+		let state: HistoryState = narrow.get_state();
+		let mut generic: ByteHistory = narrow;
+		let matched: ByteMatched = generic.rank_of(Byte::from(value), 3);
+		generic.matched(state, Byte::from(value), matched);
+		assert_eq!(narrow.plain_matching(state, Byte::from(value)), matched);
+		assert_eq!(narrow.cell(), generic.cell());
+		let state: HistoryState = wide.get_state();
+		let matched: ByteMatched = wide.rank_of(Byte::from(value), 7);
+		wide.matched(state, Byte::from(value), matched);
+	}
+	// the narrow history keeps 3 bytes and the wide one 7, the latest first, where the repeated 2
+	// moved in front of the 3
+	assert_eq!(narrow.cell() >> 8, 0x06_07_08);
+	assert_eq!(wide.cell() >> 8, 0x03_02_04_05_06_07_08);
+	assert_eq!(narrow.rank_of(Byte::from(6), 3), ByteMatched::THIRD);
+	assert_eq!(narrow.rank_of(Byte::from(5), 3), ByteMatched::NONE);
+	assert_eq!(wide.rank_of(Byte::from(3), 7), ByteMatched::SEVENTH);
+	assert_eq!(narrow.widen().byte(2), narrow.third_byte());
+}
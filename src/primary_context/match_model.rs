@@ -0,0 +1,154 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::basic::Byte;
+
+// -----------------------------------------------
+
+// a match is looked up by the hash of its last 8 bytes, and its length is counted up to the limit
+const MIN_MATCH_LENGTH: usize = 8;
+const MAX_MATCH_LENGTH: usize = 0xFFFF;
+// the bytes compared backwards to measure a new match
+const VERIFIED_LENGTH: usize = 32;
+
+// -----------------------------------------------
+
+// the long-range match model, which finds the last occurrence of the recent bytes in a window of
+// the previous bytes and expects the byte that followed it
+pub struct MatchModel {
+	window: Box<[u8]>,
+	window_mask: usize,
+	// the position after the last occurrence of each hash of the last bytes, 0 for none
+	table: Box<[u32]>,
+	table_shift: u32,
+	// the number of bytes seen so far, which also tells where the next one goes in the window
+	position: usize,
+	recent_bytes: u64,
+	// the position of the expected byte and the length of the current match, 0 for none
+	match_position: usize,
+	match_length: usize,
+}
+
+impl MatchModel {
+	// a window of 2^bits bytes and a table of 2^(bits - 2) positions
+	pub fn new(bits: u8) -> Self {
+		Self {
+			window: vec![0; 1 << bits].into_boxed_slice(),
+			window_mask: (1 << bits) - 1,
+			table: vec![0; 1 << (bits - 2)].into_boxed_slice(),
+			table_shift: 64 - (bits - 2) as u32,
+			position: 0,
+			recent_bytes: 0,
+			match_position: 0,
+			match_length: 0,
+		}
+	}
+
+	// the next byte of the current match and the length of the match, if any
+	pub fn expected(&self) -> Option<(Byte, usize)> {
+		match self.match_length {
+			0 => None,
+			length => Some((
+				Byte::from(self.window[self.match_position & self.window_mask]),
+				length,
+			)),
+		}
+	}
+
+	pub fn push(&mut self, next_byte: Byte) {
+		let value: u8 = next_byte.into();
+		if self.match_length > 0 {
+			if self.window[self.match_position & self.window_mask] == value {
+				self.match_length = (self.match_length + 1).min(MAX_MATCH_LENGTH);
+				self.match_position += 1;
+			} else {
+				self.match_length = 0;
+			}
+		}
+		self.window[self.position & self.window_mask] = value;
+		self.position += 1;
+		self.recent_bytes = (self.recent_bytes << 8) | u64::from(value);
+		if self.position < MIN_MATCH_LENGTH {
+			return;
+		}
+		let hash: usize =
+			(self.recent_bytes.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> self.table_shift) as usize;
+		if self.match_length == 0 {
+			self.find_match(self.table[hash] as usize);
+		}
+		// positions wrap around at 4 GiB, which only makes older matches unreachable
+		self.table[hash] = self.position as u32;
+	}
+
+	// start a match at the candidate position if the bytes before it are the last ones
+	fn find_match(&mut self, candidate: usize) {
+		let distance: usize = (self.position as u32).wrapping_sub(candidate as u32) as usize;
+		if candidate == 0 || distance == 0 || distance > self.window_mask {
+			return;
+		}
+		let start: usize = self.position - distance;
+		let limit: usize = VERIFIED_LENGTH.min(start);
+		let length: usize = (1..=limit)
+			.take_while(|&back: &usize| {
+				self.window[(start - back) & self.window_mask]
+					== self.window[(self.position - back) & self.window_mask]
+			})
+			.count();
+		if length >= MIN_MATCH_LENGTH {
+			self.match_position = start;
+			self.match_length = length;
+		}
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::MatchModel;
+	use crate::basic::Byte;
+
+	fn push(model: &mut MatchModel, bytes: &[u8]) {
+		for &byte in bytes {
+			model.push(Byte::from(byte));
+		}
+	}
+
+	#[test]
+	fn test_match_model() {
+		let mut model: MatchModel = MatchModel::new(16);
+		push(&mut model, b"0123456789abcdef--");
+		assert_eq!(model.expected(), None);
+
+		// a match starts once the last 8 bytes are repeated, and grows with every expected byte
+		push(&mut model, b"0123456");
+		assert_eq!(model.expected(), None);
+		push(&mut model, b"7");
+		for (length, &byte) in b"89abc".iter().enumerate() {
+			assert_eq!(model.expected(), Some((Byte::from(byte), 8 + length)));
+			push(&mut model, &[byte]);
+		}
+
+		// and ends at the first other byte
+		assert_eq!(model.expected(), Some((Byte::from(b'd'), 13)));
+		push(&mut model, b"x");
+		assert_eq!(model.expected(), None);
+		push(&mut model, b"def");
+		assert_eq!(model.expected(), None);
+	}
+}
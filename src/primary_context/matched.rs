@@ -22,4 +22,39 @@ pub enum ByteMatched {
 	NONE,
 	SECOND,
 	THIRD,
+	// only with wide histories
+	FOURTH,
+	FIFTH,
+	SIXTH,
+	SEVENTH,
+}
+
+impl ByteMatched {
+	// the byte at the given rank, from 0 for the first one, or a miss past the last rank
+	pub fn from_rank(rank: usize) -> Self {
+		match rank {
+			0 => ByteMatched::FIRST,
+			1 => ByteMatched::SECOND,
+			2 => ByteMatched::THIRD,
+			3 => ByteMatched::FOURTH,
+			4 => ByteMatched::FIFTH,
+			5 => ByteMatched::SIXTH,
+			6 => ByteMatched::SEVENTH,
+			_ => ByteMatched::NONE,
+		}
+	}
+
+	// the rank of the matched byte, or None on a miss
+	pub fn rank(&self) -> Option<usize> {
+		match self {
+			ByteMatched::FIRST => Some(0),
+			ByteMatched::SECOND => Some(1),
+			ByteMatched::THIRD => Some(2),
+			ByteMatched::FOURTH => Some(3),
+			ByteMatched::FIFTH => Some(4),
+			ByteMatched::SIXTH => Some(5),
+			ByteMatched::SEVENTH => Some(6),
+			ByteMatched::NONE => None,
+		}
+	}
 }
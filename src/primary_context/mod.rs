@@ -18,8 +18,10 @@
 
 mod context;
 mod history;
+mod match_model;
 mod matched;
 
 pub use self::context::PrimaryContext;
 pub use self::history::{ByteHistory, HistoryState};
+pub use self::match_model::MatchModel;
 pub use self::matched::ByteMatched;
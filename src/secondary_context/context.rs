@@ -16,29 +16,32 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::Buffer;
 use super::bit::Bit;
 use super::state::{BitState, StateInfo};
 
-pub struct SecondaryContext<const SIZE: usize> {
-	context: Buffer<BitState, SIZE>,
+// states are stored as raw u16 so that the buffer is allocated as lazily zeroed memory, of the size
+// chosen at runtime
+pub struct SecondaryContext {
+	context: Box<[u16]>,
 }
 
-impl<const SIZE: usize> SecondaryContext<SIZE> {
-	pub fn new() -> Self {
+impl SecondaryContext {
+	pub fn new(size: usize) -> Self {
 		Self {
-			context: Buffer::new(),
+			context: vec![0; size].into_boxed_slice(),
 		}
 	}
 
-	pub fn get_info(&self, context_index: usize) -> StateInfo {
-		debug_assert!(context_index < SIZE);
-		self.context[context_index].get_info()
+	pub fn get_state(&self, context_index: usize) -> BitState {
+		debug_assert!(context_index < self.context.len());
+		BitState::from(self.context[context_index])
 	}
 
 	// return current prediction and then update the prediction with new bit
 	pub fn update(&mut self, current_state: StateInfo, context_index: usize, bit: Bit) {
-		debug_assert!(context_index < SIZE);
-		self.context[context_index].update(current_state, bit)
+		debug_assert!(context_index < self.context.len());
+		let mut state: BitState = BitState::from(self.context[context_index]);
+		state.update(current_state, bit);
+		self.context[context_index] = u16::from(state);
 	}
 }
@@ -0,0 +1,180 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::bit::Bit;
+use crate::basic::reciprocals;
+
+// -----------------------------------------------
+
+/// How the secondary context models each coded bit, recorded in the header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CellType {
+	/// A nonstationary bit history state, predicted by the state table (the default) or by
+	/// learned probabilities with [`adaptive`](crate::Options::adaptive).
+	State,
+	/// A 16-bit probability which moves by 1/2^`shift` of each error, with `shift` from 1 to 15.
+	Counter16 { shift: u8 },
+	/// A 32-bit probability with a count of its updates, which moves by 1/(count + 1.5) of each
+	/// error until the count reaches `limit`, from 1 to 1023, then by 1/(`limit` + 1.5).
+	Counter32 { limit: u16 },
+}
+
+impl CellType {
+	// whether the parameter of the counter is in range
+	pub fn is_valid(&self) -> bool {
+		match *self {
+			CellType::State => true,
+			CellType::Counter16 { shift } => (1..=15).contains(&shift),
+			CellType::Counter32 { limit } => (1..=COUNT_MASK as u16).contains(&limit),
+		}
+	}
+}
+
+// -----------------------------------------------
+
+// probabilities of a one stored xor 0x8000, so that the buffer is allocated as lazily zeroed memory
+// with every probability at one half
+pub struct Counter16Context {
+	context: Box<[u16]>,
+	shift: u32,
+}
+
+impl Counter16Context {
+	pub fn new(size: usize, shift: u8) -> Self {
+		Self {
+			context: vec![0; size].into_boxed_slice(),
+			shift: shift as u32,
+		}
+	}
+
+	#[inline(always)]
+	pub fn prediction(&self, context_index: usize) -> u32 {
+		debug_assert!(context_index < self.context.len());
+		let probability: u32 = (self.context[context_index] ^ 0x8000) as u32;
+		(probability << 16).clamp(1 << 16, 0xFFFF << 16)
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, context_index: usize, bit: Bit) {
+		let probability: i32 = (self.context[context_index] ^ 0x8000) as i32;
+		let target: i32 = if bit.into() { 0xFFFF } else { 0 };
+		let probability: i32 = probability + ((target - probability) >> self.shift);
+		self.context[context_index] = probability as u16 ^ 0x8000;
+	}
+}
+
+// -----------------------------------------------
+
+// the low bits of a 32-bit counter holding the number of its updates
+const COUNT_MASK: u32 = 1023;
+
+static RECIPROCALS: [u32; COUNT_MASK as usize + 1] = reciprocals();
+
+// probabilities of a one in the high 22 bits stored xor 1 << 21, for the same reason, and the
+// number of updates in the low 10 bits
+pub struct Counter32Context {
+	context: Box<[u32]>,
+	limit: u32,
+}
+
+impl Counter32Context {
+	pub fn new(size: usize, limit: u16) -> Self {
+		Self {
+			context: vec![0; size].into_boxed_slice(),
+			limit: limit as u32,
+		}
+	}
+
+	#[inline(always)]
+	pub fn prediction(&self, context_index: usize) -> u32 {
+		debug_assert!(context_index < self.context.len());
+		let probability: u32 = (self.context[context_index] >> 10) ^ (1 << 21);
+		(probability << 10).clamp(1 << 10, !COUNT_MASK)
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, context_index: usize, bit: Bit) {
+		let cell: u32 = self.context[context_index];
+		let count: u32 = cell & COUNT_MASK;
+		let probability: i64 = ((cell >> 10) ^ (1 << 21)) as i64;
+		let target: i64 = if bit.into() { (1 << 22) - 1 } else { 0 };
+		let probability: i64 =
+			probability + (((target - probability) * RECIPROCALS[count as usize] as i64) >> 16);
+		self.context[context_index] =
+			(((probability as u32) ^ (1 << 21)) << 10) | (count + 1).min(self.limit);
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{CellType, Counter16Context, Counter32Context};
+	use crate::basic::{AnyError, AnyResult, FormatError};
+	use crate::container::Header;
+	use crate::secondary_context::Bit;
+	use crate::Options;
+
+	fn probability(prediction: u32) -> f64 {
+		prediction as f64 / 2f64.powi(32)
+	}
+
+	#[test]
+	fn test_counters() -> AnyResult<()> {
+		// a 16-bit counter moves by a fixed part of each error
+		let mut counter16: Counter16Context = Counter16Context::new(2, 2);
+		assert_eq!(counter16.prediction(0), 1 << 31);
+		counter16.update(0, Bit::One);
+		assert_eq!(counter16.prediction(0), 0x9FFF << 16);
+		counter16.update(0, Bit::Zero);
+		assert_eq!(counter16.prediction(0), 0x77FF << 16);
+		assert_eq!(counter16.prediction(1), 1 << 31);
+
+		// a 32-bit counter moves by 1/(count + 1.5) of each error, up to its limit
+		let mut counter32: Counter32Context = Counter32Context::new(2, 2);
+		let mut expected: f64 = 0.5;
+		for count in [0, 1, 2, 2, 2] {
+			counter32.update(0, Bit::One);
+			expected += (1.0 - expected) / (count as f64 + 1.5);
+			assert!((probability(counter32.prediction(0)) - expected).abs() < 1e-4);
+		}
+		assert_eq!(counter32.prediction(1), 1 << 31);
+
+		// the kind and the parameter of the cells are the last 3 bytes of the header
+		assert!(!CellType::Counter16 { shift: 16 }.is_valid());
+		assert!(!CellType::Counter32 { limit: 0 }.is_valid());
+		let header: Header =
+			Header::new(&Options::new().cell_type(CellType::Counter16 { shift: 4 }));
+		let mut bytes: Vec<u8> = Vec::new();
+		header.write(&mut bytes)?;
+		assert_eq!(
+			Header::read(&mut &bytes[..])?.cell_type(),
+			header.cell_type()
+		);
+		let cells: usize = bytes.len() - 3;
+		for (position, value) in [(cells + 1, 16), (cells, 3)] {
+			let mut forged: Vec<u8> = bytes.clone();
+			forged[position] = value;
+			assert!(matches!(
+				Header::read(&mut &forged[..]),
+				Err(AnyError::Format(FormatError::UnsupportedHeader(_)))
+			));
+		}
+		Ok(())
+	}
+}
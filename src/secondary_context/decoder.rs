@@ -16,28 +16,39 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::{AnyResult, Closable, PipedReader, Reader};
+use crate::basic::{AnyResult, Closable, FormatError, Reader};
 use crate::secondary_context::Bit;
 
 // -----------------------------------------------
 
-pub struct BitDecoder<const SIZE: usize> {
+pub struct BitDecoder<R: Reader<u8>> {
 	value: u32,
 	low: u32,
 	high: u32,
-	reader: PipedReader<u8, SIZE>,
+	reader: R,
+	position: u64,
+	strict: bool,
 }
 
-impl<const SIZE: usize> BitDecoder<SIZE> {
-	pub fn new(reader: PipedReader<u8, SIZE>) -> Self {
+impl<R: Reader<u8>> BitDecoder<R> {
+	// the position is the offset of the reader in the whole input, used for error reporting;
+	// a strict decoder fails when the input ends, otherwise it is padded with 0xFF like v0.3
+	pub fn new(reader: R, position: u64, strict: bool) -> Self {
 		Self {
 			value: 0,
 			low: 0,
 			high: 0,
 			reader,
+			position,
+			strict,
 		}
 	}
 
+	// the offset right after the last byte read from the reader
+	pub fn position(&self) -> u64 {
+		self.position
+	}
+
 	#[cold]
 	#[inline(always)]
 	fn flush(&mut self) -> AnyResult<()> {
@@ -46,8 +57,17 @@ impl<const SIZE: usize> BitDecoder<SIZE> {
 			// shift byte in
 			self.value = (self.value << 8)
 				| match self.reader.read()? {
+					None if self.strict => {
+						return Err(FormatError::Truncated {
+							offset: self.position,
+						}
+						.into());
+					}
 					None => 0xFF,
-					Some(byte) => byte as u32,
+					Some(byte) => {
+						self.position += 1;
+						byte as u32
+					}
 				};
 			// shift new bits into high/low
 			self.low <<= 8;
@@ -85,10 +105,41 @@ impl<const SIZE: usize> BitDecoder<SIZE> {
 		// return the value
 		return Ok(bit);
 	}
+
+	// consume the bytes the encoder shifted out after the last bit, if any bit was decoded
+	// since the last reset (high is only zero before the first bit)
+	pub fn finish(&mut self) -> AnyResult<()> {
+		if self.high != 0 && (self.high ^ self.low) < 0x01000000 {
+			self.flush()?;
+		}
+		Ok(())
+	}
+
+	// read a byte that is not part of a coded stream, after finish and before the next reset
+	pub fn read_raw(&mut self) -> AnyResult<u8> {
+		match self.reader.read()? {
+			None => Err(FormatError::Truncated {
+				offset: self.position,
+			}
+			.into()),
+			Some(byte) => {
+				self.position += 1;
+				Ok(byte)
+			}
+		}
+	}
+
+	// start decoding a new coded stream from the current position
+	pub fn reset(&mut self) {
+		self.value = 0;
+		self.low = 0;
+		self.high = 0;
+	}
 }
 
-impl<const SIZE: usize> Closable<()> for BitDecoder<SIZE> {
-	fn close(self) -> AnyResult<()> {
-		self.reader.close()
+impl<R: Reader<u8>> Closable<R> for BitDecoder<R> {
+	fn close(mut self) -> AnyResult<R> {
+		self.finish()?;
+		Ok(self.reader)
 	}
 }
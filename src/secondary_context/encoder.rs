@@ -16,19 +16,19 @@
  * this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::basic::{AnyResult, Closable, PipedWriter, Writer};
+use crate::basic::{AnyResult, Closable, Writer};
 use crate::secondary_context::Bit;
 
 // -----------------------------------------------
 
-pub struct BitEncoder<const SIZE: usize> {
+pub struct BitEncoder<W: Writer<u8>> {
 	low: u32,
 	high: u32,
-	writer: PipedWriter<u8, SIZE>,
+	writer: W,
 }
 
-impl<const SIZE: usize> BitEncoder<SIZE> {
-	pub fn new(writer: PipedWriter<u8, SIZE>) -> Self {
+impl<W: Writer<u8>> BitEncoder<W> {
+	pub fn new(writer: W) -> Self {
 		Self {
 			low: 0,
 			high: 0xFFFFFFFF,
@@ -36,6 +36,10 @@ impl<const SIZE: usize> BitEncoder<SIZE> {
 		}
 	}
 
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.writer
+	}
+
 	#[cold]
 	#[inline(always)]
 	fn flush(&mut self) -> AnyResult<()> {
@@ -44,7 +48,7 @@ impl<const SIZE: usize> BitEncoder<SIZE> {
 			// write byte
 			self.writer.write((self.low >> 24) as u8)?;
 			// shift new bits into high/low
-			self.low = self.low << 8;
+			self.low <<= 8;
 			self.high = (self.high << 8) | 0xFF;
 			// check condition again
 			(self.high ^ self.low) < 0x01000000
@@ -75,11 +79,14 @@ impl<const SIZE: usize> BitEncoder<SIZE> {
 	}
 }
 
-impl<const SIZE: usize> Closable<()> for BitEncoder<SIZE> {
-	fn close(mut self) -> AnyResult<()> {
-		// write byte
+impl<W: Writer<u8>> Closable<W> for BitEncoder<W> {
+	fn close(mut self) -> AnyResult<W> {
+		// write all bytes of low, so the decoder never has to read past the end of the stream
 		self.writer.write((self.low >> 24) as u8)?;
+		self.writer.write((self.low >> 16) as u8)?;
+		self.writer.write((self.low >> 8) as u8)?;
+		self.writer.write(self.low as u8)?;
 		// return the writer
-		self.writer.close()
+		Ok(self.writer)
 	}
 }
@@ -18,12 +18,16 @@
 
 mod bit;
 mod context;
+mod counter;
 mod decoder;
 mod encoder;
 mod state;
+mod state_map;
 
 pub use self::bit::Bit;
 pub use self::context::SecondaryContext;
-pub use self::state::StateInfo;
+pub use self::counter::{CellType, Counter16Context, Counter32Context};
+pub use self::state::{BitState, StateInfo};
+pub use self::state_map::StateMap;
 pub use self::decoder::BitDecoder;
 pub use self::encoder::BitEncoder;
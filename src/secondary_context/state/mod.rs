@@ -18,7 +18,9 @@
 
 mod info;
 mod state;
+// generator of the state table, not held to the lints
 #[cfg(test)]
+#[allow(clippy::all, dead_code)]
 mod test;
 
 pub use self::state::BitState;
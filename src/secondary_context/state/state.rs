@@ -21,12 +21,18 @@ use super::info::{StateInfo, STATE_TABLE};
 
 // -----------------------------------------------
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct BitState(u16);
 
-impl Default for BitState {
-	fn default() -> Self {
-		Self(0)
+impl From<u16> for BitState {
+	fn from(value: u16) -> Self {
+		Self(value)
+	}
+}
+
+impl From<BitState> for u16 {
+	fn from(value: BitState) -> Self {
+		value.0
 	}
 }
 
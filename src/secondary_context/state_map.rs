@@ -0,0 +1,114 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::bit::Bit;
+use super::state::BitState;
+use crate::basic::reciprocals;
+
+// -----------------------------------------------
+
+// the number of updates after which a probability settles to a moving average
+const LIMIT: u32 = 1023;
+
+// the low bits of an entry holding the number of updates
+const COUNT_MASK: u32 = 1023;
+
+// the fixed prediction of a state weighs as much as this many updates
+const PRIOR: u32 = 64;
+
+static RECIPROCALS: [u32; LIMIT as usize + 1] = reciprocals();
+
+// the probabilities of a one learned for every bit history state, in several independent maps, each
+// a u32 holding the probability in the high 22 bits and the number of updates in the low 10 bits;
+// every probability starts at the fixed prediction of its state
+pub struct StateMap {
+	table: Box<[u32]>,
+	// the entry of the last prediction, the one that is updated
+	index: usize,
+}
+
+impl StateMap {
+	pub fn new(maps: usize) -> Self {
+		let initial: Vec<u32> = (0..=u16::MAX)
+			.map(|state: u16| BitState::from(state).get_info().prediction() & !COUNT_MASK | PRIOR)
+			.collect();
+		Self {
+			table: initial.repeat(maps).into_boxed_slice(),
+			index: 0,
+		}
+	}
+
+	// the learned prediction of a one for the state, in the same scale as the fixed one
+	#[inline(always)]
+	pub fn predict(&mut self, map: usize, state: BitState) -> u32 {
+		self.index = (map << 16) | u16::from(state) as usize;
+		(self.table[self.index] & !COUNT_MASK).clamp(COUNT_MASK + 1, !COUNT_MASK)
+	}
+
+	#[inline(always)]
+	pub fn update(&mut self, bit: Bit) {
+		let entry: u32 = self.table[self.index];
+		let count: u32 = entry & COUNT_MASK;
+		let probability: i64 = (entry >> 10) as i64;
+		let target: i64 = if bit.into() { (1 << 22) - 1 } else { 0 };
+		let probability: i64 =
+			probability + (((target - probability) * RECIPROCALS[count as usize] as i64) >> 16);
+		self.table[self.index] = ((probability as u32) << 10) | (count + 1).min(LIMIT);
+	}
+}
+
+// -----------------------------------------------
+
+#[cfg(test)]
+mod test {
+	use super::{StateMap, COUNT_MASK, PRIOR};
+	use crate::secondary_context::{Bit, BitState};
+
+	#[test]
+	fn test_state_map() {
+		// every map starts from the fixed prediction of the state
+		let mut map: StateMap = StateMap::new(2);
+		let state: BitState = BitState::from(5);
+		let fixed: u32 = state.get_info().prediction() & !COUNT_MASK;
+		assert_eq!(map.predict(0, state), fixed);
+
+		// which weighs as much as PRIOR updates, so that as many ones move it halfway to a one
+		for _ in 0..PRIOR {
+			map.predict(0, state);
+			map.update(Bit::from(1));
+		}
+		let expected: f64 = 1.0
+			- (1.0 - fixed as f64 / 2f64.powi(32)) * (PRIOR as f64 + 0.5)
+				/ (2.0 * PRIOR as f64 + 0.5);
+		let learned: f64 = map.predict(0, state) as f64 / 2f64.powi(32);
+		assert!((learned - expected).abs() < 0.01);
+
+		// then keeps learning, while the other states and the other maps keep theirs
+		for _ in 0..2000 {
+			map.predict(0, state);
+			map.update(Bit::from(1));
+		}
+		assert!(map.predict(0, state) > 0xFF00_0000);
+		assert_eq!(map.predict(1, state), fixed);
+		let other: BitState = BitState::default();
+		assert_eq!(
+			map.predict(0, other),
+			other.get_info().prediction() & !COUNT_MASK
+		);
+	}
+}
@@ -0,0 +1,390 @@
+/*
+ * srx: The fast Symbol Ranking based compressor.
+ * Copyright (C) 2023  Mai Thanh Minh (a.k.a. thanhminhmr)
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either  version 3 of the  License,  or (at your option) any later
+ * version.
+ *
+ * This program  is distributed in the hope  that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR  A PARTICULAR PURPOSE. See  the  GNU  General  Public   License  for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{
+	compress, compress_slice, decompress, decompress_slice, measure_dictionary, read_metadata,
+	train_dictionary, AnyError, AnyResult, CellType, Dictionary, FileMetadata, FormatError,
+	Options, SampleGain, SeekableSrxReader, SrxReader, SRX_MAGIC,
+};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::time::{Duration, SystemTime};
+
+// -----------------------------------------------
+
+fn sample(length: usize) -> Vec<u8> {
+	let words: [&[u8]; 8] = [
+		b"symbol ",
+		b"ranking ",
+		b"compressor ",
+		b"context ",
+		b"history ",
+		b"state ",
+		b"bit ",
+		b"\n",
+	];
+	let mut seed: u32 = 0x12345678;
+	let mut data: Vec<u8> = Vec::with_capacity(length + 16);
+	while data.len() < length {
+		seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+		data.extend_from_slice(words[(seed >> 16) as usize % words.len()]);
+	}
+	data.truncate(length);
+	data
+}
+
+fn round_trip(data: &[u8], options: &Options) -> AnyResult<Vec<u8>> {
+	let (_, compressed): (&[u8], Vec<u8>) = compress(data, Vec::new(), options)?;
+	let (_, decompressed): (&[u8], Vec<u8>) = decompress(&compressed[..], Vec::new(), options)?;
+	assert_eq!(decompressed, data);
+	Ok(compressed)
+}
+
+// -----------------------------------------------
+
+#[test]
+fn test_round_trip() -> AnyResult<()> {
+	let options: Options = Options::new();
+	round_trip(&[], &options)?;
+	round_trip(b"a", &options)?;
+	let compressed: Vec<u8> = round_trip(&sample(100000), &options)?;
+	assert!(compressed.starts_with(SRX_MAGIC));
+	assert!(compressed.len() < 50000);
+	Ok(())
+}
+
+#[test]
+fn test_models() -> AnyResult<()> {
+	// the decoders read the models from the header
+	let data: Vec<u8> = sample(30000);
+	for options in [
+		Options::new().max(true),
+		Options::new().max(true).block_size(10000),
+		Options::new().apm(true),
+		Options::new().max(true).apm(true),
+		Options::new().adaptive(true),
+		Options::new().max(true).apm(true).adaptive(true),
+		Options::new().cell_type(CellType::Counter16 { shift: 4 }),
+		Options::new()
+			.max(true)
+			.apm(true)
+			.cell_type(CellType::Counter32 { limit: 255 }),
+		Options::new().context_order(1),
+		Options::new().context_order(3).max(true).apm(true),
+		Options::new().context_order(8).block_size(10000),
+		Options::new().context_order(1).wide(true),
+		Options::new().wide(true).max(true).adaptive(true),
+		Options::new().level(2),
+		Options::new().context_order(6).fallback(true),
+		Options::new().fallback(true).wide(true).max(true).apm(true),
+		Options::new().long_match(true),
+		Options::new()
+			.long_match(true)
+			.memory_level(16)
+			.block_size(10000),
+		Options::new()
+			.long_match(true)
+			.fallback(true)
+			.adaptive(true),
+		Options::new().tagged(true),
+		Options::new()
+			.tagged(true)
+			.memory_level(16)
+			.context_order(3),
+		Options::new().tagged(true).wide(true).context_order(6),
+		Options::new().tagged(true).long_match(true).fallback(true),
+		Options::new().exclusion(true),
+		Options::new().exclusion(true).max(true).apm(true),
+		Options::new().exclusion(true).wide(true).fallback(true),
+		Options::new().exclusion(true).long_match(true).tagged(true),
+	] {
+		for length in [0, 1, data.len()] {
+			let compressed: Vec<u8> = round_trip(&data[..length], &options)?;
+			assert_eq!(
+				decompress_slice(&compressed, &Options::new())?,
+				&data[..length]
+			);
+		}
+	}
+	Ok(())
+}
+
+#[test]
+fn test_reject_unknown_header() {
+	let result = decompress(&b"gzip, not srx"[..], Vec::new(), &Options::new());
+	assert!(result.is_err());
+}
+
+fn format_error<T>(result: AnyResult<T>) -> FormatError {
+	match result {
+		Err(AnyError::Format(error)) => error,
+		Err(error) => panic!("unexpected error: {}", error),
+		Ok(_) => panic!("unexpected success"),
+	}
+}
+
+#[test]
+fn test_checksum() -> AnyResult<()> {
+	let data: Vec<u8> = sample(30000);
+	let options: Options = Options::new();
+	let mut compressed: Vec<u8> = compress_slice(&data, &options)?;
+	let unchecked: Vec<u8> = compress_slice(&data, &options.clone().checksum(false))?;
+	assert_eq!(unchecked.len() + 4, compressed.len());
+	assert_eq!(decompress_slice(&unchecked, &options)?, data);
+
+	// corrupt the stored checksum
+	*compressed.last_mut().unwrap() ^= 0x80;
+	assert!(matches!(
+		format_error(decompress_slice(&compressed, &options)),
+		FormatError::ChecksumMismatch { .. }
+	));
+	assert!(matches!(
+		format_error(decompress(&compressed[..], Vec::new(), &options)),
+		FormatError::ChecksumMismatch { .. }
+	));
+	let mut reader: SrxReader<&[u8]> = SrxReader::new(&compressed[..])?;
+	assert!(reader.read_to_end(&mut Vec::new()).is_err());
+	Ok(())
+}
+
+#[test]
+fn test_truncated_and_trailing_data() -> AnyResult<()> {
+	let data: Vec<u8> = sample(20000);
+	let options: Options = Options::new();
+	let compressed: Vec<u8> = compress_slice(&data, &options)?;
+
+	// cut inside the header, the compressed stream and the trailer
+	for length in [
+		6,
+		12,
+		100,
+		compressed.len() / 2,
+		compressed.len() - 4,
+		compressed.len() - 1,
+	] {
+		let truncated: &[u8] = &compressed[..length];
+		let expected: FormatError = FormatError::Truncated {
+			offset: length as u64,
+		};
+		assert_eq!(
+			format_error(decompress_slice(truncated, &options)),
+			expected
+		);
+		assert_eq!(
+			format_error(decompress(truncated, Vec::new(), &options)),
+			expected
+		);
+	}
+
+	let mut trailing: Vec<u8> = compressed.clone();
+	trailing.extend_from_slice(b"garbage");
+	let expected: FormatError = FormatError::TrailingData {
+		offset: compressed.len() as u64,
+	};
+	assert_eq!(
+		format_error(decompress_slice(&trailing, &options)),
+		expected
+	);
+	assert_eq!(
+		format_error(decompress(&trailing[..], Vec::new(), &options)),
+		expected
+	);
+	let mut reader: SrxReader<&[u8]> = SrxReader::new(&trailing[..])?;
+	let error: std::io::Error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+	assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+	Ok(())
+}
+
+#[test]
+fn test_concatenated() -> AnyResult<()> {
+	let data: Vec<u8> = sample(30000);
+	let parts: [(&[u8], Options); 5] = [
+		(&data[..10000], Options::new()),
+		(&data[10000..10000], Options::new().checksum(false)),
+		(
+			&data[10000..20000],
+			Options::new().block_size(3000).threads(2),
+		),
+		(&data[20000..25000], Options::new().seekable(true)),
+		(&data[25000..], Options::new().checksum(false)),
+	];
+	let mut compressed: Vec<u8> = Vec::new();
+	for (part, options) in &parts {
+		compress(*part, &mut compressed, options)?;
+	}
+	let options: Options = Options::new();
+	assert_eq!(decompress_slice(&compressed, &options)?, data);
+	let (_, decompressed): (&[u8], Vec<u8>) = decompress(&compressed[..], Vec::new(), &options)?;
+	assert_eq!(decompressed, data);
+	let mut reader: SrxReader<&[u8]> = SrxReader::new(&compressed[..])?;
+	let mut decompressed: Vec<u8> = Vec::new();
+	let mut buffer: [u8; 777] = [0; 777];
+	loop {
+		match reader.read(&mut buffer)? {
+			0 => break,
+			length => decompressed.extend_from_slice(&buffer[..length]),
+		}
+	}
+	assert_eq!(decompressed, data);
+
+	// errors in a later member are reported at their offset in the whole input
+	let first: Vec<u8> = compress_slice(&data[..10000], &options)?;
+	let second: Vec<u8> = compress_slice(&data[10000..], &options)?;
+	let mut joined: Vec<u8> = [&first[..], &second[..]].concat();
+	let expected: FormatError = FormatError::Truncated {
+		offset: first.len() as u64 + 6,
+	};
+	assert_eq!(
+		format_error(decompress_slice(&joined[..first.len() + 6], &options)),
+		expected
+	);
+	assert_eq!(
+		format_error(decompress(&joined[..first.len() + 6], Vec::new(), &options)),
+		expected
+	);
+	joined.extend_from_slice(b"srx");
+	let expected: FormatError = FormatError::TrailingData {
+		offset: (first.len() + second.len()) as u64,
+	};
+	assert_eq!(format_error(decompress_slice(&joined, &options)), expected);
+	assert_eq!(
+		format_error(decompress(&joined[..], Vec::new(), &options)),
+		expected
+	);
+	let position: usize = joined.len() - 7;
+	joined[position] ^= 1;
+	assert!(matches!(
+		format_error(decompress_slice(&joined, &options)),
+		FormatError::ChecksumMismatch { .. }
+	));
+	Ok(())
+}
+
+#[test]
+fn test_metadata() -> AnyResult<()> {
+	let data: Vec<u8> = sample(5000);
+	let metadata: FileMetadata = FileMetadata {
+		name: "notes.txt".to_string(),
+		modified: SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+		mode: Some(0o644),
+		owner: None,
+	};
+	for options in [Options::new(), Options::new().block_size(1000)] {
+		let options: Options = options.metadata(metadata.clone());
+		let compressed: Vec<u8> = round_trip(&data, &options)?;
+		assert_eq!(decompress_slice(&compressed, &options)?, data);
+		assert_eq!(read_metadata(&mut &compressed[..])?, Some(metadata.clone()));
+	}
+	assert_eq!(
+		read_metadata(&mut &compress_slice(&data, &Options::new())?[..])?,
+		None
+	);
+	Ok(())
+}
+
+#[test]
+fn test_dictionary() -> AnyResult<()> {
+	let dictionary: Dictionary = Dictionary::new(sample(20000));
+	let data: Vec<u8> = sample(3000);
+	let options: Options = Options::new().dictionary(dictionary.clone());
+	let plain: Vec<u8> = compress_slice(&data, &Options::new())?;
+	let primed: Vec<u8> = compress_slice(&data, &options)?;
+	assert!(primed.len() < plain.len() / 2);
+	assert_eq!(decompress_slice(&primed, &options)?, data);
+	let compressed: Vec<u8> = round_trip(&data, &options)?;
+	let mut decompressed: Vec<u8> = Vec::new();
+	SrxReader::with_options(&compressed[..], &options)?.read_to_end(&mut decompressed)?;
+	assert_eq!(decompressed, data);
+
+	// every block and every member starts from the primed model
+	let block_options: Options = options.clone().seekable(true).block_size(1000);
+	let blocks: Vec<u8> = compress_slice(&data, &block_options)?;
+	let (_, decompressed): (&[u8], Vec<u8>) = decompress(&blocks[..], Vec::new(), &options)?;
+	assert_eq!(decompressed, data);
+	let mut reader: SeekableSrxReader<Cursor<&[u8]>> =
+		SeekableSrxReader::with_options(Cursor::new(&blocks[..]), &options)?;
+	let mut buffer: Vec<u8> = vec![0; 500];
+	reader.seek(SeekFrom::Start(1700))?;
+	reader.read_exact(&mut buffer)?;
+	assert_eq!(buffer, &data[1700..2200]);
+	let joined: Vec<u8> = [&plain[..], &primed[..]].concat();
+	assert_eq!(
+		decompress_slice(&joined, &options)?,
+		[&data[..], &data[..]].concat()
+	);
+
+	// the stream can not be decompressed without the same dictionary
+	let missing: FormatError = FormatError::DictionaryMismatch {
+		expected: dictionary.id(),
+		actual: None,
+	};
+	assert_eq!(
+		format_error(decompress_slice(&primed, &Options::new())),
+		missing
+	);
+	assert_eq!(
+		format_error(decompress(&primed[..], Vec::new(), &Options::new())),
+		missing
+	);
+	assert_eq!(format_error(SrxReader::new(&primed[..])), missing);
+	assert_eq!(
+		format_error(SeekableSrxReader::new(Cursor::new(&blocks[..]))),
+		missing
+	);
+	assert_eq!(
+		format_error(decompress_slice(&joined, &Options::new())),
+		missing
+	);
+	Ok(())
+}
+
+#[test]
+fn test_train_dictionary() -> AnyResult<()> {
+	let documents: Vec<Vec<u8>> = (0..40)
+		.map(|index: usize| {
+			format!(
+				"{{\n  \"id\": {},\n  \"type\": \"event\",\n  \"user\": {{\n    \"name\": \"user{}\",\n    \
+				\"active\": {}\n  }},\n  \"tags\": [\"alpha\", \"beta\"]\n}}\n",
+				index,
+				index * 7919 % 1000,
+				index % 3 == 1
+			)
+			.into_bytes()
+		})
+		.collect();
+	let samples: Vec<&[u8]> = documents[1..].iter().map(Vec::as_slice).collect();
+	let dictionary: Dictionary = train_dictionary(&samples, 1000)?;
+	assert!(!dictionary.as_bytes().is_empty() && dictionary.as_bytes().len() <= 1000);
+	assert!(train_dictionary(&[], 1000)?.as_bytes().is_empty());
+
+	// the sample left out of the training compresses better with the dictionary
+	let gain: SampleGain = measure_dictionary(&documents[0], &dictionary)?;
+	assert_eq!(gain.length, documents[0].len() as u64);
+	assert_eq!(gain.plain.total(), gain.length);
+	assert_eq!(gain.primed.total(), gain.length);
+	assert!(gain.primed.first > gain.plain.first);
+	assert!(gain.primed_size < gain.plain_size);
+	let options: Options = Options::new()
+		.length(documents[0].len() as u64)
+		.fit_memory(true)
+		.dictionary(dictionary);
+	assert_eq!(
+		compress_slice(&documents[0], &options)?.len() as u64,
+		gain.primed_size
+	);
+	Ok(())
+}